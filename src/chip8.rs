@@ -1,18 +1,40 @@
-use std::fs::File;
-use std::io::Read;
+#![allow(clippy::needless_return)]
+
+use std::collections::VecDeque;
 
 use crate::chip8_display;
 use crate::chip8_memory;
-use crate::chip8_instructions::{get_and_print_instruction_type, exec};
+use crate::chip8_quirks::{Chip8Quirks, build_default_quirks};
+use crate::rom_file::{RomFile, read_patched_rom};
+use crate::chip8_instructions::exec;
 use std::ops::Shl;
-use std::borrow::{BorrowMut, Borrow};
-use crate::chip8_display::{SPRITE_0, DEFAULT_SPRITE};
+use std::borrow::BorrowMut;
+use crate::chip8_display::DEFAULT_SPRITE;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
 
 pub const REGISTER_COUNT: usize = 16;
 pub const STACK_SIZE: usize = 16;
 pub const KEYBOARD_SIZE: usize = 16;
 pub const CLOCK_SPEED: u16 = 540;
 
+// A key released quickly stays down for this number of instructions (one frame), so SKP / SKNP can see short taps
+pub const KEY_MIN_HOLD_TICKS: u64 = 14;
+
+// Value of `key` when no key was released while waiting in Fx0A
+pub const NO_KEY: usize = KEYBOARD_SIZE + 1;
+
+/**
+ * Key press or release, applied once the machine executed `tick` instructions
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub(crate) tick: u64,
+    pub(crate) key: usize,
+    pub(crate) down: bool,
+}
+
+#[derive(Clone)]
 pub struct Chip8{
 
     // Memory
@@ -36,9 +58,24 @@ pub struct Chip8{
     // Keyboard
     pub(crate) keyboard: [bool; KEYBOARD_SIZE],
 
-    // Last pressed key
+    // Key released while Fx0A waits
     pub(crate) key: usize,
 
+    // Instruction count at which Fx0A started waiting for a key press and release
+    pub(crate) key_wait_start: Option<u64>,
+
+    // Key events not applied yet, oldest first
+    pub(crate) key_events: VecDeque<KeyEvent>,
+
+    // Number of events queued and not yet taken by the debugger recording
+    pub(crate) new_key_events: usize,
+
+    // Instruction count at which each key was last pressed
+    pub(crate) key_pressed_at: [u64; KEYBOARD_SIZE],
+
+    // Instructions executed since the machine started
+    pub(crate) ticks: u64,
+
     // Program counter
     pub(crate) pc: u16,
 
@@ -53,6 +90,15 @@ pub struct Chip8{
 
     pub(crate) turbo: bool,
 
+    // Interpreter behaviours followed by the instructions
+    pub(crate) quirks: Chip8Quirks,
+
+    // Random generator, kept in the machine so a snapshot replays the same RND results
+    pub(crate) rng: StdRng,
+
+    // Set when a RET or a CALL would go past the bounds of the stack
+    pub(crate) stack_warning: Option<String>,
+
 }
 
 /**
@@ -67,12 +113,20 @@ pub fn build_chip8() -> Chip8{
         i: 0,
         display: chip8_display::build_chip8_display(),
         keyboard: [false; KEYBOARD_SIZE],
-        key: KEYBOARD_SIZE + 1,
+        key: NO_KEY,
+        key_wait_start: None,
+        key_events: VecDeque::new(),
+        new_key_events: 0,
+        key_pressed_at: [0; KEYBOARD_SIZE],
+        ticks: 0,
         pc: chip8_memory::START_PRG as u16,
         sp: 0,
         stack: [0; STACK_SIZE],
         cycle: 0,
-        turbo: false
+        turbo: false,
+        quirks: build_default_quirks(),
+        rng: StdRng::from_entropy(),
+        stack_warning: None
     };
     load_default_sprites(device.borrow_mut());
     return device;
@@ -83,9 +137,9 @@ pub fn build_chip8() -> Chip8{
  */
 fn load_default_sprites(device: &mut Chip8){
     let mut address:usize = 0x00;
-    for sprite in 0..DEFAULT_SPRITE.len(){
-        load_sprite_at(device.borrow_mut(), address, DEFAULT_SPRITE[sprite]);
-        address = address + 5;
+    for sprite in DEFAULT_SPRITE {
+        load_sprite_at(device.borrow_mut(), address, sprite);
+        address += 5;
     }
 }
 
@@ -93,8 +147,42 @@ fn load_default_sprites(device: &mut Chip8){
  * Put sprite data in memory
  */
 fn load_sprite_at(device: &mut Chip8, address: usize, sprite: [u8; 5]){
-    for i in 0..5{
-        device.memory[address + i] = sprite[i];
+    device.memory[address..address + 5].copy_from_slice(&sprite);
+}
+
+/**
+ * Queue a key press or release, timestamped with the current instruction count
+ */
+pub fn queue_key_event(device: &mut Chip8, key: usize, down: bool) {
+    device.key_events.push_back(KeyEvent { tick: device.ticks, key, down });
+    device.new_key_events = device.new_key_events.saturating_add(1);
+}
+
+/**
+ * Apply the queued key events that are due
+ *
+ * A release is held back until the key was down for KEY_MIN_HOLD_TICKS, the events behind it wait too to keep their order.
+ */
+fn apply_key_events(device: &mut Chip8) {
+    while let Some(event) = device.key_events.front().copied() {
+        if event.tick > device.ticks {
+            break;
+        }
+        if !event.down && device.ticks < device.key_pressed_at[event.key] + KEY_MIN_HOLD_TICKS {
+            break;
+        }
+        device.key_events.pop_front();
+        if event.down && !device.keyboard[event.key] {
+            device.key_pressed_at[event.key] = device.ticks;
+        } else if !event.down && device.keyboard[event.key] {
+            // Fx0A only takes keys pressed after it started waiting
+            if let Some(start) = device.key_wait_start {
+                if device.key_pressed_at[event.key] >= start {
+                    device.key = event.key;
+                }
+            }
+        }
+        device.keyboard[event.key] = event.down;
     }
 }
 
@@ -102,6 +190,7 @@ fn load_sprite_at(device: &mut Chip8, address: usize, sprite: [u8; 5]){
  * Step
  */
 pub fn step(device: &mut Chip8){
+    apply_key_events(device);
     let instruction:u16 = (device.memory[(device.pc+ 1) as usize] as u16) + (device.memory[device.pc as usize] as u16).shl(8);
     exec(instruction, device);
     if device.cycle >= CLOCK_SPEED/60{
@@ -114,28 +203,39 @@ pub fn step(device: &mut Chip8){
         }
     }
     device.cycle += 1;
+    device.ticks += 1;
 }
 
 /**
- * Load a Chip 8 program in memory
+ * Check a program is not empty and fits in the program area
  */
-pub fn load_program(device: &mut Chip8, path: &str) -> bool{
-
-    let file = File::open(path);
-    let mut file = match file {
-        Ok(file) => file,
-        Err(e) => {
-            panic!("Error : not a valid ROM file");
-        }
-    };
-
-    // Do read the file
-    let mut mem: usize = chip8_memory::START_PRG;
-    for byte in file.bytes() {
-        let b = byte.unwrap();
-        device.memory[mem] = b;
-        mem = mem + 1;
+pub fn check_rom(name: &str, rom: Vec<u8>) -> Result<Vec<u8>, String> {
+    let capacity = chip8_memory::END_MEM + 1 - chip8_memory::START_PRG;
+    if rom.is_empty() {
+        return Err(format!("{} is empty", name));
+    }
+    if rom.len() > capacity {
+        return Err(format!("{} is {} bytes long, programs are at most {} bytes", name, rom.len(), capacity));
     }
+    return Ok(rom);
+}
 
-    return true;
+/**
+ * Load a Chip 8 program in memory, from a ROM file, a file inside a zip archive (`pack.zip:TETRIS`) or an Octo cartridge
+ *
+ * The IPS or BPS patches are applied in order to the program. Returns the bytes of the patched program, with the
+ * settings the file gives.
+ */
+pub fn load_program(device: &mut Chip8, path: &str, patches: &[String]) -> Result<RomFile, String> {
+    let rom = read_patched_rom(path, patches)?;
+    load_rom(device, &rom.bytes);
+    return Ok(rom);
+}
+
+/**
+ * Copy the bytes of a program in memory, at the start of the program area
+ */
+pub fn load_rom(device: &mut Chip8, rom: &[u8]) {
+    let start = chip8_memory::START_PRG;
+    device.memory[start..start + rom.len()].copy_from_slice(rom);
 }
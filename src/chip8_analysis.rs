@@ -0,0 +1,426 @@
+#![allow(clippy::needless_return)]
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use crate::chip8_decompiler::subroutine_name;
+use crate::chip8_instructions::{CH8_INSTRUCTION, get_instruction_type};
+use crate::chip8_memory::START_PRG;
+use crate::chip8_symbols::{SymbolTable, disassemble_symbolic, format_address, symbol_name};
+
+/**
+ * Kind of an edge between two basic blocks
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    // Unconditional JP
+    Jump,
+    // Execution falls through to the next instruction
+    Next,
+    // A skip instruction jumps over the next instruction
+    Skip,
+    // CALL to a subroutine entry
+    Call,
+    // Bnnn to the start of its jump table, the target is decided by V0 at run time and is not followed
+    Indirect,
+}
+
+pub struct BasicBlock {
+
+    // Address of the first instruction
+    pub(crate) start: u16,
+
+    // Addresses of the instructions of the block, in order
+    pub(crate) instructions: Vec<u16>,
+
+    // Outgoing edges (target, kind)
+    pub(crate) successors: Vec<(u16, EdgeKind)>,
+
+    // The block ends with RET
+    pub(crate) returns: bool,
+}
+
+pub struct ControlFlowGraph {
+
+    // Program bytes, loaded at START_PRG
+    pub(crate) rom: Vec<u8>,
+
+    // Basic blocks by start address
+    pub(crate) blocks: BTreeMap<u16, BasicBlock>,
+
+    // Subroutines by entry address, with the start addresses of their blocks
+    pub(crate) subroutines: BTreeMap<u16, Vec<u16>>,
+
+    // Addresses of Bnnn instructions, their targets are only known at run time
+    pub(crate) computed_jumps: Vec<u16>,
+
+    // Instructions writing over code (instruction address, written address)
+    pub(crate) self_modifying: Vec<(u16, u16)>,
+
+    // Addresses reached by the control flow but lying outside of the ROM
+    pub(crate) out_of_rom: Vec<u16>,
+}
+
+/**
+ * Read the instruction at an address, None when it lies outside of the ROM
+ */
+pub fn read_opcode(rom: &[u8], address: u16) -> Option<u16> {
+    let offset = (address as usize).checked_sub(START_PRG)?;
+    if offset + 1 >= rom.len() {
+        return None;
+    }
+    return Some(((rom[offset] as u16) << 8) | rom[offset + 1] as u16);
+}
+
+/**
+ * Successors of an instruction, and whether it ends its basic block
+ */
+fn instruction_successors(ins: u16, address: u16) -> (Vec<(u16, EdgeKind)>, bool) {
+    let nnn = ins & 0x0FFF;
+    return match get_instruction_type(ins) {
+        CH8_INSTRUCTION::JP => (vec![(nnn, EdgeKind::Jump)], true),
+        CH8_INSTRUCTION::CALL => (vec![(nnn, EdgeKind::Call), (address + 2, EdgeKind::Next)], true),
+        CH8_INSTRUCTION::RET => (vec![], true),
+        CH8_INSTRUCTION::JPV0addr => (vec![(nnn, EdgeKind::Indirect)], true),
+        CH8_INSTRUCTION::SE | CH8_INSTRUCTION::SNE | CH8_INSTRUCTION::SEVxVy | CH8_INSTRUCTION::SNEVxVy |
+        CH8_INSTRUCTION::SKPVx | CH8_INSTRUCTION::SKNPVx => {
+            (vec![(address + 2, EdgeKind::Next), (address + 4, EdgeKind::Skip)], true)
+        }
+        _ => (vec![(address + 2, EdgeKind::Next)], false),
+    };
+}
+
+/**
+ * Recover the control flow graph of a program
+ *
+ * Starts at START_PRG and follows JP, CALL, RET and the skip instructions. Only reachable code is
+ * decoded, so data mixed with the code is left alone.
+ */
+pub fn analyze_program(rom: &[u8]) -> ControlFlowGraph {
+    let entry = START_PRG as u16;
+    let mut code: BTreeSet<u16> = BTreeSet::new();
+    let mut leaders: BTreeSet<u16> = BTreeSet::new();
+    let mut entries: BTreeSet<u16> = BTreeSet::new();
+    let mut out_of_rom: BTreeSet<u16> = BTreeSet::new();
+    let mut computed_jumps = Vec::new();
+
+    // Find every reachable instruction and the addresses starting a block
+    let mut work = vec![entry];
+    leaders.insert(entry);
+    entries.insert(entry);
+    while let Some(start) = work.pop() {
+        let mut address = start;
+        loop {
+            if code.contains(&address) {
+                leaders.insert(address);
+                break;
+            }
+            let ins = match read_opcode(rom, address) {
+                Some(ins) => ins,
+                None => {
+                    out_of_rom.insert(address);
+                    break;
+                }
+            };
+            code.insert(address);
+            match get_instruction_type(ins) {
+                CH8_INSTRUCTION::CALL => { entries.insert(ins & 0x0FFF); }
+                CH8_INSTRUCTION::JPV0addr => { computed_jumps.push(address); }
+                _ => {}
+            }
+            let (successors, ends) = instruction_successors(ins, address);
+            if ends {
+                for (target, _) in successors.into_iter().filter(|(_, kind)| *kind != EdgeKind::Indirect) {
+                    leaders.insert(target);
+                    work.push(target);
+                }
+                break;
+            }
+            address += 2;
+        }
+    }
+
+    // Cut the reachable code in basic blocks
+    let mut blocks = BTreeMap::new();
+    for leader in leaders.iter().filter(|a| code.contains(a)) {
+        let mut block = BasicBlock { start: *leader, instructions: Vec::new(), successors: Vec::new(), returns: false };
+        let mut address = *leader;
+        loop {
+            let ins = read_opcode(rom, address).unwrap();
+            block.instructions.push(address);
+            let (successors, ends) = instruction_successors(ins, address);
+            if ends {
+                block.returns = get_instruction_type(ins) == CH8_INSTRUCTION::RET;
+                block.successors = successors;
+                break;
+            }
+            address += 2;
+            if leaders.contains(&address) || !code.contains(&address) {
+                block.successors = successors;
+                break;
+            }
+        }
+        block.successors.retain(|(target, kind)| code.contains(target) || *kind == EdgeKind::Indirect);
+        blocks.insert(*leader, block);
+    }
+
+    // Group the blocks by subroutine, calls are not followed
+    let mut subroutines = BTreeMap::new();
+    for entry in entries.iter().filter(|a| blocks.contains_key(a)) {
+        let mut members = BTreeSet::new();
+        let mut work = vec![*entry];
+        while let Some(start) = work.pop() {
+            if !members.insert(start) {
+                continue;
+            }
+            for (target, kind) in &blocks[&start].successors {
+                if *kind != EdgeKind::Call && *kind != EdgeKind::Indirect {
+                    work.push(*target);
+                }
+            }
+        }
+        subroutines.insert(*entry, members.into_iter().collect());
+    }
+
+    let self_modifying = find_code_writes(rom, &blocks, &code);
+
+    return ControlFlowGraph {
+        rom: rom.to_vec(),
+        blocks,
+        subroutines,
+        computed_jumps,
+        self_modifying,
+        out_of_rom: out_of_rom.into_iter().collect(),
+    };
+}
+
+/**
+ * Find the Fx33 and Fx55 instructions writing over code
+ *
+ * I is tracked inside each block from the last LD I, addr. Writes through an unknown I are not reported.
+ */
+fn find_code_writes(rom: &[u8], blocks: &BTreeMap<u16, BasicBlock>, code: &BTreeSet<u16>) -> Vec<(u16, u16)> {
+    let is_code = |address: u16| code.contains(&address) || (address > 0 && code.contains(&(address - 1)));
+    let mut writes = Vec::new();
+    for block in blocks.values() {
+        let mut i: Option<u16> = None;
+        for address in &block.instructions {
+            let ins = read_opcode(rom, *address).unwrap();
+            let x = (ins & 0x0F00) >> 8;
+            match get_instruction_type(ins) {
+                CH8_INSTRUCTION::LDIaddr => { i = Some(ins & 0x0FFF); }
+                CH8_INSTRUCTION::LDBVx => {
+                    if let Some(target) = i.and_then(|i| (i..i + 3).find(|a| is_code(*a))) {
+                        writes.push((*address, target));
+                    }
+                }
+                CH8_INSTRUCTION::LDIVx => {
+                    if let Some(target) = i.and_then(|i| (i..i + x + 1).find(|a| is_code(*a))) {
+                        writes.push((*address, target));
+                    }
+                    i = i.map(|i| i + x + 1);
+                }
+                CH8_INSTRUCTION::LDVxI => { i = i.map(|i| i + x + 1); }
+                CH8_INSTRUCTION::ADDIVx | CH8_INSTRUCTION::LDFVx => { i = None; }
+                _ => {}
+            }
+        }
+    }
+    return writes;
+}
+
+/**
+ * Print the constructs the analysis cannot follow
+ */
+pub fn print_analysis_warnings(graph: &ControlFlowGraph, symbols: &SymbolTable) {
+    for address in &graph.computed_jumps {
+        println!("{:#05x}: computed jump, targets are unknown", address);
+    }
+    for (address, target) in &graph.self_modifying {
+        println!("{:#05x}: self-modifying code, writes to {}", address, format_address(symbols, *target));
+    }
+    for address in &graph.out_of_rom {
+        println!("{:#05x}: control flow leaves the ROM", address);
+    }
+}
+
+fn edge_name(kind: EdgeKind) -> &'static str {
+    return match kind {
+        EdgeKind::Jump => "jump",
+        EdgeKind::Next => "next",
+        EdgeKind::Skip => "skip",
+        EdgeKind::Call => "call",
+        EdgeKind::Indirect => "indirect",
+    };
+}
+
+/**
+ * Export the graph in Graphviz DOT format, with one cluster per subroutine
+ */
+pub fn cfg_to_dot(graph: &ControlFlowGraph, symbols: &SymbolTable) -> String {
+    let mut dot = String::new();
+    writeln!(dot, "digraph cfg {{").unwrap();
+    writeln!(dot, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+
+    // A node can only be drawn in one cluster, blocks shared by several subroutines go to the first one
+    let mut placed = BTreeSet::new();
+    for (entry, members) in &graph.subroutines {
+        writeln!(dot, "    subgraph \"cluster_{:03x}\" {{", entry).unwrap();
+        writeln!(dot, "        label=\"{}\";", subroutine_name(*entry, symbols)).unwrap();
+        for start in members {
+            if placed.insert(*start) {
+                writeln!(dot, "        \"{:#05x}\";", start).unwrap();
+            }
+        }
+        writeln!(dot, "    }}").unwrap();
+    }
+
+    for block in graph.blocks.values() {
+        let mut label = String::new();
+        let mut color = "black";
+        for address in &block.instructions {
+            let ins = read_opcode(&graph.rom, *address).unwrap();
+            if let Some(name) = symbol_name(symbols, *address) {
+                write!(label, "{}:\\l", name).unwrap();
+            }
+            write!(label, "{:03x}: {}\\l", address, disassemble_symbolic(ins, symbols)).unwrap();
+            if graph.self_modifying.iter().any(|(a, _)| a == address) {
+                color = "orange";
+            }
+            if graph.computed_jumps.contains(address) {
+                color = "red";
+            }
+        }
+        writeln!(dot, "    \"{:#05x}\" [label=\"{}\", color={}];", block.start, label, color).unwrap();
+        for (target, kind) in &block.successors {
+            let style = match kind {
+                EdgeKind::Call => "dashed",
+                EdgeKind::Indirect => "dotted",
+                _ => "solid",
+            };
+            writeln!(dot, "    \"{:#05x}\" -> \"{:#05x}\" [label=\"{}\", style={}];",
+                     block.start, target, edge_name(*kind), style).unwrap();
+        }
+    }
+    writeln!(dot, "}}").unwrap();
+    return dot;
+}
+
+/**
+ * Export the graph as JSON, addresses are written as numbers
+ */
+pub fn cfg_to_json(graph: &ControlFlowGraph, symbols: &SymbolTable) -> String {
+    let mut json = String::new();
+    writeln!(json, "{{").unwrap();
+    writeln!(json, "  \"entry\": {},", START_PRG).unwrap();
+
+    writeln!(json, "  \"blocks\": [").unwrap();
+    let blocks: Vec<String> = graph.blocks.values().map(|block| {
+        let instructions: Vec<String> = block.instructions.iter().map(|address| {
+            let ins = read_opcode(&graph.rom, *address).unwrap();
+            format!("{{\"address\": {}, \"opcode\": {}, \"text\": \"{}\"}}", address, ins, disassemble_symbolic(ins, symbols))
+        }).collect();
+        let successors: Vec<String> = block.successors.iter().map(|(target, kind)| {
+            format!("{{\"target\": {}, \"kind\": \"{}\"}}", target, edge_name(*kind))
+        }).collect();
+        format!("    {{\"start\": {}, \"returns\": {}, \"instructions\": [{}], \"successors\": [{}]}}",
+                block.start, block.returns, instructions.join(", "), successors.join(", "))
+    }).collect();
+    writeln!(json, "{}", blocks.join(",\n")).unwrap();
+    writeln!(json, "  ],").unwrap();
+
+    writeln!(json, "  \"subroutines\": [").unwrap();
+    let subroutines: Vec<String> = graph.subroutines.iter().map(|(entry, members)| {
+        let members: Vec<String> = members.iter().map(|m| m.to_string()).collect();
+        format!("    {{\"entry\": {}, \"name\": \"{}\", \"blocks\": [{}]}}", entry, subroutine_name(*entry, symbols), members.join(", "))
+    }).collect();
+    writeln!(json, "{}", subroutines.join(",\n")).unwrap();
+    writeln!(json, "  ],").unwrap();
+
+    let computed: Vec<String> = graph.computed_jumps.iter().map(|a| a.to_string()).collect();
+    writeln!(json, "  \"computed_jumps\": [{}],", computed.join(", ")).unwrap();
+    let writes: Vec<String> = graph.self_modifying.iter()
+        .map(|(address, target)| format!("{{\"address\": {}, \"target\": {}}}", address, target))
+        .collect();
+    writeln!(json, "  \"self_modifying\": [{}],", writes.join(", ")).unwrap();
+    let outside: Vec<String> = graph.out_of_rom.iter().map(|a| a.to_string()).collect();
+    writeln!(json, "  \"out_of_rom\": [{}]", outside.join(", ")).unwrap();
+    writeln!(json, "}}").unwrap();
+    return json;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chip8_symbols::build_symbol_table;
+    use super::*;
+
+    // 200: CALL 208, 202: SE V0 5, 204: JP 200, 206: JP V0 20C, 208: LD V0 1, 20A: RET, 20C: jump table
+    const ROM: [u8; 16] = [0x22, 0x08, 0x30, 0x05, 0x12, 0x00, 0xB2, 0x0C, 0x60, 0x01, 0x00, 0xEE, 0x12, 0x00, 0x12, 0x04];
+
+    #[test]
+    fn blocks_and_subroutines() {
+        let graph = analyze_program(&ROM);
+        assert_eq!(graph.blocks.keys().copied().collect::<Vec<u16>>(), vec![0x200, 0x202, 0x204, 0x206, 0x208]);
+        assert_eq!(graph.subroutines[&0x200], vec![0x200, 0x202, 0x204, 0x206]);
+        assert_eq!(graph.subroutines[&0x208], vec![0x208]);
+        assert!(graph.blocks[&0x208].returns);
+        // The jump table is data, the indirect edge is not followed
+        assert_eq!(graph.computed_jumps, vec![0x206]);
+        assert!(!graph.blocks.contains_key(&0x20C));
+        assert!(graph.self_modifying.is_empty() && graph.out_of_rom.is_empty());
+    }
+
+    #[test]
+    fn dot_export() {
+        let dot = cfg_to_dot(&analyze_program(&ROM), &build_symbol_table());
+        assert_eq!(dot, r#"digraph cfg {
+    node [shape=box, fontname="monospace"];
+    subgraph "cluster_200" {
+        label="main";
+        "0x200";
+        "0x202";
+        "0x204";
+        "0x206";
+    }
+    subgraph "cluster_208" {
+        label="sub_208";
+        "0x208";
+    }
+    "0x200" [label="200: CALL 0x208\l", color=black];
+    "0x200" -> "0x208" [label="call", style=dashed];
+    "0x200" -> "0x202" [label="next", style=solid];
+    "0x202" [label="202: SE V0, 0x05\l", color=black];
+    "0x202" -> "0x204" [label="next", style=solid];
+    "0x202" -> "0x206" [label="skip", style=solid];
+    "0x204" [label="204: JP 0x200\l", color=black];
+    "0x204" -> "0x200" [label="jump", style=solid];
+    "0x206" [label="206: JP V0, 0x20c\l", color=red];
+    "0x206" -> "0x20c" [label="indirect", style=dotted];
+    "0x208" [label="208: LD V0, 0x01\l20a: RET\l", color=black];
+}
+"#);
+    }
+
+    #[test]
+    fn json_export() {
+        let json = cfg_to_json(&analyze_program(&ROM), &build_symbol_table());
+        assert_eq!(json, r#"{
+  "entry": 512,
+  "blocks": [
+    {"start": 512, "returns": false, "instructions": [{"address": 512, "opcode": 8712, "text": "CALL 0x208"}], "successors": [{"target": 520, "kind": "call"}, {"target": 514, "kind": "next"}]},
+    {"start": 514, "returns": false, "instructions": [{"address": 514, "opcode": 12293, "text": "SE V0, 0x05"}], "successors": [{"target": 516, "kind": "next"}, {"target": 518, "kind": "skip"}]},
+    {"start": 516, "returns": false, "instructions": [{"address": 516, "opcode": 4608, "text": "JP 0x200"}], "successors": [{"target": 512, "kind": "jump"}]},
+    {"start": 518, "returns": false, "instructions": [{"address": 518, "opcode": 45580, "text": "JP V0, 0x20c"}], "successors": [{"target": 524, "kind": "indirect"}]},
+    {"start": 520, "returns": true, "instructions": [{"address": 520, "opcode": 24577, "text": "LD V0, 0x01"}, {"address": 522, "opcode": 238, "text": "RET"}], "successors": []}
+  ],
+  "subroutines": [
+    {"entry": 512, "name": "main", "blocks": [512, 514, 516, 518]},
+    {"entry": 520, "name": "sub_208", "blocks": [520]}
+  ],
+  "computed_jumps": [518],
+  "self_modifying": [],
+  "out_of_rom": []
+}
+"#);
+    }
+}
@@ -0,0 +1,554 @@
+#![allow(clippy::needless_return)]
+
+use std::collections::VecDeque;
+use std::io::BufRead;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+use crate::chip8::{Chip8, KeyEvent, step};
+use crate::chip8_decompiler::subroutine_name;
+use crate::chip8_instructions::{CH8_INSTRUCTION, get_instruction_type};
+use crate::chip8_memory::START_PRG;
+use crate::chip8_symbols::{SymbolTable, describe_location, disassemble_symbolic, format_address, symbol_address};
+use crate::debug_utils::print_registers;
+
+// Number of steps between two machine snapshots
+pub const SNAPSHOT_INTERVAL: u64 = 500;
+
+// Maximum number of snapshots kept, the oldest ones are dropped first
+pub const MAX_SNAPSHOTS: usize = 1000;
+
+pub struct Chip8Debugger {
+
+    // Breakpoint addresses, the machine stops when PC reaches one of them
+    pub(crate) breakpoints: Vec<u16>,
+
+    // Watched memory addresses, the machine stops after an instruction changes one of them
+    pub(crate) watchpoints: Vec<u16>,
+
+    // When paused, the machine only moves through debugger commands
+    pub(crate) paused: bool,
+
+    // Number of steps executed since the program was loaded
+    pub(crate) step_count: u64,
+
+    // Periodic machine snapshots (step count, machine), oldest first
+    snapshots: VecDeque<(u64, Chip8)>,
+
+    // Key events queued in the machine (step count when queued, event), oldest first
+    inputs: VecDeque<(u64, KeyEvent)>,
+
+    // Commands typed in the console
+    commands: Receiver<String>,
+
+    // Labels used to show and parse addresses
+    pub(crate) symbols: SymbolTable,
+
+    // Print every executed instruction
+    pub(crate) trace: bool,
+}
+
+/**
+ * Create a debugger attached to the console
+ *
+ * Commands are read from stdin on a separate thread, so the emulator window keeps running
+ * while waiting for input.
+ */
+pub fn build_chip8_debugger(symbols: SymbolTable) -> Chip8Debugger {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let stdin = std::io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(line) => {
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+    println!("Debugger ready, type 'help' for the list of commands");
+    return Chip8Debugger {
+        breakpoints: Vec::new(),
+        watchpoints: Vec::new(),
+        paused: false,
+        step_count: 0,
+        snapshots: VecDeque::new(),
+        inputs: VecDeque::new(),
+        commands: receiver,
+        symbols,
+        trace: false,
+    };
+}
+
+/**
+ * Execute one instruction under the debugger
+ *
+ * Records the keyboard state and takes snapshots so the step can be replayed later.
+ * Returns false when a breakpoint or a watchpoint stopped the machine.
+ */
+#[allow(clippy::manual_is_multiple_of)]
+pub fn debugger_step(debugger: &mut Chip8Debugger, device: &mut Chip8) -> bool {
+    // After going back to a snapshot, the step it was taken at already has one
+    let saved = debugger.snapshots.back().map(|(count, _)| *count) == Some(debugger.step_count);
+    if debugger.step_count % SNAPSHOT_INTERVAL == 0 && !saved {
+        take_snapshot(debugger, device);
+    }
+    record_input(debugger, device);
+    if debugger.trace {
+        println!("{}", describe_instruction(debugger, device));
+    }
+    let mut hit = step_and_check(debugger, device);
+    debugger.step_count += 1;
+    if let Some(warning) = device.stack_warning.take() {
+        println!("Warning : {}", warning);
+        hit = true;
+    }
+    if hit {
+        debugger.paused = true;
+        print_stop(debugger, device);
+        return false;
+    }
+    return true;
+}
+
+/**
+ * Forget the recorded history, after the machine was replaced by a new one
+ */
+pub fn clear_history(debugger: &mut Chip8Debugger) {
+    debugger.snapshots.clear();
+    debugger.inputs.clear();
+    debugger.step_count = 0;
+}
+
+/**
+ * Handle every command typed since the last call
+ */
+pub fn poll_debugger_commands(debugger: &mut Chip8Debugger, device: &mut Chip8) {
+    while let Ok(line) = debugger.commands.try_recv() {
+        run_command(debugger, device, &line);
+    }
+}
+
+/**
+ * Parse and run a single debugger command
+ */
+pub fn run_command(debugger: &mut Chip8Debugger, device: &mut Chip8, line: &str) {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if words.is_empty() {
+        return;
+    }
+    match words[0] {
+        "break" | "b" => {
+            match words.get(1).and_then(|w| parse_address(debugger, w)) {
+                Some(address) => {
+                    if !debugger.breakpoints.contains(&address) {
+                        debugger.breakpoints.push(address);
+                    }
+                    println!("Breakpoint at {}", format_address(&debugger.symbols, address));
+                }
+                None => println!("Usage: break <address>"),
+            }
+        }
+        "watch" | "w" => {
+            match words.get(1).and_then(|w| parse_address(debugger, w)) {
+                Some(address) => {
+                    if !debugger.watchpoints.contains(&address) {
+                        debugger.watchpoints.push(address);
+                    }
+                    println!("Watchpoint at {}", format_address(&debugger.symbols, address));
+                }
+                None => println!("Usage: watch <address>"),
+            }
+        }
+        "delete" | "d" => {
+            match words.get(1).and_then(|w| parse_address(debugger, w)) {
+                Some(address) => {
+                    debugger.breakpoints.retain(|a| *a != address);
+                    debugger.watchpoints.retain(|a| *a != address);
+                    println!("Removed {}", format_address(&debugger.symbols, address));
+                }
+                None => println!("Usage: delete <address>"),
+            }
+        }
+        "continue" | "c" => {
+            debugger.paused = false;
+        }
+        "pause" | "p" => {
+            debugger.paused = true;
+            print_stop(debugger, device);
+        }
+        "step" | "s" => {
+            debugger.paused = true;
+            let count = parse_count(words.get(1));
+            for _ in 0..count {
+                if !debugger_step(debugger, device) {
+                    return;
+                }
+            }
+            print_stop(debugger, device);
+        }
+        "rstep" | "rs" => {
+            debugger.paused = true;
+            let count = parse_count(words.get(1));
+            let target = debugger.step_count.saturating_sub(count);
+            if !rewind_to(debugger, device, target) {
+                println!("Reached the start of the recorded history");
+            }
+            print_stop(debugger, device);
+        }
+        "rcontinue" | "rc" => {
+            debugger.paused = true;
+            if !reverse_continue(debugger, device) {
+                println!("No breakpoint or watchpoint hit in the recorded history");
+            }
+            print_stop(debugger, device);
+        }
+        "regs" | "r" => {
+            print_stop(debugger, device);
+        }
+        "stack" | "bt" => {
+            print_call_stack(debugger, device);
+        }
+        "trace" | "t" => {
+            debugger.trace = !debugger.trace;
+            println!("Trace {}", if debugger.trace { "on" } else { "off" });
+        }
+        "help" | "h" => {
+            println!("break <addr>   (b)   stop when PC reaches addr (address or label)");
+            println!("watch <addr>   (w)   stop after memory at addr changes (address or label)");
+            println!("delete <addr>  (d)   remove a breakpoint or watchpoint");
+            println!("continue       (c)   resume execution");
+            println!("pause          (p)   pause execution");
+            println!("step [n]       (s)   execute n instructions");
+            println!("rstep [n]      (rs)  go back n instructions");
+            println!("rcontinue      (rc)  go back to the previous breakpoint or watchpoint hit");
+            println!("regs           (r)   print the registers");
+            println!("stack          (bt)  print the call stack");
+            println!("trace          (t)   print every executed instruction");
+        }
+        _ => println!("Unknown command '{}', type 'help' for the list of commands", words[0]),
+    }
+}
+
+/**
+ * Parse a label name or an hexadecimal address, with or without 0x prefix
+ */
+fn parse_address(debugger: &Chip8Debugger, word: &str) -> Option<u16> {
+    if let Some(address) = symbol_address(&debugger.symbols, word) {
+        return Some(address);
+    }
+    let digits = word.trim_start_matches("0x").trim_start_matches("0X");
+    return u16::from_str_radix(digits, 16).ok();
+}
+
+/**
+ * Parse the optional repeat count of step commands
+ */
+fn parse_count(word: Option<&&str>) -> u64 {
+    return word.and_then(|w| w.parse().ok()).unwrap_or(1);
+}
+
+/**
+ * Execute one instruction and tell whether the resulting state is a breakpoint or watchpoint hit
+ */
+fn step_and_check(debugger: &Chip8Debugger, device: &mut Chip8) -> bool {
+    let watched: Vec<u8> = debugger.watchpoints.iter()
+        .map(|a| device.memory[*a as usize])
+        .collect();
+    step(device);
+    let watch_hit = debugger.watchpoints.iter().zip(watched.iter())
+        .any(|(a, before)| device.memory[*a as usize] != *before);
+    return watch_hit || debugger.breakpoints.contains(&device.pc);
+}
+
+/**
+ * Remember the key events queued since the last step, they are the last ones of the machine queue
+ */
+fn record_input(debugger: &mut Chip8Debugger, device: &mut Chip8) {
+    let queued = device.key_events.len();
+    for event in device.key_events.iter().skip(queued - device.new_key_events.min(queued)) {
+        debugger.inputs.push_back((debugger.step_count, *event));
+    }
+    device.new_key_events = 0;
+}
+
+/**
+ * Store a copy of the machine, dropping the oldest snapshot when the history is full
+ */
+fn take_snapshot(debugger: &mut Chip8Debugger, device: &Chip8) {
+    let mut snapshot = device.clone();
+    // The events queued before this step are recorded as inputs and queued again when it is replayed
+    let queued = snapshot.key_events.len();
+    snapshot.key_events.truncate(queued - snapshot.new_key_events.min(queued));
+    snapshot.new_key_events = 0;
+    debugger.snapshots.push_back((debugger.step_count, snapshot));
+    if debugger.snapshots.len() > MAX_SNAPSHOTS {
+        debugger.snapshots.pop_front();
+        let oldest = debugger.snapshots[0].0;
+        // Events queued before the oldest snapshot are part of it
+        while !debugger.inputs.is_empty() && debugger.inputs[0].0 < oldest {
+            debugger.inputs.pop_front();
+        }
+    }
+}
+
+/**
+ * Re-execute one recorded step, queuing the key events the machine received before it
+ */
+fn replay_step(debugger: &Chip8Debugger, device: &mut Chip8, count: u64) -> bool {
+    let first = debugger.inputs.partition_point(|(queued, _)| *queued < count);
+    for (_, event) in debugger.inputs.iter().skip(first).take_while(|(queued, _)| *queued == count) {
+        device.key_events.push_back(*event);
+    }
+    return step_and_check(debugger, device);
+}
+
+/**
+ * Restore the snapshot at the given index, keeping host side settings such as turbo
+ */
+fn restore_snapshot(debugger: &Chip8Debugger, device: &mut Chip8, index: usize) -> u64 {
+    let (count, snapshot) = &debugger.snapshots[index];
+    let turbo = device.turbo;
+    *device = snapshot.clone();
+    device.turbo = turbo;
+    return *count;
+}
+
+/**
+ * Bring the machine back to the state it had after `target` steps
+ *
+ * The closest snapshot before the target is restored, then the steps are re-executed.
+ * The history after the target is discarded since new input will make it diverge.
+ * Returns false when the target is older than the recorded history.
+ */
+fn rewind_to(debugger: &mut Chip8Debugger, device: &mut Chip8, target: u64) -> bool {
+    let index = match debugger.snapshots.iter().rposition(|(count, _)| *count <= target) {
+        Some(index) => index,
+        None => {
+            if debugger.snapshots.is_empty() {
+                return false;
+            }
+            0
+        }
+    };
+    let mut count = restore_snapshot(debugger, device, index);
+    let reached = count <= target;
+    while count < target {
+        replay_step(debugger, device, count);
+        count += 1;
+    }
+    debugger.step_count = count;
+    device.stack_warning = None;
+    debugger.snapshots.truncate(index + 1);
+    while !debugger.inputs.is_empty() && debugger.inputs[debugger.inputs.len() - 1].0 >= count {
+        debugger.inputs.pop_back();
+    }
+    return reached;
+}
+
+/**
+ * Go back to the last breakpoint or watchpoint hit before the current step
+ *
+ * Snapshot intervals are replayed from the most recent one backward until a hit is found.
+ */
+fn reverse_continue(debugger: &mut Chip8Debugger, device: &mut Chip8) -> bool {
+    let current = debugger.step_count;
+    let mut scratch = device.clone();
+    for index in (0..debugger.snapshots.len()).rev() {
+        let mut count = restore_snapshot(debugger, &mut scratch, index);
+        if count >= current {
+            continue;
+        }
+        let end = match debugger.snapshots.get(index + 1) {
+            Some((next, _)) => (*next).min(current - 1),
+            None => current - 1,
+        };
+        let mut last_hit = None;
+        while count < end {
+            let hit = replay_step(debugger, &mut scratch, count);
+            count += 1;
+            if hit {
+                last_hit = Some(count);
+            }
+        }
+        if let Some(target) = last_hit {
+            return rewind_to(debugger, device, target);
+        }
+    }
+    rewind_to(debugger, device, 0);
+    return false;
+}
+
+/**
+ * Describe the instruction at PC, with its location relative to the closest label
+ */
+fn describe_instruction(debugger: &Chip8Debugger, device: &Chip8) -> String {
+    let pc = device.pc as usize;
+    let ins: u16 = ((device.memory[pc] as u16) << 8) | device.memory[pc + 1] as u16;
+    let location = match describe_location(&debugger.symbols, device.pc) {
+        Some(name) => format!("{:#05x} <{}>", device.pc, name),
+        None => format!("{:#05x}", device.pc),
+    };
+    return format!("{} | {:04x} {}", location, ins, disassemble_symbolic(ins, &debugger.symbols));
+}
+
+/**
+ * Print where the machine stopped
+ */
+fn print_stop(debugger: &Chip8Debugger, device: &Chip8) {
+    println!("step {} | pc {} | i {} | dt {} | st {}",
+             debugger.step_count, describe_instruction(debugger, device),
+             format_address(&debugger.symbols, device.i), device.dt, device.st);
+    print_registers(device);
+    println!();
+}
+
+/**
+ * Print the active frames, innermost first
+ *
+ * The stack holds the address of each CALL, the entry of the callee is read back from that CALL.
+ */
+pub fn print_call_stack(debugger: &Chip8Debugger, device: &Chip8) {
+    let symbols = &debugger.symbols;
+    let location = |address: u16| match describe_location(symbols, address) {
+        Some(name) => format!("{:#05x} <{}>", address, name),
+        None => format!("{:#05x}", address),
+    };
+    println!("#0  pc {}", location(device.pc));
+    for frame in (0..device.sp as usize).rev() {
+        let caller = device.stack[frame];
+        let ins: u16 = ((device.memory[caller as usize] as u16) << 8) | device.memory[caller as usize + 1] as u16;
+        let callee = if get_instruction_type(ins) == CH8_INSTRUCTION::CALL {
+            let entry = ins & 0x0FFF;
+            format!("{} ({:#05x})", subroutine_name(entry, symbols), entry)
+        } else {
+            "? (CALL was overwritten)".to_string()
+        };
+        println!("#{}  in {} called from {}", device.sp as usize - frame, callee, location(caller));
+    }
+    let entry = START_PRG as u16;
+    println!("#{}  in {} ({:#05x})", device.sp as usize + 1, subroutine_name(entry, symbols), entry);
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use crate::chip8::{build_chip8, load_rom, queue_key_event};
+    use crate::chip8_symbols::{build_symbol_table, parse_symbols};
+    use super::*;
+
+    const BRIX: &[u8] = include_bytes!("../resources/BRIX");
+
+    fn brix_machine() -> Chip8 {
+        let mut device = build_chip8();
+        device.rng = StdRng::seed_from_u64(8);
+        load_rom(&mut device, BRIX);
+        return device;
+    }
+
+    /**
+     * Key 4 goes down and up at fixed steps, so the replayed history holds key events
+     */
+    fn press_keys(device: &mut Chip8, step_count: u64) {
+        match step_count % 700 {
+            100 => queue_key_event(device, 4, true),
+            400 => queue_key_event(device, 4, false),
+            _ => {}
+        }
+    }
+
+    fn assert_same_machine(device: &Chip8, expected: &Chip8) {
+        assert_eq!(device.pc, expected.pc);
+        assert_eq!(device.i, expected.i);
+        assert_eq!(device.vn, expected.vn);
+        assert_eq!(device.sp, expected.sp);
+        assert_eq!(device.stack, expected.stack);
+        assert_eq!((device.dt, device.st), (expected.dt, expected.st));
+        assert_eq!(device.ticks, expected.ticks);
+        assert_eq!(device.keyboard, expected.keyboard);
+        assert_eq!(device.key_events, expected.key_events);
+        assert_eq!(device.memory[..], expected.memory[..]);
+        assert_eq!(device.display.display_data, expected.display.display_data);
+    }
+
+    #[test]
+    fn reverse_step_matches_a_fresh_run() {
+        let steps = 3000;
+        let back = 1234;
+        let mut debugger = build_chip8_debugger(build_symbol_table());
+        let mut device = brix_machine();
+        for n in 0..steps {
+            press_keys(&mut device, n);
+            debugger_step(&mut debugger, &mut device);
+        }
+        run_command(&mut debugger, &mut device, &format!("rstep {}", back));
+        assert_eq!(debugger.step_count, steps - back);
+
+        let mut expected = brix_machine();
+        for n in 0..steps - back {
+            press_keys(&mut expected, n);
+            step(&mut expected);
+        }
+        assert_same_machine(&device, &expected);
+    }
+
+    #[test]
+    fn replay_after_reverse_step_keeps_one_snapshot_per_step() {
+        let mut debugger = build_chip8_debugger(build_symbol_table());
+        let mut device = brix_machine();
+        for n in 0..1200 {
+            press_keys(&mut device, n);
+            debugger_step(&mut debugger, &mut device);
+        }
+        run_command(&mut debugger, &mut device, "rstep 700");
+        assert_eq!(debugger.step_count, 500);
+        for n in 500..1600 {
+            press_keys(&mut device, n);
+            debugger_step(&mut debugger, &mut device);
+        }
+        let counts: Vec<u64> = debugger.snapshots.iter().map(|(count, _)| *count).collect();
+        assert_eq!(counts, vec![0, 500, 1000, 1500]);
+    }
+
+    #[test]
+    fn reverse_continue_stops_on_the_last_breakpoint_hit() {
+        let mut debugger = build_chip8_debugger(build_symbol_table());
+        let mut device = brix_machine();
+        for n in 0..1500 {
+            press_keys(&mut device, n);
+            debugger_step(&mut debugger, &mut device);
+        }
+        let address = device.pc;
+        let hit = debugger.step_count;
+        for n in 1500..2200 {
+            press_keys(&mut device, n);
+            debugger_step(&mut debugger, &mut device);
+        }
+        debugger.breakpoints.push(address);
+        run_command(&mut debugger, &mut device, "rcontinue");
+        assert_eq!(device.pc, address);
+        assert!(debugger.step_count >= hit && debugger.step_count < 2200);
+    }
+
+    #[test]
+    fn trace_names_the_labels() {
+        let symbols = parse_symbols("main = 0x200\ndraw = 0x208\nsprite = 0x300", "brix.sym").unwrap();
+        let debugger = build_chip8_debugger(symbols);
+        let mut device = build_chip8();
+        load_rom(&mut device, &[0x22, 0x08, 0xA3, 0x00, 0x12, 0x04, 0x00, 0x00, 0xD0, 0x15, 0x00, 0xEE]);
+        assert_eq!(describe_instruction(&debugger, &device), "0x200 <main> | 2208 CALL draw");
+        device.pc = 0x202;
+        assert_eq!(describe_instruction(&debugger, &device), "0x202 <main+2> | a300 LD I, sprite");
+        device.pc = 0x20A;
+        assert_eq!(describe_instruction(&debugger, &device), "0x20a <draw+2> | 00ee RET");
+
+        let debugger = build_chip8_debugger(build_symbol_table());
+        device.pc = 0x204;
+        assert_eq!(describe_instruction(&debugger, &device), "0x204 | 1204 JP 0x204");
+    }
+}
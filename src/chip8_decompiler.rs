@@ -0,0 +1,360 @@
+#![allow(clippy::needless_return)]
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::chip8_analysis::{ControlFlowGraph, read_opcode};
+use crate::chip8_instructions::{CH8_INSTRUCTION, get_instruction_type};
+use crate::chip8_memory::START_PRG;
+use crate::chip8_symbols::{SymbolTable, format_address, symbol_name};
+
+// Indentation of one nesting level
+const INDENT: &str = "    ";
+
+/**
+ * State of the lifting of one subroutine
+ */
+struct Lifter<'a> {
+
+    // Program bytes, loaded at START_PRG
+    rom: &'a [u8],
+
+    // Names used for subroutines, labels and addresses
+    symbols: &'a SymbolTable,
+
+    // Addresses of the instructions of the subroutine, sorted
+    addresses: Vec<u16>,
+
+    // Enclosing loops (continue target, break target), innermost last
+    loops: Vec<(u16, u16)>,
+
+    // Targets of the gotos emitted so far
+    gotos: BTreeSet<u16>,
+
+    // Labels to print, found by a previous pass
+    labels: BTreeSet<u16>,
+
+    // Generated pseudo-code
+    out: String,
+}
+
+/**
+ * Decompile every subroutine of the graph into structured pseudo-code
+ */
+pub fn decompile(graph: &ControlFlowGraph, symbols: &SymbolTable) -> String {
+    let mut out = String::new();
+    for (entry, members) in &graph.subroutines {
+        let mut addresses: Vec<u16> = members.iter()
+            .flat_map(|start| graph.blocks[start].instructions.iter().copied())
+            .collect();
+        addresses.sort_unstable();
+        addresses.dedup();
+
+        // The first pass finds the labels needed by gotos, the second one prints them
+        let mut lifter = Lifter { rom: &graph.rom, symbols, addresses, loops: Vec::new(), gotos: BTreeSet::new(), labels: BTreeSet::new(), out: String::new() };
+        lift_subroutine(&mut lifter, *entry);
+        lifter.labels = std::mem::take(&mut lifter.gotos);
+        lifter.out.clear();
+        lift_subroutine(&mut lifter, *entry);
+
+        writeln!(out, "{}() {{", subroutine_name(*entry, symbols)).unwrap();
+        out.push_str(&lifter.out);
+        writeln!(out, "}}").unwrap();
+        writeln!(out).unwrap();
+    }
+    return out;
+}
+
+/**
+ * Name of the subroutine starting at an address, from the symbols when known
+ */
+pub fn subroutine_name(entry: u16, symbols: &SymbolTable) -> String {
+    if let Some(name) = symbol_name(symbols, entry) {
+        return name;
+    }
+    if entry as usize == START_PRG {
+        return "main".to_string();
+    }
+    return format!("sub_{:03x}", entry);
+}
+
+fn label_name(address: u16, symbols: &SymbolTable) -> String {
+    if let Some(name) = symbol_name(symbols, address) {
+        return name;
+    }
+    return format!("label_{:03x}", address);
+}
+
+fn lift_subroutine(lifter: &mut Lifter, entry: u16) {
+    if lifter.addresses.first() != Some(&entry) {
+        emit_goto(lifter, entry, 1);
+    }
+    let end = lifter.addresses.len();
+    lift_range(lifter, 0, end, 1);
+}
+
+fn opcode(lifter: &Lifter, index: usize) -> u16 {
+    return read_opcode(lifter.rom, lifter.addresses[index]).unwrap();
+}
+
+fn index_of(lifter: &Lifter, address: u16) -> Option<usize> {
+    return lifter.addresses.binary_search(&address).ok();
+}
+
+fn is_skip(ins: u16) -> bool {
+    return matches!(get_instruction_type(ins),
+        CH8_INSTRUCTION::SE | CH8_INSTRUCTION::SNE | CH8_INSTRUCTION::SEVxVy |
+        CH8_INSTRUCTION::SNEVxVy | CH8_INSTRUCTION::SKPVx | CH8_INSTRUCTION::SKNPVx);
+}
+
+/**
+ * Target of an unconditional JP, None for any other instruction
+ */
+fn jump_target(ins: u16) -> Option<u16> {
+    if get_instruction_type(ins) == CH8_INSTRUCTION::JP {
+        return Some(ins & 0x0FFF);
+    }
+    return None;
+}
+
+fn emit_line(lifter: &mut Lifter, depth: usize, line: &str) {
+    writeln!(lifter.out, "{}{}", INDENT.repeat(depth), line).unwrap();
+}
+
+fn emit_goto(lifter: &mut Lifter, target: u16, depth: usize) {
+    lifter.gotos.insert(target);
+    let label = label_name(target, lifter.symbols);
+    emit_line(lifter, depth, &format!("goto {};", label));
+}
+
+/**
+ * Lift the instructions between two indexes of the subroutine
+ */
+fn lift_range(lifter: &mut Lifter, from: usize, to: usize, depth: usize) {
+    let mut i = from;
+    while i < to {
+        let address = lifter.addresses[i];
+        // The head of a loop gets its label before the loop keyword, not inside the body
+        let loop_head = i == from && lifter.loops.last().map(|l| l.0) == Some(address);
+        if lifter.labels.contains(&address) && !loop_head {
+            let label = label_name(address, lifter.symbols);
+            emit_line(lifter, depth.saturating_sub(1), &format!("{}:", label));
+        }
+        i = lift_loop(lifter, i, to, depth)
+            .or_else(|| lift_skip(lifter, i, to, depth))
+            .unwrap_or_else(|| {
+                lift_single(lifter, i, depth);
+                i + 1
+            });
+    }
+}
+
+/**
+ * A backward JP to the current instruction closes a loop, returns the index following the loop
+ */
+fn lift_loop(lifter: &mut Lifter, i: usize, to: usize, depth: usize) -> Option<usize> {
+    let head = lifter.addresses[i];
+    if lifter.loops.last().map(|l| l.0) == Some(head) {
+        return None;
+    }
+    let j = (i + 1..to).rev().find(|j| jump_target(opcode(lifter, *j)) == Some(head))?;
+    let exit = lifter.addresses[j] + 2;
+    lifter.loops.push((head, exit));
+
+    let conditional = j > i && is_skip(opcode(lifter, j - 1)) && lifter.addresses[j - 1] + 2 == lifter.addresses[j];
+    if conditional {
+        emit_line(lifter, depth, "do {");
+        lift_range(lifter, i, j - 1, depth + 1);
+        let condition = skip_condition(opcode(lifter, j - 1), false);
+        emit_line(lifter, depth, &format!("}} while ({});", condition));
+    } else {
+        emit_line(lifter, depth, "loop {");
+        lift_range(lifter, i, j, depth + 1);
+        emit_line(lifter, depth, "}");
+    }
+    lifter.loops.pop();
+    return Some(j + 1);
+}
+
+/**
+ * A skip instruction with the instruction it may skip, returns the index following the construct
+ */
+fn lift_skip(lifter: &mut Lifter, i: usize, to: usize, depth: usize) -> Option<usize> {
+    let ins = opcode(lifter, i);
+    if !is_skip(ins) || i + 1 >= to || lifter.addresses[i + 1] != lifter.addresses[i] + 2 {
+        return None;
+    }
+    let next = opcode(lifter, i + 1);
+    // The next instruction runs when the skip condition is false
+    let runs = skip_condition(ins, false);
+    let skips = skip_condition(ins, true);
+
+    let target = match jump_target(next) {
+        Some(target) => target,
+        None => {
+            if is_skip(next) {
+                return None;
+            }
+            emit_line(lifter, depth, &format!("if ({}) {{", runs));
+            lift_single(lifter, i + 1, depth + 1);
+            emit_line(lifter, depth, "}");
+            return Some(i + 2);
+        }
+    };
+
+    if let Some((head, exit)) = lifter.loops.last().copied() {
+        if target == exit {
+            emit_line(lifter, depth, &format!("if ({}) break;", runs));
+            return Some(i + 2);
+        }
+        if target == head {
+            emit_line(lifter, depth, &format!("if ({}) continue;", runs));
+            return Some(i + 2);
+        }
+    }
+
+    // Forward jump over a block: if, possibly with an else part
+    let k = match index_of(lifter, target) {
+        Some(k) if k > i + 1 && k <= to => k,
+        _ => {
+            emit_line(lifter, depth, &format!("if ({}) {{", runs));
+            emit_goto(lifter, target, depth + 1);
+            emit_line(lifter, depth, "}");
+            return Some(i + 2);
+        }
+    };
+    let else_end = match jump_target(opcode(lifter, k - 1)) {
+        Some(end) if k - 1 > i + 1 && !is_skip(opcode(lifter, k - 2)) => {
+            index_of(lifter, end).filter(|e| *e > k && *e <= to)
+        }
+        _ => None,
+    };
+    emit_line(lifter, depth, &format!("if ({}) {{", skips));
+    match else_end {
+        Some(e) => {
+            lift_range(lifter, i + 2, k - 1, depth + 1);
+            emit_line(lifter, depth, "} else {");
+            lift_range(lifter, k, e, depth + 1);
+            emit_line(lifter, depth, "}");
+            return Some(e);
+        }
+        None => {
+            lift_range(lifter, i + 2, k, depth + 1);
+            emit_line(lifter, depth, "}");
+            return Some(k);
+        }
+    }
+}
+
+/**
+ * Lift an instruction on its own
+ */
+fn lift_single(lifter: &mut Lifter, i: usize, depth: usize) {
+    let ins = opcode(lifter, i);
+    let address = lifter.addresses[i];
+    match get_instruction_type(ins) {
+        CH8_INSTRUCTION::JP => {
+            let target = ins & 0x0FFF;
+            match lifter.loops.last().copied() {
+                Some((head, _)) if head == target => emit_line(lifter, depth, "continue;"),
+                Some((_, exit)) if exit == target => emit_line(lifter, depth, "break;"),
+                _ => {
+                    if target == address {
+                        emit_line(lifter, depth, "halt();");
+                    } else {
+                        emit_goto(lifter, target, depth);
+                    }
+                }
+            }
+        }
+        _ => {
+            let statement = statement(ins, lifter.symbols);
+            emit_line(lifter, depth, &statement);
+        }
+    }
+}
+
+/**
+ * Condition under which a skip instruction skips (or not) the next instruction
+ */
+fn skip_condition(ins: u16, skipped: bool) -> String {
+    let x = (ins & 0x0F00) >> 8;
+    let y = (ins & 0x00F0) >> 4;
+    let kk = ins & 0x00FF;
+    let (equal, different) = match get_instruction_type(ins) {
+        CH8_INSTRUCTION::SE => ("==", "!="),
+        CH8_INSTRUCTION::SNE => ("!=", "=="),
+        CH8_INSTRUCTION::SEVxVy => ("==", "!="),
+        CH8_INSTRUCTION::SNEVxVy => ("!=", "=="),
+        CH8_INSTRUCTION::SKPVx => ("", "!"),
+        _ => ("!", ""),
+    };
+    let operator = if skipped { equal } else { different };
+    return match get_instruction_type(ins) {
+        CH8_INSTRUCTION::SE | CH8_INSTRUCTION::SNE => format!("v{:x} {} {:#04x}", x, operator, kk),
+        CH8_INSTRUCTION::SEVxVy | CH8_INSTRUCTION::SNEVxVy => format!("v{:x} {} v{:x}", x, operator, y),
+        _ => format!("{}key_pressed(v{:x})", operator, x),
+    };
+}
+
+/**
+ * Pseudo-code of a non control flow instruction
+ */
+fn statement(ins: u16, symbols: &SymbolTable) -> String {
+    let x = (ins & 0x0F00) >> 8;
+    let y = (ins & 0x00F0) >> 4;
+    let n = ins & 0x000F;
+    let kk = ins & 0x00FF;
+    let nnn = ins & 0x0FFF;
+    return match get_instruction_type(ins) {
+        CH8_INSTRUCTION::SYS => format!("sys({});", format_address(symbols, nnn)),
+        CH8_INSTRUCTION::CLS => "clear();".to_string(),
+        CH8_INSTRUCTION::RET => "return;".to_string(),
+        CH8_INSTRUCTION::JP => format!("goto {};", label_name(nnn, symbols)),
+        CH8_INSTRUCTION::CALL => format!("{}();", subroutine_name(nnn, symbols)),
+        CH8_INSTRUCTION::LDVxbyte => format!("v{:x} = {:#04x};", x, kk),
+        CH8_INSTRUCTION::ADDVxbyte => format!("v{:x} += {:#04x};", x, kk),
+        CH8_INSTRUCTION::LDVxVy => format!("v{:x} = v{:x};", x, y),
+        CH8_INSTRUCTION::OR => format!("v{:x} |= v{:x};", x, y),
+        CH8_INSTRUCTION::AND => format!("v{:x} &= v{:x};", x, y),
+        CH8_INSTRUCTION::XOR => format!("v{:x} ^= v{:x};", x, y),
+        CH8_INSTRUCTION::ADDVxVy => format!("v{:x} += v{:x};  // vf = carry", x, y),
+        CH8_INSTRUCTION::SUBVxVy => format!("v{:x} -= v{:x};  // vf = no borrow", x, y),
+        CH8_INSTRUCTION::SHR => format!("v{:x} = v{:x} >> 1;  // vf = shifted bit", x, y),
+        CH8_INSTRUCTION::SUBN => format!("v{:x} = v{:x} - v{:x};  // vf = no borrow", x, y, x),
+        CH8_INSTRUCTION::SHL => format!("v{:x} = v{:x} << 1;  // vf = shifted bit", x, y),
+        CH8_INSTRUCTION::LDIaddr => format!("i = {};", format_address(symbols, nnn)),
+        CH8_INSTRUCTION::JPV0addr => format!("jump({} + v0);", format_address(symbols, nnn)),
+        CH8_INSTRUCTION::RND => format!("v{:x} = random() & {:#04x};", x, kk),
+        CH8_INSTRUCTION::DRW => format!("draw(v{:x}, v{:x}, sprite@I, {});", x, y, n),
+        CH8_INSTRUCTION::LDVxDT => format!("v{:x} = delay_timer;", x),
+        CH8_INSTRUCTION::LDVxK => format!("v{:x} = wait_key();", x),
+        CH8_INSTRUCTION::LDDTVx => format!("delay_timer = v{:x};", x),
+        CH8_INSTRUCTION::LDSTVx => format!("sound_timer = v{:x};", x),
+        CH8_INSTRUCTION::ADDIVx => format!("i += v{:x};", x),
+        CH8_INSTRUCTION::LDFVx => format!("i = font(v{:x});", x),
+        CH8_INSTRUCTION::LDBVx => format!("memory[i..i+3] = bcd(v{:x});", x),
+        CH8_INSTRUCTION::LDIVx => format!("store(v0..v{:x});", x),
+        CH8_INSTRUCTION::LDVxI => format!("v0..v{:x} = load();", x),
+        CH8_INSTRUCTION::SE | CH8_INSTRUCTION::SNE | CH8_INSTRUCTION::SEVxVy | CH8_INSTRUCTION::SNEVxVy |
+        CH8_INSTRUCTION::SKPVx | CH8_INSTRUCTION::SKNPVx => format!("skip_next_if({});", skip_condition(ins, true)),
+        CH8_INSTRUCTION::NOOP => format!("data({:#06x});", ins),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chip8_analysis::analyze_program;
+    use crate::chip8_symbols::build_symbol_table;
+    use super::*;
+
+    // 200: LD V0 0, 202: CALL 20C, 204: ADD V0 1, 206: SE V0 10, 208: JP 202, 20A: JP 20A, 20C: LD V1 5, 20E: RET
+    const ROM: [u8; 16] = [0x60, 0x00, 0x22, 0x0C, 0x70, 0x01, 0x30, 0x0A, 0x12, 0x02, 0x12, 0x0A, 0x61, 0x05, 0x00, 0xEE];
+
+    #[test]
+    fn loop_and_subroutine() {
+        let code = decompile(&analyze_program(&ROM), &build_symbol_table());
+        assert_eq!(code, "main() {\n    v0 = 0x00;\n    do {\n        sub_20c();\n        v0 += 0x01;\n    } while (v0 != 0x0a);\n\
+                          \x20   halt();\n}\n\nsub_20c() {\n    v1 = 0x05;\n    return;\n}\n\n");
+    }
+}
@@ -1,13 +1,15 @@
+#![allow(clippy::needless_return)]
 
 pub const DISPLAY_WIDTH: usize = 64;
 pub const DISPLAY_HEIGHT: usize = 32;
 
+#[derive(Clone)]
 pub struct Chip8Display{
     pub(crate) display_data: [[u8; DISPLAY_WIDTH/8];DISPLAY_HEIGHT],
 }
 
 pub fn build_chip8_display() -> Chip8Display {
-    let mut display = Chip8Display{
+    let display = Chip8Display{
         display_data: [[0b00000000; DISPLAY_WIDTH/8];DISPLAY_HEIGHT]
     };
     return display;
@@ -26,6 +28,13 @@ pub fn xor_px_at(display: &mut Chip8Display, x:usize, y:usize) -> bool{
     return previous > 0 && display.display_data[ry][rx/8]&((0x80 >> byte) as u8) == 0;
 }
 
+/**
+ * Tell whether the pixel at (x, y) is lit
+ */
+pub fn get_px_at(display: &Chip8Display, x: usize, y: usize) -> bool {
+    return display.display_data[y][x/8] & (0x80 >> (x%8)) as u8 > 0;
+}
+
 /**
  * Default Chip 8 Sprites
  */
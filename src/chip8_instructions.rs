@@ -1,15 +1,15 @@
+#![allow(clippy::needless_return)]
+
 extern crate rand;
-use crate::chip8::{Chip8, KEYBOARD_SIZE};
-use crate::chip8_display;
+use crate::chip8::{Chip8, KEYBOARD_SIZE, NO_KEY, STACK_SIZE};
 use crate::chip8_display::{DISPLAY_HEIGHT, DISPLAY_WIDTH, xor_px_at};
 
 use rand::Rng;
-use std::ops::{SubAssign, Add};
 use std::borrow::BorrowMut;
-use crate::debug_utils::{print_display, print_registers};
 
 #[derive(Debug)]
 #[derive(PartialEq, Eq)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 pub enum CH8_INSTRUCTION {
     SYS,
     CLS,
@@ -49,6 +49,7 @@ pub enum CH8_INSTRUCTION {
     NOOP,
 }
 
+#[allow(dead_code)]
 pub fn get_and_print_instruction_type(ins: u16) -> CH8_INSTRUCTION {
     let a = get_instruction_type(ins);
     println!("{:#016b} {:#04x?} | {:.16?}", ins, ins, a);
@@ -130,6 +131,55 @@ pub fn get_instruction_type(ins: u16) -> CH8_INSTRUCTION {
     return CH8_INSTRUCTION::NOOP;
 }
 
+/**
+ * Disassemble an instruction using Cowgod's mnemonics
+ */
+pub fn disassemble(ins: u16) -> String {
+    let x = (ins & 0x0F00) >> 8;
+    let y = (ins & 0x00F0) >> 4;
+    let n = ins & 0x000F;
+    let kk = ins & 0x00FF;
+    let nnn = ins & 0x0FFF;
+    return match get_instruction_type(ins) {
+        CH8_INSTRUCTION::SYS => format!("SYS {:#05x}", nnn),
+        CH8_INSTRUCTION::CLS => "CLS".to_string(),
+        CH8_INSTRUCTION::RET => "RET".to_string(),
+        CH8_INSTRUCTION::JP => format!("JP {:#05x}", nnn),
+        CH8_INSTRUCTION::CALL => format!("CALL {:#05x}", nnn),
+        CH8_INSTRUCTION::SE => format!("SE V{:X}, {:#04x}", x, kk),
+        CH8_INSTRUCTION::SNE => format!("SNE V{:X}, {:#04x}", x, kk),
+        CH8_INSTRUCTION::SEVxVy => format!("SE V{:X}, V{:X}", x, y),
+        CH8_INSTRUCTION::LDVxbyte => format!("LD V{:X}, {:#04x}", x, kk),
+        CH8_INSTRUCTION::ADDVxbyte => format!("ADD V{:X}, {:#04x}", x, kk),
+        CH8_INSTRUCTION::LDVxVy => format!("LD V{:X}, V{:X}", x, y),
+        CH8_INSTRUCTION::OR => format!("OR V{:X}, V{:X}", x, y),
+        CH8_INSTRUCTION::AND => format!("AND V{:X}, V{:X}", x, y),
+        CH8_INSTRUCTION::XOR => format!("XOR V{:X}, V{:X}", x, y),
+        CH8_INSTRUCTION::ADDVxVy => format!("ADD V{:X}, V{:X}", x, y),
+        CH8_INSTRUCTION::SUBVxVy => format!("SUB V{:X}, V{:X}", x, y),
+        CH8_INSTRUCTION::SHR => format!("SHR V{:X}, V{:X}", x, y),
+        CH8_INSTRUCTION::SUBN => format!("SUBN V{:X}, V{:X}", x, y),
+        CH8_INSTRUCTION::SHL => format!("SHL V{:X}, V{:X}", x, y),
+        CH8_INSTRUCTION::SNEVxVy => format!("SNE V{:X}, V{:X}", x, y),
+        CH8_INSTRUCTION::LDIaddr => format!("LD I, {:#05x}", nnn),
+        CH8_INSTRUCTION::JPV0addr => format!("JP V0, {:#05x}", nnn),
+        CH8_INSTRUCTION::RND => format!("RND V{:X}, {:#04x}", x, kk),
+        CH8_INSTRUCTION::DRW => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        CH8_INSTRUCTION::SKPVx => format!("SKP V{:X}", x),
+        CH8_INSTRUCTION::SKNPVx => format!("SKNP V{:X}", x),
+        CH8_INSTRUCTION::LDVxDT => format!("LD V{:X}, DT", x),
+        CH8_INSTRUCTION::LDVxK => format!("LD V{:X}, K", x),
+        CH8_INSTRUCTION::LDDTVx => format!("LD DT, V{:X}", x),
+        CH8_INSTRUCTION::LDSTVx => format!("LD ST, V{:X}", x),
+        CH8_INSTRUCTION::ADDIVx => format!("ADD I, V{:X}", x),
+        CH8_INSTRUCTION::LDFVx => format!("LD F, V{:X}", x),
+        CH8_INSTRUCTION::LDBVx => format!("LD B, V{:X}", x),
+        CH8_INSTRUCTION::LDIVx => format!("LD [I], V{:X}", x),
+        CH8_INSTRUCTION::LDVxI => format!("LD V{:X}, [I]", x),
+        CH8_INSTRUCTION::NOOP => format!("DW {:#06x}", ins),
+    };
+}
+
 pub fn exec(ins: u16, device: &mut Chip8){
     let itype = get_instruction_type(ins);
     //print_registers(device);
@@ -180,7 +230,7 @@ pub fn exec(ins: u16, device: &mut Chip8){
 00E0 - CLS
 Clear the display.
 */
-fn cls(device: &mut Chip8, ins: u16) {
+fn cls(device: &mut Chip8, _ins: u16) {
     for i in 0..DISPLAY_HEIGHT {
         for j in 0..DISPLAY_WIDTH / 8 {
             device.display.display_data[i][j] = 0x00;
@@ -195,9 +245,14 @@ fn cls(device: &mut Chip8, ins: u16) {
 Return from a subroutine.
 
 The interpreter sets the program counter to the address at the top of the stack, then subtracts 1 from the stack pointer.
+A RET with an empty stack stops the machine on it, reported in stack_warning.
 */
-fn ret(device: &mut Chip8, ins: u16) {
-    device.sp = device.sp - 1;
+fn ret(device: &mut Chip8, _ins: u16) {
+    if device.sp == 0 {
+        device.stack_warning = Some(format!("RET with an empty stack at {:#05x}", device.pc));
+        return;
+    }
+    device.sp -= 1;
     device.pc = device.stack[device.sp as usize];
     device.pc += 2;
 }
@@ -218,11 +273,16 @@ fn jp(device: &mut Chip8, ins: u16) {
 Call subroutine at nnn.
 
 The interpreter increments the stack pointer, then puts the current PC on the top of the stack. The PC is then set to nnn.
+A CALL on a full stack stops the machine on it, reported in stack_warning.
 */
 fn call(device: &mut Chip8, ins: u16) {
     let nnn:u16 = ins & 0x0FFF;
+    if device.sp as usize >= STACK_SIZE {
+        device.stack_warning = Some(format!("CALL at {:#05x} nests deeper than {} levels", device.pc, STACK_SIZE));
+        return;
+    }
     device.stack[device.sp as usize] = device.pc;
-    device.sp = device.sp + 1;
+    device.sp += 1;
     device.pc = nnn;
 }
 
@@ -325,7 +385,8 @@ Performs a bitwise OR on the values of Vx and Vy, then stores the result in Vx.
 fn or(device: &mut Chip8, ins: u16) {
     let x:usize = ((ins & 0x0F00) >> 8) as usize;
     let y:usize = ((ins & 0x00F0) >> 4) as usize;
-    device.vn[x] = device.vn[x] | device.vn[y];
+    device.vn[x] |= device.vn[y];
+    if device.quirks.logic_reset_vf { device.vn[0xF] = 0; }
     device.pc += 2;
 }
 
@@ -340,7 +401,8 @@ Performs a bitwise AND on the values of Vx and Vy, then stores the result in Vx.
 fn and(device: &mut Chip8, ins: u16) {
     let x:usize = ((ins & 0x0F00) >> 8) as usize;
     let y:usize = ((ins & 0x00F0) >> 4) as usize;
-    device.vn[x] = device.vn[x] & device.vn[y];
+    device.vn[x] &= device.vn[y];
+    if device.quirks.logic_reset_vf { device.vn[0xF] = 0; }
     device.pc += 2;
 }
 
@@ -354,7 +416,8 @@ Performs a bitwise exclusive OR on the values of Vx and Vy, then stores the resu
 fn xor(device: &mut Chip8, ins: u16) {
     let x:usize = ((ins & 0x0F00) >> 8) as usize;
     let y:usize = ((ins & 0x00F0) >> 4) as usize;
-    device.vn[x] = device.vn[x] ^ device.vn[y];
+    device.vn[x] ^= device.vn[y];
+    if device.quirks.logic_reset_vf { device.vn[0xF] = 0; }
     device.pc += 2;
 }
 
@@ -378,13 +441,15 @@ fn addvxvy(device: &mut Chip8, ins: u16) {
 8xy5 - SUB Vx, Vy
 Set Vx = Vx - Vy, set VF = NOT borrow.
 
-If Vx > Vy, then VF is set to 1, otherwise 0. Then Vy is subtracted from Vx, and the results stored in Vx.
+If Vx >= Vy, then VF is set to 1, otherwise 0. Then Vy is subtracted from Vx, and the results stored in Vx.
+VF is written after the result, so that it wins when x is F.
 */
 fn subvxvy(device: &mut Chip8, ins: u16) {
     let x:usize = ((ins & 0x0F00) >> 8) as usize;
     let y:usize = ((ins & 0x00F0) >> 4) as usize;
-    if device.vn[x] > device.vn[y] { device.vn[0xF] = 1;} else { device.vn[0xF] = 0; }
+    let not_borrow = device.vn[x] >= device.vn[y];
     device.vn[x] = device.vn[x].wrapping_sub(device.vn[y]);
+    device.vn[0xF] = not_borrow as u8;
     device.pc += 2;
 }
 
@@ -400,8 +465,10 @@ VY is unchanged
 fn shr(device: &mut Chip8, ins: u16) {
     let x:usize = ((ins & 0x0F00) >> 8) as usize;
     let y:usize = ((ins & 0x00F0) >> 4) as usize;
-    device.vn[0xF] = device.vn[x]&0x01;
-    device.vn[x] = device.vn[y] >> 1;
+    let source = if device.quirks.shift_vy { device.vn[y] } else { device.vn[x] };
+    // VF holds the bit shifted out of the source, written last so that it wins when x is F
+    device.vn[x] = source >> 1;
+    device.vn[0xF] = source & 0x01;
     device.pc += 2;
 }
 
@@ -409,25 +476,20 @@ fn shr(device: &mut Chip8, ins: u16) {
 8xy7 - SUBN Vx, Vy
 Set Vx = Vy - Vx, set VF = NOT borrow.
 
-If Vy > Vx, then VF is set to 1, otherwise 0. Then Vx is subtracted from Vy, and the results stored in Vx.
+If Vy >= Vx, then VF is set to 1, otherwise 0. Then Vx is subtracted from Vy, and the results stored in Vx.
 
 Set register VX to the value of VY minus VX
 Set VF to 00 if a borrow occurs
-Set VF to 01 if a borrow does not occur
+Set VF to 01 if a borrow does not occur, written after the result so that it wins when x is F
 
 */
 fn subnvxvy(device: &mut Chip8, ins: u16) {
-    let x: usize = (ins & 0x0F00 >> 8) as usize;
-    let y: usize = (ins & 0x00F0 >> 4) as usize;
-
-    if device.vn[y] > device.vn[x] {
-        device.vn[0xF] = 1;
-    } else {
-        device.vn[0xF] = 0;
-    }
+    let x: usize = ((ins & 0x0F00) >> 8) as usize;
+    let y: usize = ((ins & 0x00F0) >> 4) as usize;
+    let not_borrow = device.vn[y] >= device.vn[x];
     device.vn[x] = device.vn[y].wrapping_sub(device.vn[x]);
+    device.vn[0xF] = not_borrow as u8;
     device.pc += 2;
-
 }
 
 /*
@@ -445,8 +507,9 @@ VY is unchanged
 fn shl(device: &mut Chip8, ins: u16) {
     let x:usize = ((ins & 0x0F00) >> 8) as usize;
     let y:usize = ((ins & 0x00F0) >> 4) as usize;
-    device.vn[0xF] = (device.vn[x] >> 7)&0x01;
-    device.vn[x] = device.vn[y] << 1;
+    let source = if device.quirks.shift_vy { device.vn[y] } else { device.vn[x] };
+    device.vn[x] = source << 1;
+    device.vn[0xF] = (source >> 7) & 0x01;
     device.pc += 2;
 }
 
@@ -487,7 +550,9 @@ The program counter is set to nnn plus the value of V0.
 */
 fn jpv0addr(device: &mut Chip8, ins: u16) {
     let nnn: u16 = ins & 0x0FFF;
-    device.pc = device.vn[0] as u16 + nnn;
+    // SUPER-CHIP reads it Bxnn, the register is given by the high nibble of the address
+    let register = if device.quirks.jump_vx { ((ins & 0x0F00) >> 8) as usize } else { 0 };
+    device.pc = device.vn[register] as u16 + nnn;
 }
 
 /*
@@ -499,7 +564,7 @@ The interpreter generates a random number from 0 to 255, which is then ANDed wit
 fn rnd(device: &mut Chip8, ins: u16) {
     let x:usize = ((ins & 0x0F00) >> 8) as usize;
     let byte = (ins & 0x00FF) as u8;
-    let rng = rand::thread_rng().gen_range(0..255);
+    let rng = device.rng.gen_range(0..255);
     device.vn[x] = rng & byte;
     device.pc += 2;
 }
@@ -522,20 +587,22 @@ fn drw(device: &mut Chip8, ins: u16) {
     let mut x:usize = ((ins & 0x0F00) >> 8) as usize;
     let mut y:usize = ((ins & 0x00F0) >> 4) as usize;
 
-    x = device.vn[x] as usize;
-    y = device.vn[y] as usize;
+    // The start position always wraps, the sprite itself wraps or is clipped
+    x = device.vn[x] as usize % DISPLAY_WIDTH;
+    y = device.vn[y] as usize % DISPLAY_HEIGHT;
 
     let n:usize = (ins & 0x000F) as usize;
 
     device.vn[0xF] = 0;
 
     for j in 0..n{
-        let sprite_byte = device.memory[(device.i as usize).saturating_add(j as usize)];
+        let sprite_byte = device.memory[(device.i as usize).saturating_add(j)];
         for b in 0..8 {
-            if (sprite_byte & (0x80 >> b)) != 0 {
-                if xor_px_at(device.display.borrow_mut(), x+b, y+j){
-                    device.vn[0xF] = 1;
-                };
+            if device.quirks.clip_sprites && (x + b >= DISPLAY_WIDTH || y + j >= DISPLAY_HEIGHT) {
+                continue;
+            }
+            if (sprite_byte & (0x80 >> b)) != 0 && xor_px_at(device.display.borrow_mut(), x+b, y+j) {
+                device.vn[0xF] = 1;
             }
         }
     }
@@ -567,7 +634,7 @@ Checks the keyboard, and if the key corresponding to the value of Vx is currentl
 */
 fn sknpvx(device: &mut Chip8, ins: u16) {
     let x:usize = ((ins & 0x0F00) >> 8) as usize;
-    if device.keyboard[device.vn[x] as usize] == false{
+    if !device.keyboard[device.vn[x] as usize]{
         device.pc += 4;
     }else{
         device.pc += 2;
@@ -594,13 +661,16 @@ All execution stops until a key is pressed, then the value of that key is stored
 */
 fn ldvxk(device: &mut Chip8, ins: u16) {
     let x:usize = ((ins & 0x0F00) >> 8) as usize;
+    // Like on the COSMAC VIP, the key is taken when it is released
     if device.key < KEYBOARD_SIZE {
         device.vn[x] = device.key as u8;
-    }else{
-        // force to loop on current ins
-        device.pc -= 2;
+        device.key = NO_KEY;
+        device.key_wait_start = None;
+        device.pc += 2;
+    }else if device.key_wait_start.is_none(){
+        // loop on current ins until a key is pressed and released
+        device.key_wait_start = Some(device.ticks);
     }
-    device.pc += 2;
 }
 
 /*
@@ -687,9 +757,11 @@ The interpreter copies the values of registers V0 through Vx into memory, starti
 fn ldivx(device: &mut Chip8, ins: u16) {
     let x:usize = ((ins & 0x0F00) >> 8) as usize;
     for n in 0..x+1{
-        device.memory[device.i as usize + (n as usize)] = device.vn[n];
+        device.memory[device.i as usize + n] = device.vn[n];
+    }
+    if device.quirks.load_store_increment {
+        device.i = device.i + (x as u16) + 1;
     }
-    device.i = device.i + (x as u16) + 1;
     device.pc += 2;
 }
 
@@ -702,8 +774,164 @@ The interpreter reads values from memory starting at location I into registers V
 fn ldvxii(device: &mut Chip8, ins: u16) {
     let x:usize = ((ins & 0x0F00) >> 8) as usize;
     for n in 0..x+1{
-        device.vn[n] = device.memory[device.i as usize + (n as usize)];
+        device.vn[n] = device.memory[device.i as usize + n];
+    }
+    if device.quirks.load_store_increment {
+        device.i = device.i + (x as u16) + 1;
     }
-    device.i = device.i + (x as u16) + 1;
     device.pc += 2;
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::chip8::build_chip8;
+    use crate::chip8_display::get_px_at;
+    use crate::chip8_quirks::quirks_preset;
+    use super::*;
+
+    fn machine(preset: &str) -> Chip8 {
+        let mut device = build_chip8();
+        device.quirks = quirks_preset(preset).unwrap();
+        device.vn[1] = 0x81;
+        device.vn[2] = 0x0F;
+        return device;
+    }
+
+    #[test]
+    fn default_quirks_keep_the_original_instructions() {
+        let mut device = machine("default");
+        exec(0x8126, &mut device);
+        assert_eq!((device.vn[1], device.vn[0xF]), (0x07, 1));
+        exec(0x812E, &mut device);
+        assert_eq!((device.vn[1], device.vn[0xF]), (0x1E, 0));
+
+        device.vn[0xF] = 1;
+        exec(0x8121, &mut device);
+        assert_eq!(device.vn[0xF], 1);
+
+        device.i = 0x300;
+        exec(0xF255, &mut device);
+        assert_eq!(device.i, 0x303);
+        exec(0xF265, &mut device);
+        assert_eq!(device.i, 0x306);
+
+        device.vn[0] = 2;
+        exec(0xB123, &mut device);
+        assert_eq!(device.pc, 0x125);
+
+        // Sprites wrap around the edges
+        device.i = 0x300;
+        device.memory[0x300] = 0xC0;
+        device.vn[3] = 63;
+        device.vn[4] = 31;
+        exec(0xD341, &mut device);
+        assert!(get_px_at(&device.display, 63, 31) && get_px_at(&device.display, 0, 31));
+    }
+
+    #[test]
+    fn vip_quirks() {
+        let mut device = machine("vip");
+        device.vn[0xF] = 1;
+        exec(0x8122, &mut device);
+        assert_eq!((device.vn[1], device.vn[0xF]), (0x01, 0));
+
+        // VF gets the bit shifted out of Vy, not out of Vx
+        device.vn[2] = 0x80;
+        exec(0x8126, &mut device);
+        assert_eq!((device.vn[1], device.vn[0xF]), (0x40, 0));
+        exec(0x812E, &mut device);
+        assert_eq!((device.vn[1], device.vn[0xF]), (0x00, 1));
+        device.vn[2] = 0x03;
+        exec(0x8F26, &mut device);
+        assert_eq!(device.vn[0xF], 1);
+        exec(0x8F2E, &mut device);
+        assert_eq!(device.vn[0xF], 0);
+
+        device.i = 0x300;
+        device.memory[0x300] = 0xC0;
+        device.vn[3] = 63;
+        device.vn[4] = 31;
+        exec(0xD341, &mut device);
+        assert!(get_px_at(&device.display, 63, 31) && !get_px_at(&device.display, 0, 31));
+    }
+
+    #[test]
+    fn schip_quirks() {
+        let mut device = machine("schip");
+        exec(0x8126, &mut device);
+        assert_eq!((device.vn[1], device.vn[0xF]), (0x40, 1));
+
+        device.i = 0x300;
+        exec(0xF255, &mut device);
+        assert_eq!(device.i, 0x300);
+
+        device.vn[1] = 2;
+        exec(0xB123, &mut device);
+        assert_eq!(device.pc, 0x125);
+        device.vn[0] = 9;
+        exec(0xB223, &mut device);
+        assert_eq!(device.pc, 0x223 + 0x0F);
+    }
+
+    #[test]
+    fn subtractions_set_not_borrow() {
+        let mut device = build_chip8();
+        device.vn[1] = 5;
+        device.vn[2] = 5;
+        exec(0x8125, &mut device);
+        assert_eq!((device.vn[1], device.vn[0xF]), (0, 1));
+        exec(0x8125, &mut device);
+        assert_eq!((device.vn[1], device.vn[0xF]), (0xFB, 0));
+
+        device.vn[1] = 3;
+        device.vn[7] = 0x77;
+        exec(0x8127, &mut device);
+        assert_eq!((device.vn[1], device.vn[0xF], device.vn[7]), (2, 1, 0x77));
+        device.vn[1] = 5;
+        exec(0x8127, &mut device);
+        assert_eq!((device.vn[1], device.vn[0xF]), (0, 1));
+        device.vn[1] = 6;
+        exec(0x8127, &mut device);
+        assert_eq!((device.vn[1], device.vn[0xF]), (0xFF, 0));
+
+        // VF as the destination holds the flag, not the difference
+        device.vn[0xF] = 9;
+        exec(0x8F25, &mut device);
+        assert_eq!(device.vn[0xF], 1);
+        device.vn[0xF] = 3;
+        exec(0x8F27, &mut device);
+        assert_eq!(device.vn[0xF], 1);
+    }
+
+    #[test]
+    fn ret_on_an_empty_stack_stops_on_it() {
+        let mut device = build_chip8();
+        exec(0x00EE, &mut device);
+        assert_eq!(device.pc, 0x200);
+        assert_eq!(device.sp, 0);
+        assert_eq!(device.stack_warning.as_deref(), Some("RET with an empty stack at 0x200"));
+    }
+
+    #[test]
+    fn call_on_a_full_stack_stops_on_it() {
+        let mut device = build_chip8();
+        for _ in 0..STACK_SIZE {
+            exec(0x2200, &mut device);
+        }
+        assert!(device.stack_warning.is_none());
+        exec(0x2200, &mut device);
+        assert_eq!(device.pc, 0x200);
+        assert_eq!(device.sp as usize, STACK_SIZE);
+        assert_eq!(device.stack_warning.as_deref(), Some("CALL at 0x200 nests deeper than 16 levels"));
+    }
+
+    #[test]
+    fn call_and_ret_within_bounds() {
+        let mut device = build_chip8();
+        exec(0x2345, &mut device);
+        assert_eq!((device.pc, device.sp), (0x345, 1));
+        exec(0x00EE, &mut device);
+        assert_eq!((device.pc, device.sp), (0x202, 0));
+        assert!(device.stack_warning.is_none());
+    }
+}
@@ -1,75 +1,105 @@
-use crate::chip8::Chip8;
+#![allow(clippy::needless_return)]
+
+use std::ffi::CStr;
+
+use crate::frontend::{InputEvent, MenuCommand, VideoCommand};
+use crate::keymap::{KeyAction, Keymap, PAD_PREFIX, keymap_action};
 use fermium::prelude::*;
 
-pub fn on_keyboard_event(device: &mut Chip8, keycode: SDL_Scancode, down: bool) {
-    match keycode {
-        SDL_SCANCODE_0 | SDL_SCANCODE_KP_0 => {
-            device.keyboard[0x0] = down;
-            if down { device.key = 0x0; }
-        }
-        SDL_SCANCODE_1 | SDL_SCANCODE_KP_1 => {
-            device.keyboard[0x1] = down;
-            if down { device.key = 0x1; }
-        }
-        SDL_SCANCODE_2 | SDL_SCANCODE_KP_2 => {
-            device.keyboard[0x2] = down;
-            if down { device.key = 0x2; }
-        }
-        SDL_SCANCODE_3 | SDL_SCANCODE_KP_3 => {
-            device.keyboard[0x3] = down;
-            if down { device.key = 0x3; }
-        }
-        SDL_SCANCODE_4 | SDL_SCANCODE_KP_4 => {
-            device.keyboard[0x4] = down;
-            if down { device.key = 0x4; }
-        }
-        SDL_SCANCODE_5 | SDL_SCANCODE_KP_5 => {
-            device.keyboard[0x5] = down;
-            if down { device.key = 0x5; }
-        }
-        SDL_SCANCODE_6 | SDL_SCANCODE_KP_6 => {
-            device.keyboard[0x6] = down;
-            if down { device.key = 0x6; }
-        }
-        SDL_SCANCODE_7 | SDL_SCANCODE_KP_7 => {
-            device.keyboard[0x7] = down;
-            if down { device.key = 0x7; }
-        }
-        SDL_SCANCODE_8 | SDL_SCANCODE_KP_8 => {
-            device.keyboard[0x8] = down;
-            if down { device.key = 0x8; }
-        }
-        SDL_SCANCODE_9 | SDL_SCANCODE_KP_9 => {
-            device.keyboard[0x9] = down;
-            if down { device.key = 0x9; }
-        }
-        SDL_SCANCODE_A => {
-            device.keyboard[0xA] = down;
-            if down { device.key = 0xA; }
-        }
-        SDL_SCANCODE_B => {
-            device.keyboard[0xB] = down;
-            if down { device.key = 0xB; }
-        }
-        SDL_SCANCODE_C => {
-            device.keyboard[0xC] = down;
-            if down { device.key = 0xC; }
-        }
-        SDL_SCANCODE_D => {
-            device.keyboard[0xD] = down;
-            if down { device.key = 0xD; }
-        }
-        SDL_SCANCODE_E => {
-            device.keyboard[0xE] = down;
-            if down { device.key = 0xE; }
-        }
-        SDL_SCANCODE_F => {
-            device.keyboard[0xF] = down;
-            if down { device.key = 0xF; }
-        }
-        SDL_SCANCODE_T => {
-            device.turbo = down;
-        }
-        _ => ()
+/**
+ * Translate an SDL key event into an emulator event
+ *
+ * Function keys are emulator hotkeys, the other keys go through the keymap.
+ */
+pub fn on_keyboard_event(keymap: &Keymap, keycode: SDL_Scancode, down: bool) -> Option<InputEvent> {
+    return match keycode {
+        SDL_SCANCODE_ESCAPE if down => Some(InputEvent::Browse),
+        SDL_SCANCODE_F1 | SDL_SCANCODE_PAUSE if down => Some(InputEvent::Pause),
+        SDL_SCANCODE_F2 if down => Some(InputEvent::FrameAdvance),
+        SDL_SCANCODE_F3 if down => Some(InputEvent::Speed(-1)),
+        SDL_SCANCODE_F4 if down => Some(InputEvent::Speed(1)),
+        SDL_SCANCODE_F5 if down => Some(InputEvent::Reset),
+        SDL_SCANCODE_F6 if down => Some(InputEvent::NextQuirks),
+        SDL_SCANCODE_F7 if down => Some(InputEvent::Video(VideoCommand::ToggleStats)),
+        SDL_SCANCODE_F12 if down => Some(InputEvent::Screenshot),
+        SDL_SCANCODE_F8 if down => Some(InputEvent::Record),
+        SDL_SCANCODE_F11 if down => Some(InputEvent::Video(VideoCommand::ToggleFullscreen)),
+        SDL_SCANCODE_F9 if down => Some(InputEvent::Video(VideoCommand::NextTheme)),
+        SDL_SCANCODE_F10 if down => Some(InputEvent::Video(VideoCommand::ToggleIntegerScale)),
+        SDL_SCANCODE_EQUALS | SDL_SCANCODE_KP_PLUS if down => Some(InputEvent::Video(VideoCommand::Zoom(1))),
+        SDL_SCANCODE_MINUS | SDL_SCANCODE_KP_MINUS if down => Some(InputEvent::Video(VideoCommand::Zoom(-1))),
+        _ => {
+            let name = unsafe { CStr::from_ptr(SDL_GetScancodeName(keycode)) }.to_string_lossy();
+            keymap_action(keymap, &name).map(|action| action_event(action, down))
+        }
+    };
+}
+
+/**
+ * Tell whether a key held down keeps sending its event : only frame advance and the speed hotkeys do
+ */
+pub fn auto_repeats(keycode: SDL_Scancode) -> bool {
+    return matches!(keycode, SDL_SCANCODE_F2 | SDL_SCANCODE_F3 | SDL_SCANCODE_F4);
+}
+
+/**
+ * Translate a key pressed with Ctrl into an emulator event : Ctrl-S saves the machine and Ctrl-L restores it
+ */
+pub fn on_ctrl_key(keycode: SDL_Scancode) -> Option<InputEvent> {
+    return match keycode {
+        SDL_SCANCODE_S => Some(InputEvent::SaveState),
+        SDL_SCANCODE_L => Some(InputEvent::LoadState),
+        _ => None,
+    };
+}
+
+/**
+ * Translate a key press into a ROM browser command
+ *
+ * The browser keys can also be bound to the keypad, the emulation loop only uses the events of its current screen.
+ */
+pub fn on_menu_key(keycode: SDL_Scancode) -> Option<InputEvent> {
+    return match keycode {
+        SDL_SCANCODE_UP => Some(InputEvent::Menu(MenuCommand::Move(-1))),
+        SDL_SCANCODE_DOWN => Some(InputEvent::Menu(MenuCommand::Move(1))),
+        SDL_SCANCODE_PAGEUP => Some(InputEvent::Menu(MenuCommand::Move(-10))),
+        SDL_SCANCODE_PAGEDOWN => Some(InputEvent::Menu(MenuCommand::Move(10))),
+        SDL_SCANCODE_HOME => Some(InputEvent::Menu(MenuCommand::End(false))),
+        SDL_SCANCODE_END => Some(InputEvent::Menu(MenuCommand::End(true))),
+        SDL_SCANCODE_RETURN | SDL_SCANCODE_KP_ENTER => Some(InputEvent::Menu(MenuCommand::Select)),
+        _ => None,
+    };
+}
+
+/**
+ * Emulator event of a bound action pressed or released
+ */
+pub fn action_event(action: KeyAction, down: bool) -> InputEvent {
+    return match action {
+        KeyAction::Key(key) => InputEvent::Key(key, down),
+        KeyAction::Turbo => InputEvent::Turbo(down),
+    };
+}
+
+/**
+ * Name of a game controller button, as the keymap names it
+ */
+pub fn pad_button_name(button: u8) -> String {
+    let name = unsafe { SDL_GameControllerGetStringForButton(SDL_GameControllerButton(button as i32)) };
+    if name.is_null() {
+        return format!("{}Button{}", PAD_PREFIX, button);
+    }
+    return format!("{}{}", PAD_PREFIX, unsafe { CStr::from_ptr(name) }.to_string_lossy());
+}
+
+/**
+ * Name of a game controller axis pushed to one side, as the keymap names it
+ */
+pub fn pad_axis_name(axis: u8, positive: bool) -> String {
+    let name = unsafe { SDL_GameControllerGetStringForAxis(SDL_GameControllerAxis(axis as i32)) };
+    let sign = if positive { '+' } else { '-' };
+    if name.is_null() {
+        return format!("{}Axis{}{}", PAD_PREFIX, axis, sign);
     }
+    return format!("{}{}{}", PAD_PREFIX, unsafe { CStr::from_ptr(name) }.to_string_lossy(), sign);
 }
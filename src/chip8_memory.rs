@@ -1,8 +1,10 @@
-/**
+/*!
  * Memory definition
  */
 
+#[allow(dead_code)]
 pub const START_MEM: usize = 0x000;
 pub const START_PRG: usize = 0x200;
+#[allow(dead_code)]
 pub const START_ETI: usize = 0x600;
 pub const END_MEM: usize = 0xFFF;
\ No newline at end of file
@@ -0,0 +1,62 @@
+#![allow(clippy::needless_return)]
+
+/**
+ * Behaviours that differ between Chip 8 interpreters
+ *
+ * Games were written for one interpreter and can break on the others, so the machine follows a chosen set.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chip8Quirks {
+
+    // 8xy6 / 8xyE shift Vy into Vx (COSMAC VIP), instead of shifting Vx in place (SUPER-CHIP)
+    pub(crate) shift_vy: bool,
+
+    // Fx55 / Fx65 leave I pointing after the last register (COSMAC VIP)
+    pub(crate) load_store_increment: bool,
+
+    // Bxnn jumps to xnn + Vx (SUPER-CHIP), instead of nnn + V0
+    pub(crate) jump_vx: bool,
+
+    // 8xy1 / 8xy2 / 8xy3 reset VF to 0 (COSMAC VIP)
+    pub(crate) logic_reset_vf: bool,
+
+    // Sprites are cut at the edges of the screen instead of wrapping around
+    pub(crate) clip_sprites: bool,
+}
+
+// Presets, in the order they are cycled through
+pub const QUIRK_PRESETS: [&str; 3] = ["default", "vip", "schip"];
+
+/**
+ * Quirks of a preset : the behaviour this emulator always had (also the one of XO-CHIP), the COSMAC VIP or SUPER-CHIP
+ */
+pub fn quirks_preset(name: &str) -> Option<Chip8Quirks> {
+    return match name {
+        "default" => Some(Chip8Quirks { shift_vy: true, load_store_increment: true, jump_vx: false, logic_reset_vf: false, clip_sprites: false }),
+        "vip" => Some(Chip8Quirks { shift_vy: true, load_store_increment: true, jump_vx: false, logic_reset_vf: true, clip_sprites: true }),
+        "schip" => Some(Chip8Quirks { shift_vy: false, load_store_increment: false, jump_vx: true, logic_reset_vf: false, clip_sprites: true }),
+        _ => None,
+    };
+}
+
+pub fn build_default_quirks() -> Chip8Quirks {
+    return quirks_preset("default").unwrap();
+}
+
+/**
+ * Name of the preset these quirks match, "custom" when they match none
+ */
+pub fn quirks_name(quirks: &Chip8Quirks) -> &'static str {
+    return QUIRK_PRESETS.iter()
+        .find(|name| quirks_preset(name).as_ref() == Some(quirks))
+        .copied()
+        .unwrap_or("custom");
+}
+
+/**
+ * Quirks of the preset following the given one, wrapping around
+ */
+pub fn next_quirks_preset(name: &str) -> &'static str {
+    let index = QUIRK_PRESETS.iter().position(|preset| *preset == name).map(|i| i + 1).unwrap_or(0);
+    return QUIRK_PRESETS[index % QUIRK_PRESETS.len()];
+}
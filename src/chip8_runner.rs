@@ -0,0 +1,728 @@
+#![allow(clippy::needless_return)]
+
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::chip8::{Chip8, build_chip8, load_program, load_rom, queue_key_event, step};
+use crate::chip8_quirks::{build_default_quirks, next_quirks_preset, quirks_name, quirks_preset};
+use crate::chip8_debugger::{Chip8Debugger, clear_history, debugger_step, poll_debugger_commands};
+use crate::frontend::{AudioSink, InputEvent, InputSource, VideoSink};
+use crate::image_export::screenshot_name;
+use crate::keymap::{Keymap, build_default_keymap, rom_keymap};
+use crate::palette::{Palette, palette_preset};
+use crate::phosphor::PersistenceMode;
+use crate::recorder::{Recorder, build_recorder, record_frame, save_recording};
+use crate::config::{DEFAULT_TONE, DEFAULT_VOLUME, config_rom_settings};
+use crate::rom_database::{RomDatabase, RomSettings, build_rom_database, merge_settings, rom_description, rom_info};
+use crate::sha1::sha1_hex;
+use crate::rom_browser::{DEFAULT_ROM_DIR, RomBrowser, browser_command, browser_lines, build_rom_browser, rom_display_name};
+use crate::save_state::{is_save_state, load_state_file, save_state_file, state_name};
+use crate::zip_archive::{archive_file, is_zip, split_archive_path};
+
+// Instructions executed per frame, runs ~540 ops/s (540hz cpu speed) at 40 FPS
+pub const STEPS_PER_FRAME: u32 = 14;
+
+// Frames emulated per second
+pub const FRAMES_PER_SECOND: u32 = 40;
+
+// Instructions executed per frame while turbo is held
+pub const TURBO_STEPS_PER_FRAME: u32 = 140;
+
+// Emulation speeds in percent of the normal speed, below 100 for slow motion
+pub const SPEED_LEVELS: [u32; 10] = [10, 25, 50, 75, 100, 125, 150, 200, 300, 400];
+const NORMAL_SPEED_LEVEL: usize = 4;
+
+// Time between two updates of the metrics shown by the frontend
+const STATS_PERIOD: Duration = Duration::from_secs(1);
+
+// Time between two looks at the ROM file in watch mode
+const WATCH_PERIOD: Duration = Duration::from_millis(250);
+
+/**
+ * Emulation loop state, driving a machine through the frontend traits
+ */
+pub struct Chip8Runner {
+
+    // Emulated machine
+    pub(crate) device: Chip8,
+
+    // Program loaded in the machine, kept to reset it, empty until a ROM is picked
+    pub(crate) rom: Vec<u8>,
+
+    // File the program was loaded from
+    pub(crate) rom_path: String,
+
+    // SHA-1 of the program, the key of the ROM database
+    pub(crate) rom_hash: String,
+
+    // Title shown to the user, from the ROM database or the file name
+    pub(crate) rom_title: String,
+
+    // Known ROMs, and the settings applied below (defaults) and above (command line) the ones of the database
+    pub(crate) database: RomDatabase,
+    pub(crate) defaults: RomSettings,
+    pub(crate) overrides: RomSettings,
+
+    // Settings of the configuration file for some ROMs, by file name or SHA-1, applied over the database
+    pub(crate) rom_sections: Vec<(String, RomSettings)>,
+
+    // Directory listed by the ROM browser
+    pub(crate) rom_dir: String,
+
+    // ROM browser, shown instead of the game when open
+    pub(crate) browser: Option<RomBrowser>,
+
+    // Keymap before the per-ROM bindings
+    pub(crate) keymap: Keymap,
+
+    // Reload the ROM when its file changes, then restore this save state when one is given
+    pub(crate) watch: bool,
+    pub(crate) watch_state: Option<String>,
+
+    // IPS or BPS patches applied to the ROM of this file (the one of the command line), when it is loaded and reloaded
+    pub(crate) patches: Vec<String>,
+    pub(crate) patched_path: String,
+
+    // Modification time of the ROM file when it was loaded, and last time it was checked
+    rom_modified: Option<SystemTime>,
+    watch_check: Instant,
+
+    // Keymap and colours of the ROM just loaded, not given to the frontend yet
+    pending_keymap: Option<Keymap>,
+    pending_palette: Option<Palette>,
+
+    // Console debugger, when enabled
+    pub(crate) debugger: Option<Chip8Debugger>,
+
+    // Number of frames emulated
+    pub(crate) frame: u64,
+
+    // ROM file name without extension, used to name the screenshots
+    pub(crate) rom_name: String,
+
+    // Size of the pixels of screenshots and recordings
+    pub(crate) export_scale: usize,
+
+    // Colours before the per-ROM ones
+    pub(crate) theme: Palette,
+
+    // Colours of the current ROM and persistence, used by the recordings
+    pub(crate) palette: Palette,
+    pub(crate) persistence: PersistenceMode,
+
+    // Recording in progress
+    pub(crate) recorder: Option<Recorder>,
+
+    pub(crate) paused: bool,
+
+    // Emulate one frame, then pause again
+    frame_advance: bool,
+
+    // Instructions executed per frame at normal speed, and while turbo is held
+    pub(crate) steps_per_frame: u32,
+    pub(crate) turbo_steps_per_frame: u32,
+
+    // Beeper tone, for the recordings
+    pub(crate) tone: f32,
+    pub(crate) volume: f32,
+
+    // Index in SPEED_LEVELS
+    pub(crate) speed_level: usize,
+
+    // Hundredths of instruction left from the previous frames, slow speeds run a fraction of an instruction per frame
+    step_credit: u32,
+
+    // Last status given to the frontend
+    status: String,
+
+    // Start of the current metrics period, with the frame and instruction counts at that time
+    stats_start: Instant,
+    stats_frame: u64,
+    stats_ticks: u64,
+}
+
+pub fn build_chip8_runner(device: Chip8, rom: Vec<u8>, debugger: Option<Chip8Debugger>, rom_name: &str) -> Chip8Runner {
+    return Chip8Runner {
+        device,
+        rom,
+        rom_path: String::new(),
+        rom_hash: String::new(),
+        rom_title: rom_name.to_string(),
+        database: build_rom_database(),
+        defaults: RomSettings::default(),
+        overrides: RomSettings::default(),
+        rom_sections: Vec::new(),
+        rom_dir: DEFAULT_ROM_DIR.to_string(),
+        browser: None,
+        keymap: build_default_keymap(),
+        watch: false,
+        watch_state: None,
+        patches: Vec::new(),
+        patched_path: String::new(),
+        rom_modified: None,
+        watch_check: Instant::now(),
+        pending_keymap: None,
+        pending_palette: None,
+        debugger,
+        frame: 0,
+        rom_name: rom_name.to_string(),
+        export_scale: 1,
+        theme: palette_preset("classic").unwrap(),
+        palette: palette_preset("classic").unwrap(),
+        persistence: PersistenceMode::Off,
+        recorder: None,
+        paused: false,
+        frame_advance: false,
+        steps_per_frame: STEPS_PER_FRAME,
+        turbo_steps_per_frame: TURBO_STEPS_PER_FRAME,
+        tone: DEFAULT_TONE,
+        volume: DEFAULT_VOLUME,
+        speed_level: NORMAL_SPEED_LEVEL,
+        step_credit: 0,
+        status: String::new(),
+        stats_start: Instant::now(),
+        stats_frame: 0,
+        stats_ticks: 0,
+    };
+}
+
+/**
+ * Power cycle the machine : a new machine gets the ROM, host side settings such as turbo and quirks are kept
+ */
+pub fn reset(runner: &mut Chip8Runner) {
+    let turbo = runner.device.turbo;
+    let quirks = runner.device.quirks;
+    runner.device = build_chip8();
+    load_rom(&mut runner.device, &runner.rom);
+    runner.device.turbo = turbo;
+    runner.device.quirks = quirks;
+    runner.stats_ticks = 0;
+    if let Some(debugger) = runner.debugger.as_mut() {
+        clear_history(debugger);
+    }
+}
+
+/**
+ * Load a ROM file in a new machine and play it, with the settings the ROM database gives for it
+ *
+ * Settings come from the defaults, then the options of an Octo cartridge, then the database, then the section of the ROM
+ * in the configuration, then the command line. The ROM database and the save states see the ROM once patched.
+ */
+pub fn open_rom(runner: &mut Chip8Runner, path: &str) -> Result<(), String> {
+    let mut device = build_chip8();
+    // Patches given for an archive holding several ROMs go to the ROM picked in it
+    let picked_in_patched_archive = split_archive_path(path).is_some() && archive_file(path) == runner.patched_path;
+    let patches = if path == runner.patched_path || picked_in_patched_archive { &runner.patches[..] } else { &[] };
+    let file = load_program(&mut device, path, patches)?;
+    if picked_in_patched_archive {
+        runner.patched_path = path.to_string();
+    }
+    let rom = file.bytes;
+    let hash = sha1_hex(&rom);
+    let info = rom_info(&runner.database, &hash);
+    let rom_name = rom_display_name(path);
+    let mut below = merge_settings(&runner.defaults, &file.settings);
+    if let Some(info) = info {
+        below = merge_settings(&below, &info.settings);
+    }
+    if let Some(section) = config_rom_settings(&runner.rom_sections, &rom_name, &hash) {
+        below = merge_settings(&below, section);
+    }
+    let settings = merge_settings(&below, &runner.overrides);
+    let keymap = rom_keymap(&runner.keymap, path, &below.keys, &runner.overrides.keys)?;
+    runner.rom_name = rom_name;
+    runner.rom_title = info.and_then(rom_description).unwrap_or_else(|| runner.rom_name.clone());
+
+    device.turbo = runner.device.turbo;
+    device.quirks = settings.quirks.unwrap_or_else(build_default_quirks);
+    runner.device = device;
+    runner.rom = rom;
+    runner.rom_path = path.to_string();
+    runner.rom_hash = hash;
+    runner.rom_modified = modified_time(path);
+    runner.steps_per_frame = settings.instructions_per_frame.unwrap_or(STEPS_PER_FRAME);
+    let mut palette = runner.theme.clone();
+    if let Some(color) = settings.bg {
+        palette.colors[0] = color;
+    }
+    if let Some(color) = settings.fg {
+        palette.colors[1] = color;
+    }
+    runner.palette = palette.clone();
+    runner.pending_palette = Some(palette);
+    runner.pending_keymap = Some(keymap);
+    runner.browser = None;
+    runner.paused = false;
+    runner.stats_ticks = 0;
+    if let Some(debugger) = runner.debugger.as_mut() {
+        clear_history(debugger);
+    }
+    return Ok(());
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    return std::fs::metadata(archive_file(path)).and_then(|metadata| metadata.modified()).ok();
+}
+
+/**
+ * Save the machine to the save state file of the ROM, returns the path written
+ */
+pub fn save_state(runner: &Chip8Runner) -> Result<String, String> {
+    let path = state_name(&runner.rom_name);
+    save_state_file(&path, &runner.device, &runner.rom_hash)?;
+    return Ok(path);
+}
+
+/**
+ * Restore the machine from a save state, host side settings such as turbo are kept
+ *
+ * A save state of another ROM is refused, unless keep_rom is set : the current program is then copied over the saved one,
+ * which lets a ROM being developed go on from the same point after each build.
+ */
+pub fn load_state(runner: &mut Chip8Runner, path: &str, keep_rom: bool) -> Result<(), String> {
+    let state = load_state_file(path)?;
+    let mut device = state.device;
+    if keep_rom {
+        load_rom(&mut device, &runner.rom);
+    } else if !state.rom_hash.eq_ignore_ascii_case(&runner.rom_hash) {
+        return Err(format!("{} was saved from another ROM", path));
+    }
+    device.turbo = runner.device.turbo;
+    runner.device = device;
+    runner.stats_ticks = runner.device.ticks;
+    if let Some(debugger) = runner.debugger.as_mut() {
+        clear_history(debugger);
+    }
+    return Ok(());
+}
+
+/**
+ * Load the ROM again when its file changed since it was loaded, keeping the keymap, the breakpoints and the pause
+ *
+ * Returns the outcome to show to the user, None when the file did not change.
+ */
+fn watch_rom(runner: &mut Chip8Runner) -> Option<String> {
+    if runner.watch_check.elapsed() < WATCH_PERIOD || runner.rom.is_empty() {
+        return None;
+    }
+    runner.watch_check = Instant::now();
+    let modified = modified_time(&runner.rom_path);
+    if modified.is_none() || modified == runner.rom_modified {
+        return None;
+    }
+    // A file being written can be seen half done, the next change loads it again
+    runner.rom_modified = modified;
+    let paused = runner.paused;
+    let path = runner.rom_path.clone();
+    let mut result = open_rom(runner, &path);
+    if let (Ok(()), Some(state)) = (&result, runner.watch_state.clone()) {
+        result = load_state(runner, &state, true);
+    }
+    runner.paused = paused;
+    return Some(match result {
+        Ok(()) => format!("Reloaded {}", runner.rom_title),
+        Err(e) => format!("Error : {}", e),
+    });
+}
+
+/**
+ * Play a ROM file, or restore a save state of the current ROM, returns the message to show to the user
+ *
+ * The file goes through the same checks as the ROM given on the command line, a bad file leaves the machine as it was.
+ * A zip archive holding several ROMs opens the ROM browser on them.
+ */
+pub fn open_file(runner: &mut Chip8Runner, path: &str) -> Result<String, String> {
+    let mut header = [0u8; 4];
+    let read = std::fs::File::open(path).and_then(|mut file| std::io::Read::read(&mut file, &mut header)).unwrap_or(0);
+    if is_save_state(&header[..read]) {
+        load_state(runner, path, false)?;
+        runner.browser = None;
+        return Ok(format!("State loaded from {}", path));
+    }
+    if split_archive_path(path).is_none() && is_zip(&header[..read]) {
+        let browser = build_rom_browser(path)?;
+        match browser.entries.len() {
+            0 => return Err(format!("{} holds no ROM", path)),
+            1 => return open_file(runner, &browser.entries[0].path),
+            _ => {
+                runner.browser = Some(browser);
+                return Ok(format!("Pick a ROM in {}", path));
+            }
+        }
+    }
+    open_rom(runner, path)?;
+    return Ok(format!("Playing {}", runner.rom_title));
+}
+
+/**
+ * Open the ROM browser on the ROM directory, with the current ROM highlighted
+ */
+pub fn open_browser(runner: &mut Chip8Runner) -> Result<(), String> {
+    let mut browser = build_rom_browser(&runner.rom_dir)?;
+    let current = std::fs::canonicalize(archive_file(&runner.rom_path)).ok();
+    if let Some(index) = browser.entries.iter().position(|entry| current.is_some() && std::fs::canonicalize(&entry.path).ok() == current) {
+        browser.selected = index;
+    }
+    runner.browser = Some(browser);
+    return Ok(());
+}
+
+/**
+ * Emulator state shown by the frontend, such as "BRIX - Paused - Speed 50%"
+ */
+pub fn runner_status(runner: &Chip8Runner) -> String {
+    if runner.browser.is_some() {
+        return "ROM browser".to_string();
+    }
+    let mut parts = vec![runner.rom_title.clone()];
+    if runner.paused {
+        parts.push("Paused".to_string());
+    }
+    if runner.device.turbo {
+        parts.push("Turbo".to_string());
+    } else if SPEED_LEVELS[runner.speed_level] != 100 {
+        parts.push(format!("Speed {}%", SPEED_LEVELS[runner.speed_level]));
+    }
+    if runner.recorder.is_some() {
+        parts.push("Recording".to_string());
+    }
+    return parts.join(" - ");
+}
+
+/**
+ * Metrics shown over the display, such as "40 FPS 540 IPS VIP PAUSED REC"
+ */
+fn runner_stats(runner: &Chip8Runner, frames: u64, ticks: u64, elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs_f64();
+    let mut stats = format!("{:.0} FPS {:.0} IPS {}", frames as f64 / seconds, ticks as f64 / seconds, quirks_name(&runner.device.quirks));
+    if runner.paused {
+        stats.push_str(" PAUSED");
+    }
+    if runner.recorder.is_some() {
+        stats.push_str(" REC");
+    }
+    return stats.to_uppercase();
+}
+
+/**
+ * Start recording the gameplay to a GIF file or to a directory of frames, returns the message to show to the user
+ */
+pub fn start_recording(runner: &mut Chip8Runner, path: &str) -> String {
+    let mut recorder = build_recorder(path, runner.palette.clone(), runner.persistence, runner.export_scale, FRAMES_PER_SECOND);
+    recorder.tone = runner.tone;
+    recorder.volume = runner.volume;
+    runner.recorder = Some(recorder);
+    return format!("Recording to {}", path);
+}
+
+/**
+ * Stop the recording in progress and save it, returns the outcome to show to the user
+ */
+pub fn stop_recording(runner: &mut Chip8Runner) -> Option<String> {
+    let recorder = runner.recorder.take()?;
+    let message = match save_recording(&recorder) {
+        Ok(frames) => format!("Recording of {} frames saved to {}", frames, recorder.path),
+        Err(e) => format!("Error : {}", e),
+    };
+    return Some(message);
+}
+
+/**
+ * Emulate one frame : handle input, show the display, execute the instructions and update the beeper
+ *
+ * Returns false when the frontend asked to quit.
+ */
+pub fn run_frame(runner: &mut Chip8Runner, video: &mut dyn VideoSink, audio: &mut dyn AudioSink, input: &mut dyn InputSource) -> bool {
+    if let Some(keymap) = runner.pending_keymap.take() {
+        input.set_keymap(keymap);
+    }
+    if let Some(palette) = runner.pending_palette.take() {
+        video.set_palette(&palette);
+    }
+    if runner.browser.is_some() {
+        return run_browser_frame(runner, video, audio, input);
+    }
+
+    for event in input.poll_events() {
+        match event {
+            InputEvent::Quit => return false,
+            InputEvent::Key(key, down) => queue_key_event(&mut runner.device, key, down),
+            InputEvent::Turbo(on) => runner.device.turbo = on,
+            InputEvent::Screenshot => {
+                let path = screenshot_name(&runner.rom_name, runner.frame);
+                match video.screenshot(&path, runner.export_scale) {
+                    Ok(()) => video.show_message(&format!("Screenshot saved to {}", path)),
+                    Err(e) => video.show_message(&format!("Error : {}", e)),
+                }
+            }
+            InputEvent::Record => {
+                let message = if runner.recorder.is_some() {
+                    stop_recording(runner)
+                } else {
+                    let path = format!("{}_{:06}.gif", runner.rom_name, runner.frame);
+                    Some(start_recording(runner, &path))
+                };
+                if let Some(message) = message {
+                    video.show_message(&message);
+                }
+            }
+            InputEvent::Pause => {
+                runner.paused = !runner.paused;
+                video.show_message(if runner.paused { "Paused" } else { "Resumed" });
+            }
+            InputEvent::FrameAdvance => {
+                runner.paused = true;
+                runner.frame_advance = true;
+            }
+            InputEvent::Reset => {
+                reset(runner);
+                video.show_message("Reset");
+            }
+            InputEvent::Speed(steps) => {
+                let level = runner.speed_level as i32 + steps;
+                runner.speed_level = level.clamp(0, SPEED_LEVELS.len() as i32 - 1) as usize;
+                video.show_message(&format!("Speed {}%", SPEED_LEVELS[runner.speed_level]));
+            }
+            InputEvent::NextQuirks => {
+                let name = next_quirks_preset(quirks_name(&runner.device.quirks));
+                runner.device.quirks = quirks_preset(name).unwrap();
+                video.show_message(&format!("Quirks : {}", name));
+            }
+            InputEvent::Browse => {
+                if let Err(e) = open_browser(runner) {
+                    video.show_message(&format!("Error : {}", e));
+                }
+            }
+            InputEvent::SaveState => match save_state(runner) {
+                Ok(path) => video.show_message(&format!("State saved to {}", path)),
+                Err(e) => video.show_message(&format!("Error : {}", e)),
+            },
+            InputEvent::LoadState => {
+                let path = state_name(&runner.rom_name);
+                match load_state(runner, &path, false) {
+                    Ok(()) => video.show_message(&format!("State loaded from {}", path)),
+                    Err(e) => video.show_message(&format!("Error : {}", e)),
+                }
+            }
+            InputEvent::Open(path) => match open_file(runner, &path) {
+                Ok(message) => video.show_message(&message),
+                Err(e) => video.show_message(&format!("Error : {}", e)),
+            },
+            InputEvent::Menu(_) => (),
+            InputEvent::Video(command) => video.command(command),
+        }
+    }
+
+    if runner.watch {
+        if let Some(message) = watch_rom(runner) {
+            video.show_message(&message);
+        }
+    }
+
+    update_status(runner, video);
+
+    let elapsed = runner.stats_start.elapsed();
+    if elapsed >= STATS_PERIOD {
+        let frames = runner.frame - runner.stats_frame;
+        let ticks = runner.device.ticks.saturating_sub(runner.stats_ticks);
+        video.set_stats(&runner_stats(runner, frames, ticks, elapsed));
+        runner.stats_start = Instant::now();
+        runner.stats_frame = runner.frame;
+        runner.stats_ticks = runner.device.ticks;
+    }
+
+    video.present(&runner.device.display);
+
+    if runner.paused && !runner.frame_advance {
+        audio.set_beep(false);
+        return true;
+    }
+    runner.frame_advance = false;
+
+    let device = &mut runner.device;
+    let speed = if device.turbo {
+        runner.turbo_steps_per_frame
+    } else {
+        runner.step_credit += runner.steps_per_frame * SPEED_LEVELS[runner.speed_level];
+        let steps = runner.step_credit / 100;
+        runner.step_credit %= 100;
+        steps
+    };
+    let mut paused = false;
+    match runner.debugger.as_mut() {
+        Some(debugger) => {
+            poll_debugger_commands(debugger, device);
+            if !debugger.paused {
+                for _ in 0..speed {
+                    if !debugger_step(debugger, device) {
+                        break;
+                    }
+                }
+            }
+            paused = debugger.paused;
+        }
+        None => {
+            for _ in 0..speed {
+                step(device);
+                if device.stack_warning.is_some() {
+                    break;
+                }
+            }
+        }
+    }
+
+    // A broken stack stops the machine on the faulty instruction, the debugger already paused on it
+    let warning = device.stack_warning.take();
+    let beep = device.st > 0 && !paused && warning.is_none();
+    audio.set_beep(beep);
+    if let Some(recorder) = runner.recorder.as_mut() {
+        record_frame(recorder, &device.display, beep);
+    }
+    if let Some(warning) = warning {
+        runner.paused = true;
+        video.show_message(&format!("Paused : {}", warning));
+    }
+
+    runner.frame += 1;
+    return true;
+}
+
+/**
+ * Give the emulator state to the frontend when it changed
+ */
+fn update_status(runner: &mut Chip8Runner, video: &mut dyn VideoSink) {
+    let status = runner_status(runner);
+    if status != runner.status {
+        video.set_status(&status);
+        runner.status = status;
+    }
+}
+
+/**
+ * Show the ROM browser for one frame and handle its keys, the game stays paused behind it
+ *
+ * Returns false when the frontend asked to quit.
+ */
+fn run_browser_frame(runner: &mut Chip8Runner, video: &mut dyn VideoSink, audio: &mut dyn AudioSink, input: &mut dyn InputSource) -> bool {
+    audio.set_beep(false);
+    for event in input.poll_events() {
+        match event {
+            InputEvent::Quit => return false,
+            InputEvent::Menu(command) => {
+                let picked = runner.browser.as_mut().and_then(|browser| browser_command(browser, command));
+                if let Some(path) = picked {
+                    match open_file(runner, &path) {
+                        Ok(message) => video.show_message(&message),
+                        Err(e) => video.show_message(&format!("Error : {}", e)),
+                    }
+                }
+            }
+            InputEvent::Open(path) => match open_file(runner, &path) {
+                Ok(message) => video.show_message(&message),
+                Err(e) => video.show_message(&format!("Error : {}", e)),
+            },
+            // Back from an archive to the ROM directory, else back to the game when there is one
+            InputEvent::Browse if runner.browser.as_ref().map(|browser| browser.archive).unwrap_or(false) => {
+                if let Err(e) = open_browser(runner) {
+                    video.show_message(&format!("Error : {}", e));
+                }
+            }
+            InputEvent::Browse if !runner.rom.is_empty() => runner.browser = None,
+            InputEvent::Video(command) => video.command(command),
+            _ => (),
+        }
+    }
+
+    update_status(runner, video);
+    match &runner.browser {
+        Some(browser) => video.present_menu(&browser_lines(browser)),
+        None => video.present(&runner.device.display),
+    }
+    return true;
+}
+
+/**
+ * Emulate frames until the frontend quits, or until the frame limit is reached
+ *
+ * The limit counts paused frames too, so a machine stopped on a broken stack does not hang a headless run.
+ */
+pub fn run(runner: &mut Chip8Runner, video: &mut dyn VideoSink, audio: &mut dyn AudioSink, input: &mut dyn InputSource, frame_limit: Option<u64>) {
+    let mut frames = 0;
+    loop {
+        if let Some(limit) = frame_limit {
+            if frames >= limit {
+                break;
+            }
+        }
+        if !run_frame(runner, video, audio, input) {
+            break;
+        }
+        frames += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chip8_display::get_px_at;
+    use crate::frontend::{NullFrontend, build_headless_audio, build_headless_video};
+    use crate::phosphor::build_phosphor;
+    use crate::zip_archive::tests::{build_zip, stored_file};
+    use super::*;
+
+    #[test]
+    fn headless_run_draws_the_ibm_logo() {
+        let mut runner = build_chip8_runner(build_chip8(), Vec::new(), None, "");
+        open_rom(&mut runner, "resources/IBM").unwrap();
+        let mut video = build_headless_video(build_phosphor(PersistenceMode::Off), runner.palette.clone());
+        let mut audio = build_headless_audio();
+        run(&mut runner, &mut video, &mut audio, &mut NullFrontend, Some(20));
+        let lit: Vec<(usize, usize)> = (0..32).flat_map(|y| (0..64).map(move |x| (x, y)))
+            .filter(|(x, y)| get_px_at(&video.last_frame, *x, *y))
+            .collect();
+        assert_eq!(lit.len(), 208);
+        assert_eq!(lit.first(), Some(&(12, 8)));
+        assert_eq!(lit.last(), Some(&(51, 22)));
+        // The logo is drawn on every other line
+        assert!(lit.iter().all(|(_, y)| y % 2 == 0));
+        // The program ends on a jump to itself
+        assert_eq!(runner.device.pc, 0x228);
+        assert_eq!(video.frames, 20);
+        assert_eq!(audio.beeps, vec![false; 20]);
+    }
+
+    #[test]
+    fn headless_run_pauses_on_a_broken_stack() {
+        let mut runner = build_chip8_runner(build_chip8(), vec![0x00, 0xEE], None, "ret");
+        reset(&mut runner);
+        let mut video = build_headless_video(build_phosphor(PersistenceMode::Off), runner.palette.clone());
+        let mut audio = build_headless_audio();
+        run(&mut runner, &mut video, &mut audio, &mut NullFrontend, Some(3));
+        assert!(runner.paused);
+        assert_eq!(runner.device.pc, 0x200);
+        assert_eq!(runner.device.ticks, 1);
+        assert_eq!(video.frames, 3);
+    }
+
+    #[test]
+    fn patches_go_to_the_rom_picked_in_an_archive() {
+        let dir = std::env::temp_dir().join(format!("rusty_chip_8_patches_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let archive = dir.join("pack.zip").to_string_lossy().to_string();
+        let patch = dir.join("fix.ips").to_string_lossy().to_string();
+        std::fs::write(&archive, build_zip(&[stored_file("BRIX", &[0x12, 0x00]), stored_file("PONG", &[0x13, 0x00])])).unwrap();
+        std::fs::write(&patch, b"PATCH\x00\x00\x01\x00\x01\x42EOF").unwrap();
+
+        let mut runner = build_chip8_runner(build_chip8(), Vec::new(), None, "");
+        runner.patches = vec![patch];
+        runner.patched_path = archive.clone();
+        assert_eq!(open_file(&mut runner, &archive), Ok(format!("Pick a ROM in {}", archive)));
+        open_rom(&mut runner, &format!("{}:BRIX", archive)).unwrap();
+        assert_eq!(runner.rom, vec![0x12, 0x42]);
+        // The patches stay with the picked ROM, for the reset and the reloads
+        assert_eq!(runner.patched_path, format!("{}:BRIX", archive));
+        open_rom(&mut runner, &format!("{}:PONG", archive)).unwrap();
+        assert_eq!(runner.rom, vec![0x13, 0x00]);
+        open_rom(&mut runner, &format!("{}:BRIX", archive)).unwrap();
+        assert_eq!(runner.rom, vec![0x12, 0x42]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+#![allow(clippy::needless_return)]
+
+use std::collections::BTreeMap;
+use std::fs;
+
+use crate::chip8_instructions::{CH8_INSTRUCTION, disassemble, get_instruction_type};
+
+/**
+ * Label names and data regions of a program
+ *
+ * Symbol files hold one symbol per line, `#` starts a comment :
+ *
+ * main = 0x200
+ * draw_paddle 0x2a4
+ * sprites = 0x300..0x320
+ *
+ * A single address defines a label, a range defines a data region (end excluded).
+ * The `=` sign is optional, so both the Octo and assembler style dumps can be loaded.
+ */
+pub struct SymbolTable {
+
+    // Labels by address
+    pub(crate) labels: BTreeMap<u16, String>,
+
+    // Data regions (start, end excluded, name)
+    pub(crate) data: Vec<(u16, u16, String)>,
+}
+
+pub fn build_symbol_table() -> SymbolTable {
+    return SymbolTable { labels: BTreeMap::new(), data: Vec::new() };
+}
+
+fn parse_number(word: &str) -> Option<u16> {
+    if let Some(hex) = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        return u16::from_str_radix(hex, 16).ok();
+    }
+    return word.parse().ok();
+}
+
+/**
+ * Load a symbol file, the error tells the offending line
+ */
+pub fn load_symbols(path: &str) -> Result<SymbolTable, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("cannot read symbol file {} ({})", path, e))?;
+    return parse_symbols(&text, path);
+}
+
+/**
+ * Read the symbols of a file already loaded, a name can only be given once
+ */
+pub fn parse_symbols(text: &str, path: &str) -> Result<SymbolTable, String> {
+    let mut symbols = build_symbol_table();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let words: Vec<&str> = line.split(|c: char| c.is_whitespace() || c == '=')
+            .filter(|w| !w.is_empty())
+            .collect();
+        if words.len() != 2 {
+            return Err(format!("{}:{}: expected '<name> = <address>'", path, number + 1));
+        }
+        let name = words[0].to_string();
+        if symbol_address(&symbols, &name).is_some() {
+            return Err(format!("{}:{}: duplicate symbol '{}'", path, number + 1, name));
+        }
+        let value = words[1];
+        match value.split_once("..") {
+            Some((start, end)) => {
+                match (parse_number(start), parse_number(end)) {
+                    (Some(start), Some(end)) if start < end => symbols.data.push((start, end, name)),
+                    _ => return Err(format!("{}:{}: invalid data region '{}'", path, number + 1, value)),
+                }
+            }
+            None => {
+                match parse_number(value) {
+                    Some(address) => { symbols.labels.insert(address, name); }
+                    None => return Err(format!("{}:{}: invalid address '{}'", path, number + 1, value)),
+                }
+            }
+        }
+    }
+    return Ok(symbols);
+}
+
+/**
+ * Address of a label or of the start of a data region
+ */
+pub fn symbol_address(symbols: &SymbolTable, name: &str) -> Option<u16> {
+    if let Some((address, _)) = symbols.labels.iter().find(|(_, n)| n.as_str() == name) {
+        return Some(*address);
+    }
+    return symbols.data.iter().find(|(_, _, n)| n == name).map(|(start, _, _)| *start);
+}
+
+/**
+ * Name of an address : a label on it, or a data region holding it (with the offset in the region)
+ */
+pub fn symbol_name(symbols: &SymbolTable, address: u16) -> Option<String> {
+    if let Some(name) = symbols.labels.get(&address) {
+        return Some(name.clone());
+    }
+    for (start, end, name) in &symbols.data {
+        if address >= *start && address < *end {
+            if address == *start {
+                return Some(name.clone());
+            }
+            return Some(format!("{}+{}", name, address - start));
+        }
+    }
+    return None;
+}
+
+/**
+ * Name of an address if known, its hexadecimal value otherwise
+ */
+pub fn format_address(symbols: &SymbolTable, address: u16) -> String {
+    return symbol_name(symbols, address).unwrap_or_else(|| format!("{:#05x}", address));
+}
+
+/**
+ * Location of code relative to the closest label before it, such as `main+6`
+ */
+pub fn describe_location(symbols: &SymbolTable, address: u16) -> Option<String> {
+    let (label, name) = symbols.labels.range(..=address).next_back()?;
+    if *label == address {
+        return Some(name.clone());
+    }
+    return Some(format!("{}+{}", name, address - label));
+}
+
+/**
+ * Disassemble an instruction, showing address operands by name
+ */
+pub fn disassemble_symbolic(ins: u16, symbols: &SymbolTable) -> String {
+    let nnn = ins & 0x0FFF;
+    return match get_instruction_type(ins) {
+        CH8_INSTRUCTION::SYS => format!("SYS {}", format_address(symbols, nnn)),
+        CH8_INSTRUCTION::JP => format!("JP {}", format_address(symbols, nnn)),
+        CH8_INSTRUCTION::CALL => format!("CALL {}", format_address(symbols, nnn)),
+        CH8_INSTRUCTION::LDIaddr => format!("LD I, {}", format_address(symbols, nnn)),
+        CH8_INSTRUCTION::JPV0addr => format!("JP V0, {}", format_address(symbols, nnn)),
+        _ => disassemble(ins),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbol_files() {
+        let text = "# Symbols of a game\nmain = 0x200\ndraw_paddle 0x2A4   # Octo style\n\nsprites = 0x300..0x320\nscore = 784\n";
+        let symbols = parse_symbols(text, "game.sym").unwrap();
+        assert_eq!(symbols.labels.len(), 3);
+        assert_eq!(symbol_address(&symbols, "draw_paddle"), Some(0x2A4));
+        assert_eq!(symbol_address(&symbols, "sprites"), Some(0x300));
+        assert_eq!(symbol_name(&symbols, 0x310), Some("score".to_string()));
+        assert_eq!(symbol_name(&symbols, 0x312), Some("sprites+18".to_string()));
+        assert_eq!(symbol_name(&symbols, 0x320), None);
+        assert_eq!(describe_location(&symbols, 0x2A0), Some("main+160".to_string()));
+        assert_eq!(describe_location(&symbols, 0x1FE), None);
+    }
+
+    #[test]
+    fn symbol_file_errors() {
+        let error = |text: &str| parse_symbols(text, "game.sym").err().unwrap();
+        assert_eq!(error("main = 0x200\n# again\nmain = 0x210"), "game.sym:3: duplicate symbol 'main'");
+        assert_eq!(error("main = 0x200\nsprites = 0x300..0x320\nsprites 0x400"), "game.sym:3: duplicate symbol 'sprites'");
+        assert_eq!(error("main = 0x2G0"), "game.sym:1: invalid address '0x2G0'");
+        assert_eq!(error("main = 70000"), "game.sym:1: invalid address '70000'");
+        assert_eq!(error("\nsprites = 0x320..0x300"), "game.sym:2: invalid data region '0x320..0x300'");
+        assert_eq!(error("main"), "game.sym:1: expected '<name> = <address>'");
+        assert_eq!(error("main = 0x200 0x202"), "game.sym:1: expected '<name> = <address>'");
+    }
+
+    #[test]
+    fn symbolic_operands() {
+        let symbols = parse_symbols("main = 0x200\nsprites = 0x300..0x320", "game.sym").unwrap();
+        assert_eq!(disassemble_symbolic(0x1200, &symbols), "JP main");
+        assert_eq!(disassemble_symbolic(0xA304, &symbols), "LD I, sprites+4");
+        assert_eq!(disassemble_symbolic(0x2400, &symbols), "CALL 0x400");
+    }
+}
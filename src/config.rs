@@ -0,0 +1,307 @@
+#![allow(clippy::needless_return)]
+
+use std::path::Path;
+
+use crate::chip8_quirks::{Chip8Quirks, QUIRK_PRESETS, build_default_quirks, quirks_preset};
+use crate::chip8_runner::{SPEED_LEVELS, TURBO_STEPS_PER_FRAME};
+use crate::keymap::parse_binding;
+use crate::palette::{THEME_NAMES, parse_color};
+use crate::rom_database::{MAX_INSTRUCTIONS_PER_FRAME, RomSettings, parse_settings_entry};
+use crate::sdl_frontend::{DEFAULT_SCALE, MAX_SCALE};
+use crate::toml_parser::{TomlEntry, TomlTable, entry_bool, entry_float, entry_integer, entry_string, entry_string_array, parse_toml};
+use crate::user_dirs::user_config_dir;
+
+// Configuration file, in the configuration directory
+const CONFIG_FILE: &str = "config.toml";
+
+// Beeper tone used when the configuration does not give one
+pub const DEFAULT_TONE: f32 = 500.0;
+pub const DEFAULT_VOLUME: f32 = 0.20;
+
+/**
+ * User settings, read from config.toml
+ *
+ * ```toml
+ * [video]
+ * scale = 12
+ * theme = "amber"
+ *
+ * [audio]
+ * tone = 440
+ * volume = 0.1
+ *
+ * [input]
+ * keys = ["5=Space"]
+ *
+ * [speed]
+ * instructions_per_frame = 20
+ *
+ * [quirks]
+ * preset = "vip"
+ *
+ * [rom.BLITZ]          # file name or SHA-1 of a ROM
+ * quirks = "vip"
+ * ```
+ */
+#[derive(Debug, Clone)]
+pub struct Config {
+
+    // [video]
+    pub(crate) scale: i32,
+    pub(crate) fullscreen: bool,
+    pub(crate) integer_scale: bool,
+    pub(crate) grid: bool,
+    pub(crate) theme: String,
+    pub(crate) grid_color: Option<u32>,
+    // Frames a turned off pixel takes to fade out, 0 when it goes off at once
+    pub(crate) persistence: u32,
+    pub(crate) blend_frames: bool,
+    pub(crate) osd_stats: bool,
+
+    // [audio], a volume of 0 mutes the beeper
+    pub(crate) tone: f32,
+    pub(crate) volume: f32,
+
+    // [input]
+    pub(crate) keymap: Option<String>,
+
+    // [speed]
+    pub(crate) turbo_instructions_per_frame: u32,
+    // Emulation speed at start, in percent, one of SPEED_LEVELS
+    pub(crate) speed: u32,
+
+    // Settings of all the ROMs : instructions_per_frame of [speed], [quirks], keys of [input], fg and bg of [video]
+    pub(crate) settings: RomSettings,
+
+    // [rom.<name or SHA-1>] sections, applied over the ROM database
+    pub(crate) roms: Vec<(String, RomSettings)>,
+}
+
+pub fn build_default_config() -> Config {
+    return Config {
+        scale: DEFAULT_SCALE,
+        fullscreen: false,
+        integer_scale: false,
+        grid: true,
+        theme: "classic".to_string(),
+        grid_color: None,
+        persistence: 0,
+        blend_frames: false,
+        osd_stats: false,
+        tone: DEFAULT_TONE,
+        volume: DEFAULT_VOLUME,
+        keymap: None,
+        turbo_instructions_per_frame: TURBO_STEPS_PER_FRAME,
+        speed: 100,
+        settings: RomSettings::default(),
+        roms: Vec::new(),
+    };
+}
+
+/**
+ * Path of the configuration file, which does not need to exist
+ */
+pub fn user_config_path() -> Option<String> {
+    return user_config_dir().map(|dir| dir.join(CONFIG_FILE).to_string_lossy().to_string());
+}
+
+/**
+ * Read a configuration file, errors name the file and the line
+ */
+pub fn load_config(path: &str) -> Result<Config, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("cannot read the configuration {} ({})", path, e))?;
+    return parse_config(&text).map_err(|e| format!("{} {}", path, e));
+}
+
+/**
+ * Configuration of the user : the given file, or config.toml in the configuration directory when there is one
+ */
+pub fn load_user_config(path: Option<&str>) -> Result<Config, String> {
+    return match path {
+        Some(path) => load_config(path),
+        None => match user_config_path().filter(|path| Path::new(path).is_file()) {
+            Some(path) => load_config(&path),
+            None => Ok(build_default_config()),
+        },
+    };
+}
+
+/**
+ * Settings of the [rom.<name>] section matching a ROM, by file name or SHA-1
+ */
+pub fn config_rom_settings<'a>(roms: &'a [(String, RomSettings)], rom_name: &str, hash: &str) -> Option<&'a RomSettings> {
+    return roms.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(hash) || name.eq_ignore_ascii_case(rom_name))
+        .map(|(_, settings)| settings);
+}
+
+pub fn parse_config(text: &str) -> Result<Config, String> {
+    let mut config = build_default_config();
+    for table in parse_toml(text)? {
+        let name: Vec<&str> = table.name.iter().map(|part| part.as_str()).collect();
+        match name.as_slice() {
+            [] => {
+                if let Some(entry) = table.entries.first() {
+                    return Err(format!("line {} : '{}' is not in a section, expected [video], [audio], [input], [speed], [quirks] or [rom.<name>]", entry.line, entry.key));
+                }
+            }
+            ["video"] => parse_video(&mut config, &table)?,
+            ["audio"] => parse_audio(&mut config, &table)?,
+            ["input"] => parse_input(&mut config, &table)?,
+            ["speed"] => parse_speed(&mut config, &table)?,
+            ["quirks"] => config.settings.quirks = Some(parse_quirks(&table)?),
+            ["rom", rom] => {
+                let mut settings = RomSettings::default();
+                for entry in &table.entries {
+                    parse_settings_entry(&mut settings, entry)?;
+                }
+                config.roms.push((rom.to_string(), settings));
+            }
+            _ => return Err(format!("line {} : unknown section [{}]", table.line, table.name.join("."))),
+        }
+    }
+    return Ok(config);
+}
+
+fn unknown_key(entry: &TomlEntry, section: &str) -> String {
+    return format!("line {} : unknown key '{}' in [{}]", entry.line, entry.key, section);
+}
+
+fn parse_video(config: &mut Config, table: &TomlTable) -> Result<(), String> {
+    for entry in &table.entries {
+        match entry.key.as_str() {
+            "scale" => config.scale = entry_integer(entry, 1, MAX_SCALE as i64)? as i32,
+            "fullscreen" => config.fullscreen = entry_bool(entry)?,
+            "integer_scale" => config.integer_scale = entry_bool(entry)?,
+            "grid" => config.grid = entry_bool(entry)?,
+            "theme" => {
+                let theme = entry_string(entry)?;
+                if !THEME_NAMES.contains(&theme.as_str()) {
+                    return Err(format!("line {} : unknown theme '{}', expected one of {}", entry.line, theme, THEME_NAMES.join(", ")));
+                }
+                config.theme = theme;
+            }
+            "grid_color" => config.grid_color = Some(parse_color(&entry_string(entry)?).map_err(|e| format!("line {} : {}", entry.line, e))?),
+            "persistence" => config.persistence = entry_integer(entry, 0, 255)? as u32,
+            "blend_frames" => config.blend_frames = entry_bool(entry)?,
+            "osd_stats" => config.osd_stats = entry_bool(entry)?,
+            "fg" | "bg" => parse_settings_entry(&mut config.settings, entry)?,
+            _ => return Err(unknown_key(entry, "video")),
+        }
+    }
+    return Ok(());
+}
+
+fn parse_audio(config: &mut Config, table: &TomlTable) -> Result<(), String> {
+    for entry in &table.entries {
+        match entry.key.as_str() {
+            "tone" => config.tone = entry_float(entry, 20.0, 20000.0)? as f32,
+            "volume" => config.volume = entry_float(entry, 0.0, 1.0)? as f32,
+            _ => return Err(unknown_key(entry, "audio")),
+        }
+    }
+    return Ok(());
+}
+
+fn parse_input(config: &mut Config, table: &TomlTable) -> Result<(), String> {
+    for entry in &table.entries {
+        match entry.key.as_str() {
+            "keymap" => config.keymap = Some(entry_string(entry)?),
+            "keys" => {
+                for binding in entry_string_array(entry)? {
+                    config.settings.keys.push(parse_binding(&binding).map_err(|e| format!("line {} : {}", entry.line, e))?);
+                }
+            }
+            _ => return Err(unknown_key(entry, "input")),
+        }
+    }
+    return Ok(());
+}
+
+fn parse_speed(config: &mut Config, table: &TomlTable) -> Result<(), String> {
+    for entry in &table.entries {
+        match entry.key.as_str() {
+            "instructions_per_frame" => parse_settings_entry(&mut config.settings, entry)?,
+            "turbo_instructions_per_frame" => config.turbo_instructions_per_frame = entry_integer(entry, 1, MAX_INSTRUCTIONS_PER_FRAME)? as u32,
+            "speed" => {
+                let speed = entry_integer(entry, 0, i64::MAX)?;
+                // Compared before narrowing, a large value would wrap to one of the levels
+                if !SPEED_LEVELS.iter().any(|level| *level as i64 == speed) {
+                    let levels: Vec<String> = SPEED_LEVELS.iter().map(|level| level.to_string()).collect();
+                    return Err(format!("line {} : speed must be one of {} (percent), not {}", entry.line, levels.join(", "), speed));
+                }
+                config.speed = speed as u32;
+            }
+            _ => return Err(unknown_key(entry, "speed")),
+        }
+    }
+    return Ok(());
+}
+
+/**
+ * Quirks of a preset, with some of them changed one by one
+ */
+fn parse_quirks(table: &TomlTable) -> Result<Chip8Quirks, String> {
+    let mut quirks = build_default_quirks();
+    if let Some(entry) = table.entries.iter().find(|entry| entry.key == "preset") {
+        let name = entry_string(entry)?;
+        quirks = quirks_preset(&name)
+            .ok_or_else(|| format!("line {} : unknown quirks '{}', expected one of {}", entry.line, name, QUIRK_PRESETS.join(", ")))?;
+    }
+    for entry in &table.entries {
+        match entry.key.as_str() {
+            "preset" => (),
+            "shift_vy" => quirks.shift_vy = entry_bool(entry)?,
+            "load_store_increment" => quirks.load_store_increment = entry_bool(entry)?,
+            "jump_vx" => quirks.jump_vx = entry_bool(entry)?,
+            "logic_reset_vf" => quirks.logic_reset_vf = entry_bool(entry)?,
+            "clip_sprites" => quirks.clip_sprites = entry_bool(entry)?,
+            _ => return Err(unknown_key(entry, "quirks")),
+        }
+    }
+    return Ok(quirks);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chip8_quirks::quirks_preset;
+    use super::*;
+
+    #[test]
+    fn sections_and_rom_overrides() {
+        let config = parse_config("[video]\nscale = 12\ntheme = \"amber\"\n\n[speed]\ninstructions_per_frame = 20\nspeed = 150\n\n\
+            [quirks]\npreset = \"vip\"\nclip_sprites = false\n\n[rom.BLITZ]\nquirks = \"schip\"\ninstructions_per_frame = 8\n").unwrap();
+        assert_eq!(config.scale, 12);
+        assert_eq!(config.theme, "amber");
+        assert_eq!(config.speed, 150);
+        assert_eq!(config.settings.instructions_per_frame, Some(20));
+        let mut quirks = quirks_preset("vip").unwrap();
+        quirks.clip_sprites = false;
+        assert_eq!(config.settings.quirks, Some(quirks));
+
+        let blitz = config_rom_settings(&config.roms, "blitz", "0000").unwrap();
+        assert_eq!(blitz.quirks, quirks_preset("schip"));
+        assert_eq!(blitz.instructions_per_frame, Some(8));
+        assert!(config_rom_settings(&config.roms, "BRIX", "0000").is_none());
+    }
+
+    #[test]
+    fn config_errors() {
+        assert_eq!(parse_config("[sound]\nvolume = 0.5").err(), Some("line 1 : unknown section [sound]".to_string()));
+        assert_eq!(parse_config("[audio]\nvolume = 0.5\nloud = true").err(), Some("line 3 : unknown key 'loud' in [audio]".to_string()));
+        assert_eq!(parse_config("scale = 2").err().unwrap(),
+                   "line 1 : 'scale' is not in a section, expected [video], [audio], [input], [speed], [quirks] or [rom.<name>]");
+        assert_eq!(parse_config("[rom.PONG]\nspeed = 50").err(), Some("line 2 : unknown key 'speed'".to_string()));
+    }
+
+    #[test]
+    fn speed_must_be_a_level() {
+        let levels = "10, 25, 50, 75, 100, 125, 150, 200, 300, 400";
+        assert_eq!(parse_config("[speed]\nspeed = 110").err(), Some(format!("line 2 : speed must be one of {} (percent), not 110", levels)));
+        // 2^32 + 100 would be 100 once narrowed to 32 bits
+        assert_eq!(parse_config("[speed]\nspeed = 4294967396").err(),
+                   Some(format!("line 2 : speed must be one of {} (percent), not 4294967396", levels)));
+        assert!(parse_config("[speed]\nspeed = -1").is_err());
+        assert_eq!(parse_config("[speed]\nspeed = 400").unwrap().speed, 400);
+    }
+}
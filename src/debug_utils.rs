@@ -1,9 +1,7 @@
 use crate::chip8;
 use crate::chip8_display;
-use crate::chip8_display::Chip8Display;
 use crate::chip8_memory;
 use crate::chip8_memory::END_MEM;
-use std::ops::Sub;
 use crate::chip8::KEYBOARD_SIZE;
 
 /**
@@ -25,9 +23,9 @@ pub fn print_display(device: &chip8::Chip8) {
         for j in 0..chip8_display::DISPLAY_WIDTH / 8 {
             for b in 0..8 {
                 if device.display.display_data[i][j] & (0b10000000 >> b) > 0 {
-                    print!("{}", "⬛");
+                    print!("⬛");
                 } else {
-                    print!("{}", "⬜");
+                    print!("⬜");
                 }
             }
         }
@@ -38,12 +36,13 @@ pub fn print_display(device: &chip8::Chip8) {
 /**
  * Display the Chip 8 screen in the console
  */
+#[allow(dead_code)]
 pub fn print_keyboard(device: &chip8::Chip8) {
     for i in 0..KEYBOARD_SIZE {
         if device.keyboard[i]{
-            print!("{}", "⬛");
+            print!("⬛");
         } else {
-            print!("{}", "⬜");
+            print!("⬜");
         }
     }
     println!("|")
@@ -67,6 +66,7 @@ pub fn print_keyboard(device: &chip8::Chip8) {
  * Each point represent 64 bytes of Data
  * When the memory is blank (filled with 0, we print ., else +)
  */
+#[allow(dead_code)]
 pub fn print_memory(device: &chip8::Chip8) {
 
     let mut position = END_MEM-1;
@@ -82,7 +82,7 @@ pub fn print_memory(device: &chip8::Chip8) {
         }
         if not_blank { print!("+"); } else { print!("."); }
         if position > 64 {
-            position = position - 64;
+            position -= 64;
         }
     }
 
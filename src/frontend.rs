@@ -0,0 +1,200 @@
+#![allow(clippy::needless_return)]
+
+use crate::chip8_display::{Chip8Display, build_chip8_display};
+use crate::image_export::save_screenshot;
+use crate::keymap::Keymap;
+use crate::palette::Palette;
+use crate::phosphor::{Phosphor, phosphor_update};
+
+/**
+ * Host events understood by the emulation loop
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputEvent {
+    // Close the emulator
+    Quit,
+    // Chip 8 key (0x0 - 0xF) pressed (true) or released (false)
+    Key(usize, bool),
+    // Run the CPU faster while held
+    Turbo(bool),
+    // Save the display to a picture
+    Screenshot,
+    // Start or stop recording the gameplay
+    Record,
+    // Pause or resume the emulation
+    Pause,
+    // Emulate one frame while paused
+    FrameAdvance,
+    // Power cycle the machine and load the ROM again
+    Reset,
+    // Switch to the next quirk preset
+    NextQuirks,
+    // Save the machine to the save state file of the ROM, or restore it
+    SaveState,
+    LoadState,
+    // Leave the game for the ROM browser
+    Browse,
+    // Play a ROM or restore a save state, such as a file dropped on the window
+    Open(String),
+    // Navigation in the ROM browser
+    Menu(MenuCommand),
+    // Go up (1) or down (-1) the emulation speed levels
+    Speed(i32),
+    // Window hotkey, handled by the video sink
+    Video(VideoCommand),
+}
+
+/**
+ * Window changes asked at runtime
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCommand {
+    // Switch between windowed and fullscreen
+    ToggleFullscreen,
+    // Switch between integer-only and fractional scaling
+    ToggleIntegerScale,
+    // Grow (1) or shrink (-1) the window by one scale step
+    Zoom(i32),
+    // Switch to the next built-in colour theme
+    NextTheme,
+    // Show or hide the metrics in the on-screen display
+    ToggleStats,
+}
+
+/**
+ * Move in the ROM browser list or pick a ROM
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuCommand {
+    // Move the selection by this number of entries, negative to go up
+    Move(i32),
+    // Jump to the first (false) or to the last (true) entry
+    End(bool),
+    // Pick the highlighted entry
+    Select,
+}
+
+/**
+ * Where the Chip 8 display is shown
+ */
+pub trait VideoSink {
+    fn present(&mut self, display: &Chip8Display);
+
+    // Sinks without a window ignore the window hotkeys
+    fn command(&mut self, _command: VideoCommand) {}
+
+    // Show the emulator state (ROM, paused, speed...) to the user
+    fn set_status(&mut self, _status: &str) {}
+
+    // Show a short lived message, such as the new speed after a hotkey, frontends not drawing in the console also log it there
+    fn show_message(&mut self, _text: &str) {}
+
+    // Update the live metrics (FPS, instructions per second...)
+    fn set_stats(&mut self, _stats: &str) {}
+
+    // Colours of the ROM being played
+    fn set_palette(&mut self, _palette: &Palette) {}
+
+    // Show lines of text instead of the Chip 8 display, such as the ROM browser
+    fn present_menu(&mut self, _lines: &[String]) {}
+
+    // Write the last presented frame to a PNG or PPM file, each Chip 8 pixel drawn as scale x scale pixels
+    fn screenshot(&mut self, _path: &str, _scale: usize) -> Result<(), String> {
+        return Err("this display cannot take screenshots".to_string());
+    }
+}
+
+/**
+ * Where the Chip 8 beeper is played
+ */
+pub trait AudioSink {
+    fn set_beep(&mut self, on: bool);
+}
+
+/**
+ * Where the host events come from
+ */
+pub trait InputSource {
+    fn poll_events(&mut self) -> Vec<InputEvent>;
+
+    // Host keys bound to the Chip 8 keypad, changed when another ROM is loaded
+    fn set_keymap(&mut self, _keymap: Keymap) {}
+}
+
+/**
+ * Frontend doing nothing, for the parts a run does not need
+ */
+pub struct NullFrontend;
+
+impl VideoSink for NullFrontend {
+    fn present(&mut self, _display: &Chip8Display) {}
+}
+
+impl AudioSink for NullFrontend {
+    fn set_beep(&mut self, _on: bool) {}
+}
+
+impl InputSource for NullFrontend {
+    fn poll_events(&mut self) -> Vec<InputEvent> {
+        return Vec::new();
+    }
+}
+
+/**
+ * Video sink keeping the last presented frame in memory
+ */
+pub struct HeadlessVideo {
+
+    // Number of frames presented
+    pub(crate) frames: u64,
+
+    // Copy of the last presented display
+    pub(crate) last_frame: Chip8Display,
+
+    // Brightness of the pixels after persistence
+    pub(crate) phosphor: Phosphor,
+
+    // Colours of the screenshots
+    pub(crate) palette: Palette,
+}
+
+pub fn build_headless_video(phosphor: Phosphor, palette: Palette) -> HeadlessVideo {
+    return HeadlessVideo { frames: 0, last_frame: build_chip8_display(), phosphor, palette };
+}
+
+impl VideoSink for HeadlessVideo {
+    fn present(&mut self, display: &Chip8Display) {
+        phosphor_update(&mut self.phosphor, display);
+        self.last_frame = display.clone();
+        self.frames += 1;
+    }
+
+    fn set_palette(&mut self, palette: &Palette) {
+        self.palette = palette.clone();
+    }
+
+    fn show_message(&mut self, text: &str) {
+        println!("{}", text);
+    }
+
+    fn screenshot(&mut self, path: &str, scale: usize) -> Result<(), String> {
+        return save_screenshot(path, &self.phosphor, &self.palette, scale);
+    }
+}
+
+/**
+ * Audio sink recording the beeper state of every frame
+ */
+pub struct HeadlessAudio {
+    pub(crate) beeps: Vec<bool>,
+}
+
+pub fn build_headless_audio() -> HeadlessAudio {
+    return HeadlessAudio { beeps: Vec::new() };
+}
+
+impl AudioSink for HeadlessAudio {
+    fn set_beep(&mut self, on: bool) {
+        self.beeps.push(on);
+    }
+}
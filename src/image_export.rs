@@ -0,0 +1,203 @@
+#![allow(clippy::needless_return)]
+
+use crate::chip8_display::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::palette::{Palette, intensity_color};
+use crate::phosphor::{Phosphor, phosphor_intensity};
+
+// Largest block of a stored (uncompressed) deflate stream
+const DEFLATE_STORED_BLOCK: usize = 65535;
+
+/**
+ * Picture in RGB, 3 bytes per pixel, row by row
+ */
+pub struct RgbImage {
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+    pub(crate) data: Vec<u8>,
+}
+
+/**
+ * Draw the display in the palette colours, each Chip 8 pixel becoming a square of scale x scale pixels
+ */
+pub fn render_image(phosphor: &Phosphor, palette: &Palette, scale: usize) -> RgbImage {
+    let scale = scale.max(1);
+    let width = DISPLAY_WIDTH * scale;
+    let height = DISPLAY_HEIGHT * scale;
+    let mut data = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            let color = intensity_color(palette, phosphor_intensity(phosphor, x / scale, y / scale));
+            data.extend_from_slice(&[(color >> 16) as u8, (color >> 8) as u8, color as u8]);
+        }
+    }
+    return RgbImage { width, height, data };
+}
+
+/**
+ * CRC-32 used by PNG chunks (and zip, gzip...)
+ */
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    return !crc;
+}
+
+/**
+ * Adler-32 checksum ending a zlib stream
+ */
+fn adler32(bytes: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for byte in bytes {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    return (b << 16) | a;
+}
+
+/**
+ * Wrap bytes in a zlib stream made of stored deflate blocks
+ *
+ * Chip 8 pictures are small, compressing them is not worth an encoder.
+ */
+fn zlib_stored(bytes: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = bytes.chunks(DEFLATE_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(if blocks.peek().is_none() { 0x01 } else { 0x00 });
+        let length = block.len() as u16;
+        out.extend_from_slice(&length.to_le_bytes());
+        out.extend_from_slice(&(!length).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(bytes).to_be_bytes());
+    return out;
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/**
+ * Encode a picture as PNG (8 bits RGB, no interlacing)
+ */
+pub fn encode_png(image: &RgbImage) -> Vec<u8> {
+    let mut out = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(image.width as u32).to_be_bytes());
+    header.extend_from_slice(&(image.height as u32).to_be_bytes());
+    // Bit depth 8, colour type RGB, default compression, filter and interlace methods
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    png_chunk(&mut out, b"IHDR", &header);
+
+    // Each row starts with its filter type, 0 for none
+    let mut rows = Vec::with_capacity((image.width * 3 + 1) * image.height);
+    for row in image.data.chunks(image.width * 3) {
+        rows.push(0);
+        rows.extend_from_slice(row);
+    }
+    png_chunk(&mut out, b"IDAT", &zlib_stored(&rows));
+    png_chunk(&mut out, b"IEND", &[]);
+    return out;
+}
+
+/**
+ * Encode a picture as binary PPM
+ */
+pub fn encode_ppm(image: &RgbImage) -> Vec<u8> {
+    let mut out = format!("P6\n{} {}\n255\n", image.width, image.height).into_bytes();
+    out.extend_from_slice(&image.data);
+    return out;
+}
+
+/**
+ * File name of a screenshot, from the ROM name and the frame number
+ */
+pub fn screenshot_name(rom_name: &str, frame: u64) -> String {
+    return format!("{}_{:06}.png", rom_name, frame);
+}
+
+/**
+ * Write the display to a PNG file, or a PPM file when the path ends with .ppm
+ */
+pub fn save_screenshot(path: &str, phosphor: &Phosphor, palette: &Palette, scale: usize) -> Result<(), String> {
+    let image = render_image(phosphor, palette, scale);
+    let bytes = if path.to_lowercase().ends_with(".ppm") { encode_ppm(&image) } else { encode_png(&image) };
+    return std::fs::write(path, bytes).map_err(|e| format!("cannot write {} ({})", path, e));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::inflate::inflate;
+    use super::*;
+
+    #[test]
+    fn crc32_known_answers() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414FA339);
+    }
+
+    #[test]
+    fn adler32_known_answers() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+    }
+
+    #[test]
+    fn zlib_stored_round_trip() {
+        for size in [0, 10, DEFLATE_STORED_BLOCK, DEFLATE_STORED_BLOCK + 1, 3 * DEFLATE_STORED_BLOCK / 2] {
+            let bytes: Vec<u8> = (0..size).map(|n| (n * 7 % 251) as u8).collect();
+            let stream = zlib_stored(&bytes);
+            assert_eq!(&stream[..2], &[0x78, 0x01]);
+            assert_eq!(u16::from_be_bytes([stream[0], stream[1]]) % 31, 0);
+            assert_eq!(inflate(&stream[2..stream.len() - 4]).unwrap(), bytes);
+            assert_eq!(stream[stream.len() - 4..], adler32(&bytes).to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn png_chunks_and_pixels() {
+        let image = RgbImage { width: 2, height: 2, data: vec![255, 0, 0, 0, 255, 0, 0, 0, 255, 1, 2, 3] };
+        let png = encode_png(&image);
+        assert_eq!(png[..8], [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]);
+        let mut chunks = Vec::new();
+        let mut position = 8;
+        while position < png.len() {
+            let length = u32::from_be_bytes([png[position], png[position + 1], png[position + 2], png[position + 3]]) as usize;
+            let kind = &png[position + 4..position + 8];
+            let data = &png[position + 8..position + 8 + length];
+            let crc = &png[position + 8 + length..position + 12 + length];
+            assert_eq!(crc, crc32(&png[position + 4..position + 8 + length]).to_be_bytes());
+            chunks.push((kind.to_vec(), data.to_vec()));
+            position += 12 + length;
+        }
+        assert_eq!(position, png.len());
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(kind, _)| kind.as_slice()).collect();
+        assert_eq!(kinds, [b"IHDR".as_slice(), b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 2, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        let idat = &chunks[1].1;
+        let rows = inflate(&idat[2..idat.len() - 4]).unwrap();
+        assert_eq!(rows, [0, 255, 0, 0, 0, 255, 0, 0, 0, 0, 255, 1, 2, 3]);
+    }
+
+    #[test]
+    fn ppm_header() {
+        let image = RgbImage { width: 1, height: 1, data: vec![1, 2, 3] };
+        assert_eq!(encode_ppm(&image), b"P6\n1 1\n255\n\x01\x02\x03");
+    }
+}
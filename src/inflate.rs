@@ -0,0 +1,222 @@
+/*!
+ * Decoder for raw DEFLATE streams (RFC 1951), the compression of zip archives
+ *
+ * ROMs are a few kilobytes, so the decoder favours short code over speed : Huffman codes are decoded bit by bit.
+ */
+
+#![allow(clippy::needless_return)]
+
+// Base lengths and extra bits of the length codes 257 to 285
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+
+// Base distances and extra bits of the distance codes 0 to 29
+const DISTANCE_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+// Order in which the lengths of the code length alphabet are stored in dynamic blocks
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+// Longest Huffman code allowed by the format
+const MAX_BITS: usize = 15;
+
+/**
+ * Reads bits least significant first, as DEFLATE packs them
+ */
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    bit: u32,
+}
+
+fn read_bit(reader: &mut BitReader) -> Result<u32, String> {
+    let byte = *reader.bytes.get(reader.position).ok_or("the compressed data is truncated")?;
+    let bit = (byte as u32 >> reader.bit) & 1;
+    reader.bit += 1;
+    if reader.bit == 8 {
+        reader.bit = 0;
+        reader.position += 1;
+    }
+    return Ok(bit);
+}
+
+fn read_bits(reader: &mut BitReader, count: u8) -> Result<u32, String> {
+    let mut value = 0;
+    for i in 0..count {
+        value |= read_bit(reader)? << i;
+    }
+    return Ok(value);
+}
+
+/**
+ * Canonical Huffman code, as the number of codes of each length and the symbols sorted by code
+ */
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+fn build_huffman(lengths: &[u8]) -> Huffman {
+    let mut counts = [0u16; MAX_BITS + 1];
+    for length in lengths {
+        counts[*length as usize] += 1;
+    }
+    counts[0] = 0;
+    let mut offsets = [0u16; MAX_BITS + 2];
+    for bits in 1..=MAX_BITS {
+        offsets[bits + 1] = offsets[bits] + counts[bits];
+    }
+    let mut symbols = vec![0u16; offsets[MAX_BITS + 1] as usize];
+    for (symbol, length) in lengths.iter().enumerate() {
+        if *length > 0 {
+            symbols[offsets[*length as usize] as usize] = symbol as u16;
+            offsets[*length as usize] += 1;
+        }
+    }
+    return Huffman { counts, symbols };
+}
+
+/**
+ * Read one symbol, the codes of each length follow the last code of the length below
+ */
+fn decode_symbol(reader: &mut BitReader, huffman: &Huffman) -> Result<u16, String> {
+    let mut code: i32 = 0;
+    let mut first: i32 = 0;
+    let mut index: i32 = 0;
+    for bits in 1..=MAX_BITS {
+        code |= read_bit(reader)? as i32;
+        let count = huffman.counts[bits] as i32;
+        if code - first < count {
+            return Ok(huffman.symbols[(index + code - first) as usize]);
+        }
+        index += count;
+        first = (first + count) << 1;
+        code <<= 1;
+    }
+    return Err("invalid Huffman code in the compressed data".to_string());
+}
+
+/**
+ * Decompress a raw DEFLATE stream
+ */
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut reader = BitReader { bytes: data, position: 0, bit: 0 };
+    let mut out = Vec::new();
+    loop {
+        let last = read_bit(&mut reader)? == 1;
+        match read_bits(&mut reader, 2)? {
+            0 => inflate_stored(&mut reader, &mut out)?,
+            1 => {
+                let (literals, distances) = fixed_codes();
+                inflate_codes(&mut reader, &mut out, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = read_dynamic_codes(&mut reader)?;
+                inflate_codes(&mut reader, &mut out, &literals, &distances)?;
+            }
+            _ => return Err("invalid block type in the compressed data".to_string()),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+/**
+ * Block copied as is, after its length and the one's complement of its length
+ */
+fn inflate_stored(reader: &mut BitReader, out: &mut Vec<u8>) -> Result<(), String> {
+    if reader.bit > 0 {
+        reader.bit = 0;
+        reader.position += 1;
+    }
+    let header = reader.bytes.get(reader.position..reader.position + 4).ok_or("the compressed data is truncated")?;
+    let length = u16::from_le_bytes([header[0], header[1]]);
+    if length != !u16::from_le_bytes([header[2], header[3]]) {
+        return Err("invalid stored block length in the compressed data".to_string());
+    }
+    let start = reader.position + 4;
+    let block = reader.bytes.get(start..start + length as usize).ok_or("the compressed data is truncated")?;
+    out.extend_from_slice(block);
+    reader.position = start + length as usize;
+    return Ok(());
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    for (symbol, length) in lengths.iter_mut().enumerate() {
+        *length = match symbol {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    return (build_huffman(&lengths), build_huffman(&[5u8; 30]));
+}
+
+/**
+ * Codes of a dynamic block, themselves compressed with a code given first
+ */
+fn read_dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), String> {
+    let literal_count = read_bits(reader, 5)? as usize + 257;
+    let distance_count = read_bits(reader, 5)? as usize + 1;
+    let length_count = read_bits(reader, 4)? as usize + 4;
+    let mut code_lengths = [0u8; 19];
+    for index in CODE_LENGTH_ORDER.iter().take(length_count) {
+        code_lengths[*index] = read_bits(reader, 3)? as u8;
+    }
+    let code_lengths = build_huffman(&code_lengths);
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let symbol = decode_symbol(reader, &code_lengths)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths.last().ok_or("invalid code lengths in the compressed data")?;
+                (previous, 3 + read_bits(reader, 2)?)
+            }
+            17 => (0, 3 + read_bits(reader, 3)?),
+            _ => (0, 11 + read_bits(reader, 7)?),
+        };
+        for _ in 0..repeat {
+            lengths.push(value);
+        }
+    }
+    if lengths.len() > literal_count + distance_count || lengths[256] == 0 {
+        return Err("invalid code lengths in the compressed data".to_string());
+    }
+    return Ok((build_huffman(&lengths[..literal_count]), build_huffman(&lengths[literal_count..])));
+}
+
+/**
+ * Literals and back references of a compressed block, up to its end code
+ */
+fn inflate_codes(reader: &mut BitReader, out: &mut Vec<u8>, literals: &Huffman, distances: &Huffman) -> Result<(), String> {
+    loop {
+        let symbol = decode_symbol(reader, literals)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            257..=285 => {
+                let code = symbol - 257;
+                let length = LENGTH_BASE[code] as usize + read_bits(reader, LENGTH_EXTRA[code])? as usize;
+                let code = decode_symbol(reader, distances)? as usize;
+                if code >= DISTANCE_BASE.len() {
+                    return Err("invalid distance in the compressed data".to_string());
+                }
+                let distance = DISTANCE_BASE[code] as usize + read_bits(reader, DISTANCE_EXTRA[code])? as usize;
+                if distance > out.len() {
+                    return Err("distance too far back in the compressed data".to_string());
+                }
+                // The copy can overlap the bytes it produces
+                let start = out.len() - distance;
+                for i in 0..length {
+                    out.push(out[start + i]);
+                }
+            }
+            _ => return Err("invalid literal in the compressed data".to_string()),
+        }
+    }
+}
@@ -0,0 +1,210 @@
+#![allow(clippy::needless_return)]
+
+use std::path::Path;
+
+use crate::chip8::KEYBOARD_SIZE;
+use crate::zip_archive::{entry_file_name, split_archive_path};
+
+/**
+ * What a host key does in the emulator
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAction {
+    // Chip 8 key (0x0 - 0xF)
+    Key(usize),
+    // Run the CPU faster while held
+    Turbo,
+}
+
+/**
+ * Host keys bound to the Chip 8 keypad
+ *
+ * Host keys are named like SDL names them ("Q", "1", "Space", "Keypad 5"...), without case.
+ * Game controller inputs are named after the SDL controller buttons and axes, with a "Pad " prefix
+ * ("Pad A", "Pad DPUp", "Pad Start"...), a stick direction adds the sign of the axis ("Pad LeftX+" is the left stick pushed right).
+ */
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    pub(crate) bindings: Vec<(String, KeyAction)>,
+}
+
+/*
+    Default layout, the left part of a QWERTY keyboard stands for the Chip 8 keypad :
+
+    1 2 3 4        1 2 3 C
+    Q W E R   ->   4 5 6 D
+    A S D F        7 8 9 E
+    Z X C V        A 0 B F
+ */
+const DEFAULT_LAYOUT: [(&str, usize); KEYBOARD_SIZE] = [
+    ("1", 0x1), ("2", 0x2), ("3", 0x3), ("4", 0xC),
+    ("Q", 0x4), ("W", 0x5), ("E", 0x6), ("R", 0xD),
+    ("A", 0x7), ("S", 0x8), ("D", 0x9), ("F", 0xE),
+    ("Z", 0xA), ("X", 0x0), ("C", 0xB), ("V", 0xF),
+];
+
+const DEFAULT_TURBO_KEY: &str = "Tab";
+
+pub const PAD_PREFIX: &str = "Pad ";
+
+// Directions on 2 / 4 / 6 / 8 like most games use them, actions on the buttons
+const DEFAULT_PAD_LAYOUT: [(&str, KeyAction); 15] = [
+    ("Pad DPUp", KeyAction::Key(0x2)), ("Pad LeftY-", KeyAction::Key(0x2)),
+    ("Pad DPDown", KeyAction::Key(0x8)), ("Pad LeftY+", KeyAction::Key(0x8)),
+    ("Pad DPLeft", KeyAction::Key(0x4)), ("Pad LeftX-", KeyAction::Key(0x4)),
+    ("Pad DPRight", KeyAction::Key(0x6)), ("Pad LeftX+", KeyAction::Key(0x6)),
+    ("Pad A", KeyAction::Key(0x5)), ("Pad B", KeyAction::Key(0x0)),
+    ("Pad X", KeyAction::Key(0xA)), ("Pad Y", KeyAction::Key(0xB)),
+    ("Pad Start", KeyAction::Key(0xF)), ("Pad Back", KeyAction::Key(0xE)),
+    ("Pad RightShoulder", KeyAction::Turbo),
+];
+
+pub fn build_default_keymap() -> Keymap {
+    let mut bindings: Vec<(String, KeyAction)> = DEFAULT_LAYOUT.iter()
+        .map(|(host, key)| (host.to_string(), KeyAction::Key(*key)))
+        .collect();
+    bindings.push((DEFAULT_TURBO_KEY.to_string(), KeyAction::Turbo));
+    bindings.extend(DEFAULT_PAD_LAYOUT.iter().map(|(host, action)| (host.to_string(), *action)));
+    return Keymap { bindings };
+}
+
+/**
+ * Tell whether a host key name is a game controller input
+ */
+pub fn is_pad_input(host: &str) -> bool {
+    return host.get(..PAD_PREFIX.len()).is_some_and(|prefix| prefix.eq_ignore_ascii_case(PAD_PREFIX));
+}
+
+/**
+ * Action bound to a host key
+ */
+pub fn keymap_action(keymap: &Keymap, host: &str) -> Option<KeyAction> {
+    return keymap.bindings.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(host))
+        .map(|(_, action)| *action);
+}
+
+/**
+ * Bind an action to these host keys, replacing its previous keys
+ *
+ * Keyboard keys only replace keyboard keys and controller inputs only replace controller inputs.
+ * The host keys are taken away from the actions they were bound to.
+ */
+pub fn bind_keys(keymap: &mut Keymap, action: KeyAction, hosts: &[String]) {
+    keymap.bindings.retain(|(name, bound)| {
+        let replaced = *bound == action && hosts.iter().any(|host| is_pad_input(host) == is_pad_input(name));
+        return !replaced && !hosts.iter().any(|host| host.eq_ignore_ascii_case(name));
+    });
+    for host in hosts {
+        keymap.bindings.push((host.clone(), action));
+    }
+}
+
+/**
+ * Parse a binding written `<key> = <host key>, <host key>...`
+ *
+ * The key is a Chip 8 key in hexadecimal (0 - F) or `turbo`.
+ */
+pub fn parse_binding(text: &str) -> Result<(KeyAction, Vec<String>), String> {
+    let (key, hosts) = match text.split_once('=') {
+        Some(parts) => parts,
+        None => return Err(format!("'{}' is not a key binding, expected <key> = <host key>", text)),
+    };
+    let key = key.trim();
+    let action = if key.eq_ignore_ascii_case("turbo") {
+        KeyAction::Turbo
+    } else {
+        match usize::from_str_radix(key.trim_start_matches("0x"), 16) {
+            Ok(key) if key < KEYBOARD_SIZE => KeyAction::Key(key),
+            _ => return Err(format!("'{}' is not a Chip 8 key, expected 0 - F or turbo", key)),
+        }
+    };
+    let hosts: Vec<String> = hosts.split(',').map(|host| host.trim().to_string()).filter(|host| !host.is_empty()).collect();
+    if hosts.is_empty() {
+        return Err(format!("no host key given in '{}'", text));
+    }
+    return Ok((action, hosts));
+}
+
+/**
+ * Apply the bindings of a keymap file, one binding per line, `#` starts a comment
+ */
+pub fn load_keymap_file(keymap: &mut Keymap, path: &str) -> Result<(), String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("cannot read the keymap {} ({})", path, e))?;
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (action, hosts) = parse_binding(line).map_err(|e| format!("{} line {} : {}", path, n + 1, e))?;
+        bind_keys(keymap, action, &hosts);
+    }
+    return Ok(());
+}
+
+/**
+ * Keymap file of a ROM, next to it with the .keys extension, next to the archive for the ROMs of a zip archive
+ */
+pub fn rom_keymap_path(rom_path: &str) -> Option<String> {
+    let path = match split_archive_path(rom_path) {
+        Some((archive, member)) => Path::new(archive).with_file_name(Path::new(entry_file_name(member)).with_extension("keys")),
+        None => Path::new(rom_path).with_extension("keys"),
+    };
+    return if path.is_file() { Some(path.to_string_lossy().to_string()) } else { None };
+}
+
+/**
+ * Keymap to play a ROM : the base keymap, the bindings known for the ROM, then its keymap file, then the bindings given on the command line
+ */
+pub fn rom_keymap(base: &Keymap, rom_path: &str, rom_bindings: &[(KeyAction, Vec<String>)], overrides: &[(KeyAction, Vec<String>)]) -> Result<Keymap, String> {
+    let mut keymap = base.clone();
+    for (action, hosts) in rom_bindings {
+        bind_keys(&mut keymap, *action, hosts);
+    }
+    if let Some(path) = rom_keymap_path(rom_path) {
+        load_keymap_file(&mut keymap, &path)?;
+    }
+    for (action, hosts) in overrides {
+        bind_keys(&mut keymap, *action, hosts);
+    }
+    return Ok(keymap);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pad_inputs_by_prefix() {
+        assert!(is_pad_input("Pad A"));
+        assert!(is_pad_input("pad dpup"));
+        assert!(!is_pad_input("Space"));
+        assert!(!is_pad_input("Pa"));
+        // The prefix length falls inside a multi-byte character
+        assert!(!is_pad_input("Pä…"));
+        assert!(!is_pad_input("Paé"));
+    }
+
+    #[test]
+    fn parse_bindings() {
+        assert_eq!(parse_binding("5 = Space, Pad A").unwrap(), (KeyAction::Key(5), vec!["Space".to_string(), "Pad A".to_string()]));
+        assert_eq!(parse_binding("turbo=T").unwrap(), (KeyAction::Turbo, vec!["T".to_string()]));
+        assert_eq!(parse_binding("G=Space").unwrap_err(), "'G' is not a Chip 8 key, expected 0 - F or turbo");
+        assert_eq!(parse_binding("é=Space").unwrap_err(), "'é' is not a Chip 8 key, expected 0 - F or turbo");
+        assert_eq!(parse_binding("5=").unwrap_err(), "no host key given in '5='");
+        assert!(parse_binding("Space").is_err());
+    }
+
+    #[test]
+    fn keyboard_and_pad_bindings_are_replaced_separately() {
+        let mut keymap = build_default_keymap();
+        bind_keys(&mut keymap, KeyAction::Key(5), &["Pad Y".to_string()]);
+        assert_eq!(keymap_action(&keymap, "w"), Some(KeyAction::Key(5)));
+        assert_eq!(keymap_action(&keymap, "Pad Y"), Some(KeyAction::Key(5)));
+        assert_eq!(keymap_action(&keymap, "Pad A"), None);
+        bind_keys(&mut keymap, KeyAction::Key(5), &["Space".to_string()]);
+        assert_eq!(keymap_action(&keymap, "W"), None);
+        assert_eq!(keymap_action(&keymap, "space"), Some(KeyAction::Key(5)));
+        assert_eq!(keymap_action(&keymap, "Pad Y"), Some(KeyAction::Key(5)));
+    }
+}
@@ -1,25 +1,29 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 #![allow(clippy::single_match)]
 
-use std::{env, thread, time};
-use std::borrow::{Borrow, BorrowMut};
-use std::convert::TryInto;
-use std::thread::sleep;
-use std::time::Duration;
+use std::path::Path;
 
 use clap::Parser;
-use fermium::{
-    *,
-    error::*, events::*, prelude::*, video::*,
-};
-use fermium::prelude::{SDL_Rect, SDL_RenderDrawRectF};
-use fermium::renderer::SDL_RenderDrawRect;
-use rodio::{Decoder, OutputStream, Sink};
-use rodio::source::{SineWave, Source};
-
-use crate::chip8::{KEYBOARD_SIZE, load_program, step};
-use crate::chip8_keyboard_utils::on_keyboard_event;
-use crate::debug_utils::{print_display, print_keyboard, print_memory, print_registers};
+
+use crate::chip8_analysis::{analyze_program, cfg_to_dot, cfg_to_json, print_analysis_warnings};
+use crate::chip8_decompiler::decompile;
+use crate::chip8_symbols::{build_symbol_table, load_symbols};
+use crate::chip8_debugger::build_chip8_debugger;
+use crate::chip8_quirks::{QUIRK_PRESETS, quirks_preset};
+use crate::chip8_runner::{Chip8Runner, build_chip8_runner, load_state, open_browser, open_file, open_rom, run, start_recording, stop_recording};
+use crate::debug_utils::print_display;
+use crate::palette::{THEME_NAMES, palette_preset, parse_color, parse_palette_colors};
+use crate::phosphor::{PersistenceMode, build_phosphor};
+use crate::keymap::{KeyAction, build_default_keymap, load_keymap_file, parse_binding};
+use crate::rom_browser::DEFAULT_ROM_DIR;
+use crate::rom_file::read_patched_rom;
+use crate::config::load_user_config;
+use crate::chip8_runner::SPEED_LEVELS;
+use crate::rom_database::{load_rom_database, rom_info, user_database_path};
+use crate::osd::build_osd;
+use crate::frontend::{NullFrontend, VideoSink, build_headless_audio, build_headless_video};
+use crate::sdl_frontend::{build_rodio_audio, build_sdl_input, build_sdl_video};
+use crate::tui_frontend::{TuiMode, build_tui_audio, build_tui_input, build_tui_video};
 
 mod chip8;
 mod chip8_display;
@@ -27,121 +31,329 @@ mod chip8_memory;
 mod debug_utils;
 mod chip8_instructions;
 mod chip8_keyboard_utils;
-
-unsafe fn render_chip8_display(renderer: *mut SDL_Renderer, device: &chip8::Chip8) {
-    for i in 0..chip8_display::DISPLAY_HEIGHT {
-        for j in 0..chip8_display::DISPLAY_WIDTH / 8 {
-            for b in 0..8 {
-                let rect = SDL_Rect { x: ((j as i32) * 8 + b) * 10, y: (i as i32) * 10, w: 10, h: 10 };
-                if device.display.display_data[i][j] & (0b10000000 >> b) > 0 {
-                    SDL_SetRenderDrawColor(renderer, 90, 190, 90, 255);
-                    SDL_RenderFillRect(renderer, &rect);
-                }
-                SDL_SetRenderDrawColor(renderer, 14, 48, 68, 255);
-                SDL_RenderDrawRect(renderer, &rect);
-            }
-        }
-    }
-}
+mod chip8_debugger;
+mod chip8_analysis;
+mod chip8_decompiler;
+mod chip8_symbols;
+mod chip8_runner;
+mod chip8_quirks;
+mod frontend;
+mod palette;
+mod image_export;
+mod recorder;
+mod keymap;
+mod phosphor;
+mod osd;
+mod sdl_frontend;
+mod tui_frontend;
+mod rom_browser;
+mod sha1;
+mod toml_parser;
+mod user_dirs;
+mod rom_database;
+mod config;
+mod save_state;
+mod inflate;
+mod zip_archive;
+mod octo_cartridge;
+mod octo_assembler;
+mod rom_file;
+mod rom_patch;
 
 /// Chip 8 Emulator
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-   /// Rom file to load
+   /// Rom file to load (a file, pack.zip:NAME inside a zip archive or an Octo cartridge), the ROM browser opens when none is given
    #[clap(short, long, value_parser)]
-   filename: String,
+   filename: Option<String>,
+
+   /// Directory listed by the ROM browser
+   #[clap(long, value_parser, default_value = DEFAULT_ROM_DIR)]
+   rom_dir: String,
+
+   /// Start the console debugger
+   #[clap(short, long)]
+   debug: bool,
+
+   /// Write the control flow graph of the ROM to this file (.dot or .json) and exit
+   #[clap(long, value_parser)]
+   export_cfg: Option<String>,
+
+   /// Write the ROM decompiled to pseudo-code in this file and exit
+   #[clap(long, value_parser)]
+   decompile: Option<String>,
+
+   /// Symbol file giving label names and data regions to the debugger, the disassembly and the decompiler
+   #[clap(long, value_parser)]
+   symbols: Option<String>,
+
+   /// Run this number of frames without window nor sound, then print the display and exit
+   #[clap(long, value_parser)]
+   headless: Option<u64>,
+
+   /// Play in the terminal instead of a window, drawing with half blocks or braille characters
+   #[clap(long, value_parser = ["half", "braille"], conflicts_with = "debug")]
+   tui: Option<String>,
+
+   /// Hide the grid drawn between the pixels
+   #[clap(long)]
+   no_grid: bool,
+
+   /// Colour theme of the window, classic by default
+   #[clap(long, value_parser = THEME_NAMES)]
+   theme: Option<String>,
+
+   /// Colour of the lit pixels, as #RRGGBB
+   #[clap(long, value_parser = parse_color)]
+   fg: Option<u32>,
+
+   /// Colour of the unlit pixels, as #RRGGBB
+   #[clap(long, value_parser = parse_color)]
+   bg: Option<u32>,
+
+   /// Colour of the grid, as #RRGGBB
+   #[clap(long, value_parser = parse_color)]
+   grid_color: Option<u32>,
+
+   /// Full palette for multi-plane displays, 4 or 16 comma separated #RRGGBB colours
+   #[clap(long, value_parser)]
+   palette: Option<String>,
+
+   /// Keep turned off pixels visible while they fade out over this number of frames, against flicker
+   #[clap(long, value_parser)]
+   persistence: Option<u32>,
+
+   /// Show the pixels lit in the current or in the previous frame, against flicker
+   #[clap(long, conflicts_with = "persistence")]
+   blend_frames: bool,
+
+   /// Save the display to this PNG or PPM file when the emulator stops
+   #[clap(long, value_parser)]
+   screenshot: Option<String>,
+
+   /// Record the gameplay from the start, to an animated GIF (.gif) or to a directory of PPM frames, with a WAV file
+   #[clap(long, value_parser)]
+   record: Option<String>,
+
+   /// Size of the pixels of screenshots and recordings, 1 for the native 64x32 resolution
+   #[clap(long, value_parser, default_value_t = 1)]
+   screenshot_scale: usize,
+
+   /// Keymap file, one `<key> = <host key>, ...` binding per line (key is 0 - F or turbo)
+   #[clap(long, value_parser)]
+   keymap: Option<String>,
+
+   /// Bind a Chip 8 key to host keys, as `<key>=<host key>,...` (repeatable, e.g. --key 5=Space --key turbo=T)
+   #[clap(long = "key", value_parser = parse_binding)]
+   keys: Vec<(KeyAction, Vec<String>)>,
+
+   /// Window pixels per Chip 8 pixel, 10 by default
+   #[clap(long, value_parser)]
+   scale: Option<i32>,
+
+   /// Start in fullscreen
+   #[clap(long)]
+   fullscreen: bool,
+
+   /// Only scale the display by whole numbers, for sharp and even pixels
+   #[clap(long)]
+   integer_scale: bool,
+
+   /// Behaviour of the instructions that differ between interpreters : default, COSMAC VIP or SUPER-CHIP (replaces the ROM database)
+   #[clap(long, value_parser = QUIRK_PRESETS)]
+   quirks: Option<String>,
+
+   /// Configuration file to use instead of config.toml in the configuration directory
+   #[clap(long, value_parser)]
+   config: Option<String>,
+
+   /// ROM database to use besides the bundled one and roms.toml in the configuration directory
+   #[clap(long, value_parser)]
+   rom_db: Option<String>,
+
+   /// Show the frame rate and the instructions per second over the display
+   #[clap(long)]
+   osd_stats: bool,
+
+   /// Reload the ROM when its file changes, keeping the keymap and the breakpoints
+   #[clap(long)]
+   watch: bool,
+
+   /// Save state to restore at start and after each reload, with the new program copied over the saved one
+   #[clap(long, value_parser, requires = "watch")]
+   watch_state: Option<String>,
+
+   /// IPS or BPS patch applied to the ROM given with --filename (repeatable, applied in order)
+   #[clap(long = "patch", value_parser, requires = "filename")]
+   patches: Vec<String>,
 }
 
 fn main() {
     let args = Args::parse();
 
-    let mut device = chip8::build_chip8();
-    load_program(device.borrow_mut(), &args.filename);
-
-    let mut window;
-    let mut renderer;
-    let mut event = SDL_Event::default();
-
-    let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-    let sink = Sink::try_new(&stream_handle).unwrap();
-    let source = SineWave::new(500.0).amplify(0.20);
-    sink.append(source);
-
-    unsafe {
-        assert_eq!(SDL_Init(SDL_INIT_EVERYTHING), 0);
-        window = SDL_CreateWindow(
-            b"Khopa's Rusty Chip 8 Emulator\0".as_ptr().cast(),
-            SDL_WINDOWPOS_CENTERED,
-            SDL_WINDOWPOS_CENTERED,
-            (chip8_display::DISPLAY_WIDTH * 10).try_into().unwrap(),
-            (chip8_display::DISPLAY_HEIGHT * 10).try_into().unwrap(),
-            (SDL_WINDOW_OPENGL | SDL_WINDOW_ALLOW_HIGHDPI).0,
-        );
-        // Panic if window is not null
-        assert!(!window.is_null());
-
-        renderer = SDL_CreateRenderer(window, -1, 1);
-        // Panic if renderer is not null
-        assert!(!renderer.is_null());
-        SDL_SetRenderDrawColor(renderer, 0, 0, 255, 255);
+    let symbols = match &args.symbols {
+        Some(path) => load_symbols(path).unwrap_or_else(|e| exit_with_error(e)),
+        None => build_symbol_table(),
+    };
+
+    if let Some(path) = &args.export_cfg {
+        let rom = read_program(&args, "analyze");
+        let graph = analyze_program(&rom);
+        print_analysis_warnings(&graph, &symbols);
+        let output = if path.ends_with(".json") { cfg_to_json(&graph, &symbols) } else { cfg_to_dot(&graph, &symbols) };
+        std::fs::write(path, output).unwrap_or_else(|e| exit_with_error(format!("cannot write the control flow graph to {} ({})", path, e)));
+        return;
     }
 
-    loop {
-
-        let mut should_exit = false;
-
-        unsafe {
-            while SDL_PollEvent(&mut event) > 0 {
-                match event.type_ {
-                    SDL_QUIT => {
-                        should_exit = true;
-                    }
-                    SDL_KEYUP => {
-                        on_keyboard_event(&mut device, event.key.keysym.scancode, false);
-                    }
-                    SDL_KEYDOWN => {
-                        on_keyboard_event(&mut device, event.key.keysym.scancode, true);
-                    }
-                    _ => (),
-                }
-            }
-        }
+    if let Some(path) = &args.decompile {
+        let rom = read_program(&args, "decompile");
+        let graph = analyze_program(&rom);
+        print_analysis_warnings(&graph, &symbols);
+        std::fs::write(path, decompile(&graph, &symbols)).unwrap_or_else(|e| exit_with_error(format!("cannot write the decompiled program to {} ({})", path, e)));
+        return;
+    }
 
-        if(should_exit){
-            break;
-        }
+    // Settings of the configuration file, the command line options win over them
+    let config = load_user_config(args.config.as_deref()).unwrap_or_else(|e| exit_with_error(e));
 
-        unsafe {
-            SDL_SetRenderDrawColor(renderer, 7, 38, 54, 255);
-            SDL_RenderClear(renderer);
-            render_chip8_display(renderer, &device);
-            SDL_RenderPresent(renderer);
-            // runs ~540 ops/s (540hz cpu speed)
-            SDL_Delay(25); // 40 FPS cap
-        }
+    let debugger = if args.debug { Some(build_chip8_debugger(symbols)) } else { None };
+    let mut runner = build_chip8_runner(chip8::build_chip8(), Vec::new(), debugger, "");
+    runner.export_scale = args.screenshot_scale;
+    runner.rom_dir = args.rom_dir.clone();
+    runner.defaults = config.settings.clone();
+    runner.rom_sections = config.roms.clone();
+    runner.turbo_steps_per_frame = config.turbo_instructions_per_frame;
+    runner.speed_level = SPEED_LEVELS.iter().position(|speed| *speed == config.speed).unwrap();
+    runner.tone = config.tone;
+    runner.volume = config.volume;
 
-        let mut speed = 14;
-        if device.turbo{
-            speed = 140;
-        }
-        for b in 0..speed {
-            step(device.borrow_mut());
-            //print_registers(&device);
-        }
+    // Bundled database, then the user database when there is one and the one given on the command line
+    let user_database = user_database_path().filter(|path| Path::new(path).is_file());
+    for path in user_database.iter().chain(args.rom_db.iter()) {
+        load_rom_database(&mut runner.database, path).unwrap_or_else(|e| exit_with_error(e));
+    }
+
+    // Command line options win over the settings of the configuration and of the ROM database
+    runner.overrides.quirks = args.quirks.as_ref().map(|name| quirks_preset(name).unwrap());
+    runner.overrides.keys = args.keys.clone();
+    runner.overrides.fg = args.fg;
+    runner.overrides.bg = args.bg;
+
+    // Default layout and the keymap file, the keymap of the ROM and the command line bindings are applied when it is loaded
+    let mut keymap = build_default_keymap();
+    if let Some(path) = args.keymap.as_ref().or(config.keymap.as_ref()) {
+        load_keymap_file(&mut keymap, path).unwrap_or_else(|e| exit_with_error(e));
+    }
+    runner.keymap = keymap.clone();
+
+    let persistence_frames = if args.blend_frames { None } else { Some(args.persistence.unwrap_or(config.persistence)) };
+    let persistence = match persistence_frames {
+        Some(frames) if frames > 0 => PersistenceMode::Decay(frames),
+        _ if args.blend_frames || config.blend_frames => PersistenceMode::Blend,
+        _ => PersistenceMode::Off,
+    };
+
+    let mut palette = palette_preset(args.theme.as_ref().unwrap_or(&config.theme)).unwrap();
+    if let Some(colors) = &args.palette {
+        palette.colors = parse_palette_colors(colors).unwrap_or_else(|e| exit_with_error(e));
+    }
+    if let Some(color) = args.grid_color.or(config.grid_color) {
+        palette.grid = color;
+    }
+    runner.theme = palette;
+    runner.persistence = persistence;
+    runner.watch = args.watch;
+    runner.watch_state = args.watch_state.clone();
+    runner.patches = args.patches.clone();
+    runner.patched_path = args.filename.clone().unwrap_or_default();
+
+    match &args.filename {
+        Some(filename) if args.headless.is_some() => open_rom(&mut runner, filename).unwrap_or_else(|e| exit_with_error(e)),
+        // An archive holding several ROMs opens the ROM browser on them
+        Some(filename) => { open_file(&mut runner, filename).unwrap_or_else(|e| exit_with_error(e)); }
+        None if args.headless.is_some() => exit_with_error("give the ROM to run with --filename".to_string()),
+        None => open_browser(&mut runner).unwrap_or_else(|e| exit_with_error(e)),
+    }
+    if let (Some(path), Some(_)) = (&args.watch_state, &args.filename) {
+        load_state(&mut runner, path, true).unwrap_or_else(|e| exit_with_error(e));
+    }
+    if let Some(info) = rom_info(&runner.database, &runner.rom_hash) {
+        println!("{} - {} ({})", runner.rom_title, info.platform.as_deref().unwrap_or("unknown platform"), runner.rom_hash);
+    }
+    if let Some(path) = &args.record {
+        println!("{}", start_recording(&mut runner, path));
+    }
 
-        if device.st > 0 {
-            sink.play();
-        }else{
-            sink.pause();
+    if let Some(frames) = args.headless {
+        let mut video = build_headless_video(build_phosphor(persistence), runner.palette.clone());
+        let mut audio = build_headless_audio();
+        run(&mut runner, &mut video, &mut audio, &mut NullFrontend, Some(frames));
+        for message in finish_run(&args, &mut runner, &mut video) {
+            println!("{}", message);
         }
+        print_display(&runner.device);
+        println!("{} frames, beeper active during {} of them", video.frames, audio.beeps.iter().filter(|b| **b).count());
+        return;
+    }
 
-        device.key = KEYBOARD_SIZE + 1;
+    if let Some(mode) = &args.tui {
+        let mode = if mode == "braille" { TuiMode::Braille } else { TuiMode::HalfBlock };
+        let mut video = build_tui_video(mode).unwrap_or_else(|e| exit_with_error(e));
+        let mut input = build_tui_input(keymap);
+        let mut audio = build_tui_audio(config.volume == 0.0);
+        run(&mut runner, &mut video, &mut audio, &mut input, None);
+        let messages = finish_run(&args, &mut runner, &mut video);
+        // The terminal is given back before writing to it
+        drop(video);
+        for message in messages {
+            println!("{}", message);
+        }
+        return;
+    }
 
+    let grid = !args.no_grid && config.grid;
+    let scale = args.scale.unwrap_or(config.scale);
+    let fullscreen = args.fullscreen || config.fullscreen;
+    let integer_scale = args.integer_scale || config.integer_scale;
+    let mut video = build_sdl_video(runner.palette.clone(), build_phosphor(persistence), grid, scale, fullscreen, integer_scale, build_osd(args.osd_stats || config.osd_stats));
+    let mut input = build_sdl_input(keymap);
+    let mut audio = build_rodio_audio(config.tone, config.volume);
+    run(&mut runner, &mut video, &mut audio, &mut input, None);
+    for message in finish_run(&args, &mut runner, &mut video) {
+        println!("{}", message);
     }
+}
+
+/**
+ * Report an error of the command line or of a file it names, and stop
+ */
+fn exit_with_error(message: String) -> ! {
+    eprintln!("Error : {}", message);
+    std::process::exit(1);
+}
+
+/**
+ * Program given with --filename, read and patched as the emulator loads it
+ */
+#[allow(clippy::needless_return)]
+fn read_program(args: &Args, action: &str) -> Vec<u8> {
+    let filename = args.filename.as_ref().unwrap_or_else(|| exit_with_error(format!("give the ROM to {} with --filename", action)));
+    return read_patched_rom(filename, &args.patches).unwrap_or_else(|e| exit_with_error(e)).bytes;
+}
 
-    unsafe{
-        SDL_DestroyWindow(window);
-        SDL_Quit();
+/**
+ * Save the recording in progress, and the last frame when a screenshot was asked on the command line
+ *
+ * Returns the messages to show once the frontend is closed.
+ */
+#[allow(clippy::needless_return)]
+fn finish_run(args: &Args, runner: &mut Chip8Runner, video: &mut dyn VideoSink) -> Vec<String> {
+    let mut messages: Vec<String> = stop_recording(runner).into_iter().collect();
+    if let Some(path) = &args.screenshot {
+        if let Err(e) = video.screenshot(path, args.screenshot_scale) {
+            messages.push(format!("Error : {}", e));
+        }
     }
+    return messages;
 }
@@ -0,0 +1,936 @@
+/*!
+ * Assembler for the Octo language, used to run the programs carried by Octo cartridges
+ *
+ * Supported : labels, `:const`, `:alias`, `:calc`, `:byte`, `:org`, `:call`, `:next`, `:unpack`, `:macro`, `:assert`,
+ * the Chip 8 statements, `if ... then`, `if ... begin ... else ... end` and `loop ... while ... again`.
+ * Not supported : the SUPER-CHIP and XO-CHIP statements, `:stringmode` and `:pointer`.
+ */
+
+#![allow(clippy::needless_return)]
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::chip8_memory::START_PRG;
+
+// Size of the memory the program is assembled in
+const MEMORY_SIZE: usize = 0x1000;
+
+// Macro expansions allowed in a program, more means a macro expands itself forever
+const MAX_EXPANSIONS: usize = 100000;
+
+// Statements of the SUPER-CHIP and XO-CHIP extensions, which this emulator does not run
+const EXTENSION_WORDS: [&str; 14] = [
+    "hires", "lores", "scroll-down", "scroll-up", "scroll-left", "scroll-right", "exit",
+    "saveflags", "loadflags", "plane", "audio", "pitch", "bighex", "long",
+];
+
+struct Token {
+    text: String,
+    line: usize,
+}
+
+/**
+ * Place in the program of an address or a byte given by a name defined further down
+ */
+enum FixupKind {
+    // The 12 low bits of an instruction
+    Address,
+    // The byte loaded by `:unpack` in v0, a nibble then the 4 high bits of the address
+    UnpackHigh(u8),
+    // The byte loaded by `:unpack` in v1, the 8 low bits of the address
+    UnpackLow,
+}
+
+struct Fixup {
+    kind: FixupKind,
+    address: usize,
+    name: String,
+    line: usize,
+}
+
+/**
+ * Jumps written for `if ... begin` and `loop`, completed once the end of the block is known
+ */
+struct Branch {
+    jump: usize,
+    line: usize,
+    has_else: bool,
+}
+
+struct Loop {
+    start: usize,
+    line: usize,
+    breaks: Vec<usize>,
+}
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    // Line of the last token read, for the error messages
+    line: usize,
+    memory: Vec<u8>,
+    here: usize,
+    // End of the highest byte written
+    end: usize,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, (Vec<String>, Vec<Token>)>,
+    expansions: usize,
+    fixups: Vec<Fixup>,
+    branches: Vec<Branch>,
+    loops: Vec<Loop>,
+    // A jump to main is written at the start, unless main comes first
+    main_jump: bool,
+}
+
+/**
+ * Assemble Octo source into the bytes of a Chip 8 program
+ */
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    let mut asm = Assembler {
+        tokens: tokenize(source)?,
+        line: 1,
+        memory: vec![0; MEMORY_SIZE],
+        here: START_PRG,
+        end: START_PRG,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        expansions: 0,
+        fixups: Vec::new(),
+        branches: Vec::new(),
+        loops: Vec::new(),
+        main_jump: true,
+    };
+    emit_op(&mut asm, 0x1000)?;
+    while let Some(token) = asm.tokens.pop_front() {
+        asm.line = token.line;
+        statement(&mut asm, token)?;
+    }
+
+    if let Some(branch) = asm.branches.last() {
+        return Err(format!("line {} : 'begin' without 'end'", branch.line));
+    }
+    if let Some(open) = asm.loops.last() {
+        return Err(format!("line {} : 'loop' without 'again'", open.line));
+    }
+    let main = *asm.labels.get("main").ok_or("the program has no ': main' label")?;
+    if asm.main_jump {
+        patch_address(&mut asm, START_PRG, main);
+    }
+    for fixup in std::mem::take(&mut asm.fixups) {
+        let target = resolve(&asm, &fixup.name).ok_or_else(|| format!("line {} : undefined name '{}'", fixup.line, fixup.name))? as usize;
+        match fixup.kind {
+            FixupKind::Address => patch_address(&mut asm, fixup.address, target),
+            FixupKind::UnpackHigh(nibble) => asm.memory[fixup.address] = (nibble << 4) | ((target >> 8) & 0xF) as u8,
+            FixupKind::UnpackLow => asm.memory[fixup.address] = target as u8,
+        }
+    }
+    return Ok(asm.memory[START_PRG..asm.end].to_vec());
+}
+
+/**
+ * Split the source in words, `#` starts a comment up to the end of the line and strings keep their spaces
+ */
+fn tokenize(source: &str) -> Result<VecDeque<Token>, String> {
+    let mut tokens = VecDeque::new();
+    let mut chars = source.chars().peekable();
+    let mut line = 1;
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            if c == '\n' {
+                line += 1;
+            }
+            chars.next();
+        } else if c == '#' {
+            while matches!(chars.peek(), Some(c) if *c != '\n') {
+                chars.next();
+            }
+        } else if c == '"' {
+            let mut text = String::new();
+            text.push(chars.next().unwrap());
+            loop {
+                match chars.next() {
+                    None | Some('\n') => return Err(format!("line {} : unterminated string", line)),
+                    Some('"') => break,
+                    Some(c) => text.push(c),
+                }
+            }
+            tokens.push_back(Token { text, line });
+        } else {
+            let mut text = String::new();
+            while matches!(chars.peek(), Some(c) if !c.is_whitespace()) {
+                text.push(chars.next().unwrap());
+            }
+            tokens.push_back(Token { text, line });
+        }
+    }
+    return Ok(tokens);
+}
+
+fn error(asm: &Assembler, message: &str) -> String {
+    return format!("line {} : {}", asm.line, message);
+}
+
+fn next_token(asm: &mut Assembler) -> Result<Token, String> {
+    let token = asm.tokens.pop_front().ok_or_else(|| error(asm, "unexpected end of the program"))?;
+    asm.line = token.line;
+    return Ok(token);
+}
+
+fn expect(asm: &mut Assembler, expected: &str) -> Result<(), String> {
+    let token = next_token(asm)?;
+    if token.text != expected {
+        return Err(error(asm, &format!("expected '{}', found '{}'", expected, token.text)));
+    }
+    return Ok(());
+}
+
+fn peek_is(asm: &Assembler, text: &str) -> bool {
+    return asm.tokens.front().map(|token| token.text == text).unwrap_or(false);
+}
+
+fn emit(asm: &mut Assembler, byte: u8) -> Result<(), String> {
+    if asm.here >= MEMORY_SIZE {
+        return Err(error(asm, "the program does not fit in memory"));
+    }
+    asm.memory[asm.here] = byte;
+    asm.here += 1;
+    asm.end = asm.end.max(asm.here);
+    return Ok(());
+}
+
+fn emit_op(asm: &mut Assembler, op: u16) -> Result<(), String> {
+    emit(asm, (op >> 8) as u8)?;
+    return emit(asm, op as u8);
+}
+
+fn patch_address(asm: &mut Assembler, at: usize, target: usize) {
+    asm.memory[at] = (asm.memory[at] & 0xF0) | ((target >> 8) & 0xF) as u8;
+    asm.memory[at + 1] = target as u8;
+}
+
+fn check_extension(asm: &Assembler, word: &str) -> Result<(), String> {
+    if EXTENSION_WORDS.contains(&word) {
+        return Err(error(asm, &format!("'{}' is a SUPER-CHIP or XO-CHIP statement, only Chip 8 programs can run", word)));
+    }
+    return Ok(());
+}
+
+/**
+ * Number written in decimal, 0x hexadecimal or 0b binary, possibly negative
+ */
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i64::from_str_radix(binary, 2).ok()?
+    } else {
+        digits.parse::<i64>().ok()?
+    };
+    return Some(if negative { -value } else { value });
+}
+
+/**
+ * Value of a name : a label, a constant or a number
+ */
+fn resolve(asm: &Assembler, name: &str) -> Option<i64> {
+    if let Some(address) = asm.labels.get(name) {
+        return Some(*address as i64);
+    }
+    if let Some(value) = asm.constants.get(name) {
+        return Some(value.floor() as i64);
+    }
+    return parse_number(name);
+}
+
+fn register_index(asm: &Assembler, text: &str) -> Option<u8> {
+    let bytes = text.as_bytes();
+    if bytes.len() == 2 && (bytes[0] == b'v' || bytes[0] == b'V') {
+        return (bytes[1] as char).to_digit(16).map(|index| index as u8);
+    }
+    return asm.aliases.get(text).copied();
+}
+
+fn register(asm: &mut Assembler) -> Result<u8, String> {
+    let token = next_token(asm)?;
+    return register_index(asm, &token.text).ok_or_else(|| error(asm, &format!("expected a register, found '{}'", token.text)));
+}
+
+/**
+ * Value known at this point of the program : a number, a name already defined or a `{ ... }` expression
+ */
+fn value(asm: &mut Assembler) -> Result<i64, String> {
+    let token = next_token(asm)?;
+    if token.text == "{" {
+        let value = calc_expression(asm)?;
+        expect(asm, "}")?;
+        return Ok(value.floor() as i64);
+    }
+    return resolve(asm, &token.text).ok_or_else(|| error(asm, &format!("undefined name '{}'", token.text)));
+}
+
+fn byte_value(asm: &mut Assembler) -> Result<u8, String> {
+    let value = value(asm)?;
+    if !(-128..=255).contains(&value) {
+        return Err(error(asm, &format!("{} does not fit in a byte", value)));
+    }
+    return Ok(value as u8);
+}
+
+/**
+ * Operand of an instruction taking an address, which can be a label defined further down
+ */
+fn emit_address_op(asm: &mut Assembler, op: u16) -> Result<(), String> {
+    let token = next_token(asm)?;
+    let target = if token.text == "{" {
+        let value = calc_expression(asm)?;
+        expect(asm, "}")?;
+        Some(value.floor() as i64)
+    } else {
+        resolve(asm, &token.text)
+    };
+    match target {
+        Some(target) if !(0..MEMORY_SIZE as i64).contains(&target) => return Err(error(asm, &format!("address {} is out of memory", target))),
+        Some(target) => emit_op(asm, op | target as u16)?,
+        None => {
+            check_extension(asm, &token.text)?;
+            asm.fixups.push(Fixup { kind: FixupKind::Address, address: asm.here, name: token.text, line: token.line });
+            emit_op(asm, op)?;
+        }
+    }
+    return Ok(());
+}
+
+fn define_label(asm: &mut Assembler, name: String, address: usize) -> Result<(), String> {
+    if asm.labels.contains_key(&name) {
+        return Err(error(asm, &format!("the label '{}' is defined twice", name)));
+    }
+    if register_index(asm, &name).is_some() || parse_number(&name).is_some() {
+        return Err(error(asm, &format!("'{}' cannot be a label", name)));
+    }
+    asm.labels.insert(name, address);
+    return Ok(());
+}
+
+fn statement(asm: &mut Assembler, token: Token) -> Result<(), String> {
+    let text = token.text.as_str();
+    if let Some(x) = register_index(asm, text) {
+        return register_statement(asm, x);
+    }
+    match text {
+        ":" => {
+            let name = next_token(asm)?.text;
+            // main given first needs no jump to it
+            if name == "main" && asm.here == START_PRG + 2 && asm.labels.is_empty() {
+                asm.here = START_PRG;
+                asm.end = START_PRG;
+                asm.main_jump = false;
+            }
+            let here = asm.here;
+            define_label(asm, name, here)?;
+        }
+        ":next" => {
+            let name = next_token(asm)?.text;
+            let here = asm.here;
+            define_label(asm, name, here + 1)?;
+        }
+        ":const" => {
+            let name = next_token(asm)?.text;
+            let value = value(asm)?;
+            asm.constants.insert(name, value as f64);
+        }
+        ":calc" => {
+            let name = next_token(asm)?.text;
+            expect(asm, "{")?;
+            let value = calc_expression(asm)?;
+            expect(asm, "}")?;
+            asm.constants.insert(name, value);
+        }
+        ":alias" => {
+            let name = next_token(asm)?.text;
+            let index = register(asm)?;
+            asm.aliases.insert(name, index);
+        }
+        ":byte" => {
+            let byte = byte_value(asm)?;
+            emit(asm, byte)?;
+        }
+        ":org" => {
+            let address = value(asm)?;
+            if !(0..MEMORY_SIZE as i64).contains(&address) {
+                return Err(error(asm, &format!("address {} is out of memory", address)));
+            }
+            asm.here = address as usize;
+        }
+        ":call" => emit_address_op(asm, 0x2000)?,
+        ":unpack" => {
+            let nibble = value(asm)?;
+            if !(0..=15).contains(&nibble) {
+                return Err(error(asm, "the value given to :unpack must fit in 4 bits"));
+            }
+            let label = next_token(asm)?;
+            match resolve(asm, &label.text) {
+                Some(address) => {
+                    emit_op(asm, 0x6000 | ((nibble as u16) << 4) | ((address as u16 >> 8) & 0xF))?;
+                    emit_op(asm, 0x6100 | (address as u16 & 0xFF))?;
+                }
+                None => {
+                    let here = asm.here;
+                    asm.fixups.push(Fixup { kind: FixupKind::UnpackHigh(nibble as u8), address: here + 1, name: label.text.clone(), line: label.line });
+                    asm.fixups.push(Fixup { kind: FixupKind::UnpackLow, address: here + 3, name: label.text, line: label.line });
+                    emit_op(asm, 0x6000)?;
+                    emit_op(asm, 0x6100)?;
+                }
+            }
+        }
+        ":macro" => define_macro(asm)?,
+        ":assert" => {
+            let message = if asm.tokens.front().map(|token| token.text.starts_with('"')).unwrap_or(false) {
+                next_token(asm)?.text[1..].to_string()
+            } else {
+                "assertion failed".to_string()
+            };
+            expect(asm, "{")?;
+            let value = calc_expression(asm)?;
+            expect(asm, "}")?;
+            if value == 0.0 {
+                return Err(error(asm, &message));
+            }
+        }
+        ":breakpoint" => { next_token(asm)?; }
+        ":monitor" => {
+            next_token(asm)?;
+            next_token(asm)?;
+        }
+        ";" | "return" => emit_op(asm, 0x00EE)?,
+        "clear" => emit_op(asm, 0x00E0)?,
+        "bcd" => {
+            let x = register(asm)? as u16;
+            emit_op(asm, 0xF033 | (x << 8))?;
+        }
+        "save" | "load" => {
+            let x = register(asm)? as u16;
+            if peek_is(asm, "-") {
+                return Err(error(asm, "saving and loading a range of registers is an XO-CHIP statement, only Chip 8 programs can run"));
+            }
+            emit_op(asm, if text == "save" { 0xF055 } else { 0xF065 } | (x << 8))?;
+        }
+        "sprite" => {
+            let x = register(asm)? as u16;
+            let y = register(asm)? as u16;
+            let height = value(asm)?;
+            if !(0..=15).contains(&height) {
+                return Err(error(asm, "sprites are at most 15 lines high"));
+            }
+            emit_op(asm, 0xD000 | (x << 8) | (y << 4) | height as u16)?;
+        }
+        "jump" => emit_address_op(asm, 0x1000)?,
+        "jump0" => emit_address_op(asm, 0xB000)?,
+        "native" => emit_address_op(asm, 0x0000)?,
+        "delay" | "buzzer" => {
+            expect(asm, ":=")?;
+            let x = register(asm)? as u16;
+            emit_op(asm, if text == "delay" { 0xF015 } else { 0xF018 } | (x << 8))?;
+        }
+        "i" => {
+            let operator = next_token(asm)?.text;
+            match operator.as_str() {
+                ":=" if peek_is(asm, "hex") => {
+                    next_token(asm)?;
+                    let x = register(asm)? as u16;
+                    emit_op(asm, 0xF029 | (x << 8))?;
+                }
+                ":=" => {
+                    if let Some(word) = asm.tokens.front().map(|token| token.text.clone()) {
+                        check_extension(asm, &word)?;
+                    }
+                    emit_address_op(asm, 0xA000)?;
+                }
+                "+=" => {
+                    let x = register(asm)? as u16;
+                    emit_op(asm, 0xF01E | (x << 8))?;
+                }
+                _ => return Err(error(asm, &format!("unknown operator 'i {}'", operator))),
+            }
+        }
+        "if" => {
+            let condition = condition(asm)?;
+            let keyword = next_token(asm)?.text;
+            match keyword.as_str() {
+                "then" => emit_skip(asm, &condition, false)?,
+                "begin" => {
+                    emit_skip(asm, &condition, true)?;
+                    asm.branches.push(Branch { jump: asm.here, line: asm.line, has_else: false });
+                    emit_op(asm, 0x1000)?;
+                }
+                _ => return Err(error(asm, &format!("expected 'then' or 'begin', found '{}'", keyword))),
+            }
+        }
+        "else" => {
+            let branch = match asm.branches.pop() {
+                Some(branch) if !branch.has_else => branch,
+                _ => return Err(error(asm, "'else' without 'if ... begin'")),
+            };
+            let jump = asm.here;
+            emit_op(asm, 0x1000)?;
+            let here = asm.here;
+            patch_address(asm, branch.jump, here);
+            asm.branches.push(Branch { jump, line: asm.line, has_else: true });
+        }
+        "end" => {
+            let branch = asm.branches.pop().ok_or_else(|| error(asm, "'end' without 'if ... begin'"))?;
+            let here = asm.here;
+            patch_address(asm, branch.jump, here);
+        }
+        "loop" => asm.loops.push(Loop { start: asm.here, line: asm.line, breaks: Vec::new() }),
+        "while" => {
+            if asm.loops.is_empty() {
+                return Err(error(asm, "'while' outside of a loop"));
+            }
+            let condition = condition(asm)?;
+            emit_skip(asm, &condition, true)?;
+            let here = asm.here;
+            asm.loops.last_mut().unwrap().breaks.push(here);
+            emit_op(asm, 0x1000)?;
+        }
+        "again" => {
+            let open = asm.loops.pop().ok_or_else(|| error(asm, "'again' without 'loop'"))?;
+            emit_op(asm, 0x1000 | open.start as u16)?;
+            let here = asm.here;
+            for jump in open.breaks {
+                patch_address(asm, jump, here);
+            }
+        }
+        "{" => {
+            let value = calc_expression(asm)?;
+            expect(asm, "}")?;
+            emit(asm, value.floor() as i64 as u8)?;
+        }
+        _ if asm.macros.contains_key(text) => expand_macro(asm, text)?,
+        _ if text.starts_with(':') => return Err(error(asm, &format!("'{}' is not supported", text))),
+        _ => {
+            check_extension(asm, text)?;
+            match resolve(asm, text) {
+                // Numbers and constants on their own are data
+                Some(value) if !asm.labels.contains_key(text) => emit(asm, value as u8)?,
+                // A label on its own calls the subroutine
+                _ => {
+                    asm.tokens.push_front(token);
+                    emit_address_op(asm, 0x2000)?;
+                }
+            }
+        }
+    }
+    return Ok(());
+}
+
+/**
+ * Statements starting with a register : assignments and arithmetic
+ */
+fn register_statement(asm: &mut Assembler, x: u8) -> Result<(), String> {
+    let x = x as u16;
+    let operator = next_token(asm)?.text;
+    let source = asm.tokens.front().map(|token| token.text.clone()).unwrap_or_default();
+    let y = register_index(asm, &source).map(|y| y as u16);
+    let logic = match operator.as_str() {
+        ":=" => 0x0,
+        "|=" => 0x1,
+        "&=" => 0x2,
+        "^=" => 0x3,
+        "+=" => 0x4,
+        "-=" => 0x5,
+        ">>=" => 0x6,
+        "=-" => 0x7,
+        "<<=" => 0xE,
+        _ => return Err(error(asm, &format!("unknown operator '{}'", operator))),
+    };
+    if let Some(y) = y {
+        next_token(asm)?;
+        return emit_op(asm, 0x8000 | (x << 8) | (y << 4) | logic);
+    }
+    match (operator.as_str(), source.as_str()) {
+        (":=", "random") => {
+            next_token(asm)?;
+            let mask = byte_value(asm)? as u16;
+            emit_op(asm, 0xC000 | (x << 8) | mask)?;
+        }
+        (":=", "key") => {
+            next_token(asm)?;
+            emit_op(asm, 0xF00A | (x << 8))?;
+        }
+        (":=", "delay") => {
+            next_token(asm)?;
+            emit_op(asm, 0xF007 | (x << 8))?;
+        }
+        (":=", _) => {
+            let byte = byte_value(asm)? as u16;
+            emit_op(asm, 0x6000 | (x << 8) | byte)?;
+        }
+        ("+=", _) => {
+            let byte = byte_value(asm)? as u16;
+            emit_op(asm, 0x7000 | (x << 8) | byte)?;
+        }
+        // Subtracting a number adds its opposite
+        ("-=", _) => {
+            let byte = byte_value(asm)?.wrapping_neg() as u16;
+            emit_op(asm, 0x7000 | (x << 8) | byte)?;
+        }
+        _ => return Err(error(asm, &format!("'{}' needs a register, found '{}'", operator, source))),
+    }
+    return Ok(());
+}
+
+/**
+ * Condition of `if` and `while`
+ */
+enum Condition {
+    // Register, comparison operator, and the register or number compared with
+    Compare(u16, String, Result<u16, u8>),
+    // Register holding a key, and whether the condition is that it is pressed
+    Key(u16, bool),
+}
+
+fn condition(asm: &mut Assembler) -> Result<Condition, String> {
+    let x = register(asm)? as u16;
+    let operator = next_token(asm)?.text;
+    match operator.as_str() {
+        "key" => return Ok(Condition::Key(x, true)),
+        "-key" => return Ok(Condition::Key(x, false)),
+        "==" | "!=" | "<" | ">" | "<=" | ">=" => (),
+        _ => return Err(error(asm, &format!("unknown comparison '{}'", operator))),
+    }
+    let source = asm.tokens.front().map(|token| token.text.clone()).unwrap_or_default();
+    let operand = match register_index(asm, &source) {
+        Some(y) => {
+            next_token(asm)?;
+            Ok(y as u16)
+        }
+        None => Err(byte_value(asm)?),
+    };
+    return Ok(Condition::Compare(x, operator, operand));
+}
+
+/**
+ * Write the instructions skipping the next one when the condition is false, or when it is true if negated
+ *
+ * Ordering comparisons go through VF : it takes the right side, the left side is subtracted, and the borrow flag is tested.
+ */
+fn emit_skip(asm: &mut Assembler, condition: &Condition, negate: bool) -> Result<(), String> {
+    let (x, operator, operand) = match condition {
+        Condition::Key(x, pressed) => {
+            let op = if *pressed != negate { 0xE0A1 } else { 0xE09E };
+            return emit_op(asm, op | (x << 8));
+        }
+        Condition::Compare(x, operator, operand) => (*x, operator.as_str(), *operand),
+    };
+    let operator = if negate {
+        match operator {
+            "==" => "!=",
+            "!=" => "==",
+            "<" => ">=",
+            ">=" => "<",
+            ">" => "<=",
+            _ => ">",
+        }
+    } else {
+        operator
+    };
+    match (operator, operand) {
+        ("==", Ok(y)) => emit_op(asm, 0x9000 | (x << 8) | (y << 4)),
+        ("==", Err(byte)) => emit_op(asm, 0x4000 | (x << 8) | byte as u16),
+        ("!=", Ok(y)) => emit_op(asm, 0x5000 | (x << 8) | (y << 4)),
+        ("!=", Err(byte)) => emit_op(asm, 0x3000 | (x << 8) | byte as u16),
+        _ => {
+            match operand {
+                Ok(y) => emit_op(asm, 0x8F00 | (y << 4))?,
+                Err(byte) => emit_op(asm, 0x6F00 | byte as u16)?,
+            }
+            // VF = right - left sets the flag when right >= left, VF = left - right when left >= right
+            let flag_when_left_ge_right = operator == "<" || operator == ">=";
+            emit_op(asm, if flag_when_left_ge_right { 0x8F07 } else { 0x8F05 } | (x << 4))?;
+            let skip_when_flag = operator == "<" || operator == ">";
+            emit_op(asm, if skip_when_flag { 0x3F01 } else { 0x3F00 })
+        }
+    }
+}
+
+/**
+ * Record the arguments and the body of a macro, between braces
+ */
+fn define_macro(asm: &mut Assembler) -> Result<(), String> {
+    let name = next_token(asm)?.text;
+    let mut arguments = Vec::new();
+    loop {
+        let token = next_token(asm)?;
+        if token.text == "{" {
+            break;
+        }
+        arguments.push(token.text);
+    }
+    let mut body = Vec::new();
+    let mut depth = 1;
+    loop {
+        let token = next_token(asm)?;
+        match token.text.as_str() {
+            "{" => depth += 1,
+            "}" => depth -= 1,
+            _ => (),
+        }
+        if depth == 0 {
+            break;
+        }
+        body.push(token);
+    }
+    asm.macros.insert(name, (arguments, body));
+    return Ok(());
+}
+
+/**
+ * Replace a macro call by its body, with the words that follow the call in place of its arguments
+ */
+fn expand_macro(asm: &mut Assembler, name: &str) -> Result<(), String> {
+    asm.expansions += 1;
+    if asm.expansions > MAX_EXPANSIONS {
+        return Err(error(asm, &format!("the macro '{}' expands forever", name)));
+    }
+    let count = asm.macros[name].0.len();
+    let mut values = Vec::with_capacity(count);
+    for _ in 0..count {
+        values.push(next_token(asm)?.text);
+    }
+    let (arguments, body) = &asm.macros[name];
+    let line = asm.line;
+    let expanded: Vec<Token> = body.iter()
+        .map(|token| {
+            let text = match arguments.iter().position(|argument| *argument == token.text) {
+                Some(index) => values[index].clone(),
+                None => token.text.clone(),
+            };
+            Token { text, line }
+        })
+        .collect();
+    for token in expanded.into_iter().rev() {
+        asm.tokens.push_front(token);
+    }
+    return Ok(());
+}
+
+/**
+ * Expression of `:calc`, evaluated from right to left without precedence between the operators
+ */
+fn calc_expression(asm: &mut Assembler) -> Result<f64, String> {
+    let left = calc_term(asm)?;
+    let operator = match asm.tokens.front() {
+        Some(token) if token.text != "}" && token.text != ")" => token.text.clone(),
+        _ => return Ok(left),
+    };
+    next_token(asm)?;
+    let right = calc_expression(asm)?;
+    let (a, b) = (left.floor() as i64, right.floor() as i64);
+    return Ok(match operator.as_str() {
+        "+" => left + right,
+        "-" => left - right,
+        "*" => left * right,
+        "/" => left / right,
+        "%" => left % right,
+        "pow" => left.powf(right),
+        "min" => left.min(right),
+        "max" => left.max(right),
+        "&" => (a & b) as f64,
+        "|" => (a | b) as f64,
+        "^" => (a ^ b) as f64,
+        "<<" => a.checked_shl(b as u32).unwrap_or(0) as f64,
+        ">>" => a.checked_shr(b as u32).unwrap_or(0) as f64,
+        "<" => (left < right) as i64 as f64,
+        ">" => (left > right) as i64 as f64,
+        "<=" => (left <= right) as i64 as f64,
+        ">=" => (left >= right) as i64 as f64,
+        "==" => (left == right) as i64 as f64,
+        "!=" => (left != right) as i64 as f64,
+        _ => return Err(error(asm, &format!("unknown operator '{}' in the expression", operator))),
+    });
+}
+
+fn calc_term(asm: &mut Assembler) -> Result<f64, String> {
+    let token = next_token(asm)?;
+    let unary: Option<fn(f64) -> f64> = match token.text.as_str() {
+        "-" => Some(|value| -value),
+        "~" => Some(|value| !(value.floor() as i64) as f64),
+        "!" => Some(|value| (value == 0.0) as i64 as f64),
+        "sin" => Some(f64::sin),
+        "cos" => Some(f64::cos),
+        "tan" => Some(f64::tan),
+        "exp" => Some(f64::exp),
+        "log" => Some(f64::ln),
+        "abs" => Some(f64::abs),
+        "sqrt" => Some(f64::sqrt),
+        "sign" => Some(f64::signum),
+        "ceil" => Some(f64::ceil),
+        "floor" => Some(f64::floor),
+        _ => None,
+    };
+    if let Some(function) = unary {
+        return Ok(function(calc_term(asm)?));
+    }
+    return match token.text.as_str() {
+        "(" => {
+            let value = calc_expression(asm)?;
+            expect(asm, ")")?;
+            Ok(value)
+        }
+        "@" => {
+            let address = calc_term(asm)?.floor() as i64;
+            asm.memory.get(address as usize).map(|byte| *byte as f64).ok_or_else(|| error(asm, &format!("address {} is out of memory", address)))
+        }
+        "HERE" => Ok(asm.here as f64),
+        "PI" => Ok(std::f64::consts::PI),
+        "E" => Ok(std::f64::consts::E),
+        text => match asm.constants.get(text) {
+            Some(value) => Ok(*value),
+            None => resolve(asm, text).map(|value| value as f64)
+                .or_else(|| text.parse::<f64>().ok())
+                .ok_or_else(|| error(asm, &format!("undefined name '{}'", text))),
+        },
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chip8::{build_chip8, load_rom, step};
+    use super::*;
+
+    /**
+     * Run an assembled program until it reaches the `: done` loop, then give the registers
+     */
+    fn run(source: &str) -> [u8; 16] {
+        let rom = assemble(source).unwrap();
+        let mut device = build_chip8();
+        load_rom(&mut device, &rom);
+        for _ in 0..1000 {
+            step(&mut device);
+        }
+        let done = rom.len() + START_PRG - 2;
+        assert_eq!(device.pc as usize, done, "{}", source);
+        return device.vn;
+    }
+
+    /**
+     * Whether `if v1 <operator> <operand> then` runs its statement, with v1 = left and v2 = right
+     */
+    fn compare(left: u8, operator: &str, right: u8, operand: &str) -> bool {
+        let source = format!(": main\n  v1 := {}\n  v2 := {}\n  v3 := 0\n  if v1 {} {} then v3 := 1\n: done\n  jump done",
+                             left, right, operator, operand);
+        let registers = run(&source);
+        // The comparison leaves the operands as they were
+        assert_eq!((registers[1], registers[2]), (left, right));
+        return registers[3] == 1;
+    }
+
+    #[test]
+    fn labels_and_loops() {
+        let source = "
+            : main
+              v0 := 5
+              loop
+                v0 -= 1
+              while v0 != 0 again
+              i := data      # defined further down
+              sprite v0 v1 3
+              jump main
+            : data
+              0x11 0x22 0b110011
+        ";
+        assert_eq!(assemble(source).unwrap(), vec![
+            0x60, 0x05, 0x70, 0xFF, 0x40, 0x00, 0x12, 0x0A, 0x12, 0x02,
+            0xA2, 0x10, 0xD0, 0x13, 0x12, 0x00, 0x11, 0x22, 0x33,
+        ]);
+    }
+
+    #[test]
+    fn directives() {
+        let source = "
+            :const SPEED 3
+            :calc DOUBLE { SPEED * 2 + 1 }
+            :alias counter vA
+            : start
+              counter := DOUBLE
+              :unpack 0xA sprite
+              :call start
+              :byte 0xFF
+            :org 0x300
+            : sprite
+              :byte { SPEED << 4 }
+            : main
+              if counter key then counter := 1
+        ";
+        let rom = assemble(source).unwrap();
+        // main does not come first, the program starts with a jump to it
+        assert_eq!(rom[..11], [0x13, 0x01, 0x6A, 0x09, 0x60, 0xA3, 0x61, 0x00, 0x22, 0x02, 0xFF]);
+        assert!(rom[11..0x100].iter().all(|byte| *byte == 0));
+        assert_eq!(rom[0x100..], [0x30, 0xEA, 0xA1, 0x6A, 0x01]);
+    }
+
+    #[test]
+    fn macros_and_conditions() {
+        let source = "
+            :macro add-to register amount { register += amount }
+            : main
+              add-to v3 4
+              if v1 == v2 begin
+                v0 := 1
+              else
+                v0 := 2
+              end
+              if v1 < 5 then v0 := 0
+              :assert \"main comes first\" { main == 0x200 }
+        ";
+        assert_eq!(assemble(source).unwrap(), vec![
+            0x73, 0x04,
+            0x51, 0x20, 0x12, 0x0A, 0x60, 0x01, 0x12, 0x0C, 0x60, 0x02,
+            0x6F, 0x05, 0x8F, 0x17, 0x3F, 0x01, 0x60, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn ordered_comparisons_run() {
+        for operator in ["<", ">", "<=", ">="] {
+            for (left, right) in [(1u8, 5u8), (5, 5), (9, 5), (0, 0), (0, 255), (255, 0), (255, 255)] {
+                let expected = match operator {
+                    "<" => left < right,
+                    ">" => left > right,
+                    "<=" => left <= right,
+                    _ => left >= right,
+                };
+                assert_eq!(compare(left, operator, right, "v2"), expected, "{} {} {}", left, operator, right);
+                assert_eq!(compare(left, operator, right, &right.to_string()), expected, "{} {} {}", left, operator, right);
+            }
+        }
+    }
+
+    #[test]
+    fn ordered_loop_runs() {
+        let registers = run(": main\n  v0 := 0\n  loop\n    v0 += 1\n  while v0 < 10 again\n: done\n  jump done");
+        assert_eq!(registers[0], 10);
+    }
+
+    #[test]
+    fn errors_give_their_line() {
+        assert_eq!(assemble("v0 := 1").unwrap_err(), "the program has no ': main' label");
+        assert_eq!(assemble(": main\n  jump nowhere").unwrap_err(), "line 2 : undefined name 'nowhere'");
+        assert_eq!(assemble(": main\n: main").unwrap_err(), "line 2 : the label 'main' is defined twice");
+        assert_eq!(assemble(": main\n\n  hires").unwrap_err(), "line 3 : 'hires' is a SUPER-CHIP or XO-CHIP statement, only Chip 8 programs can run");
+        assert_eq!(assemble(": main\n  loop\n  v0 += 1").unwrap_err(), "line 2 : 'loop' without 'again'");
+        assert_eq!(assemble(": main\n  if v0 == 1 begin").unwrap_err(), "line 2 : 'begin' without 'end'");
+        assert_eq!(assemble(": main\n  v0 := 300").unwrap_err(), "line 2 : 300 does not fit in a byte");
+        assert_eq!(assemble(": main\n  v0 <<= 1").unwrap_err(), "line 2 : '<<=' needs a register, found '1'");
+        assert_eq!(assemble(": main\n  :assert \"too big\" { 1 > 2 }").unwrap_err(), "line 2 : too big");
+        assert_eq!(assemble(": main\n  :macro forever { forever }\n  forever").unwrap_err(), "line 3 : the macro 'forever' expands forever");
+        assert_eq!(assemble(": main\n  :breakpoint \"open").unwrap_err(), "line 2 : unterminated string");
+    }
+}
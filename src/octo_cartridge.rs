@@ -0,0 +1,494 @@
+/*!
+ * Reader for Octo cartridges, GIF images that carry the source of a program and the options it runs with
+ *
+ * The payload is hidden in the 2 low bits of the colour index of each pixel, 4 pixels per byte from the most significant
+ * bits, through all the frames : a 32 bits big endian length, then a UTF-8 JSON object `{"program": ..., "options": {...}}`.
+ */
+
+#![allow(clippy::needless_return)]
+
+use crate::chip8_quirks::build_default_quirks;
+use crate::chip8_runner::FRAMES_PER_SECOND;
+use crate::palette::parse_color;
+use crate::rom_database::{MAX_INSTRUCTIONS_PER_FRAME, RomSettings};
+
+// Rate of the frames Octo counts its instructions per tick in
+const OCTO_TICKS_PER_SECOND: f64 = 60.0;
+
+// Widest code of the GIF flavour of LZW
+const GIF_MAX_CODE_BITS: u32 = 12;
+
+/**
+ * Program found in a cartridge, still as Octo source, with the settings given by its options
+ */
+pub struct OctoCartridge {
+    pub(crate) program: String,
+    pub(crate) settings: RomSettings,
+}
+
+/**
+ * Tell whether these bytes are a GIF image
+ */
+pub fn is_gif(bytes: &[u8]) -> bool {
+    return bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a");
+}
+
+/**
+ * Read the program and the options hidden in a cartridge
+ */
+pub fn read_cartridge(bytes: &[u8]) -> Result<OctoCartridge, String> {
+    let mut payload = Vec::new();
+    for frame in decode_gif_frames(bytes)? {
+        for pixels in frame.chunks_exact(4) {
+            payload.push(pixels.iter().fold(0u8, |byte, pixel| (byte << 2) | (pixel & 3)));
+        }
+    }
+    if payload.len() < 4 {
+        return Err("not an Octo cartridge".to_string());
+    }
+    let size = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+    if size > payload.len() - 4 {
+        return Err("not an Octo cartridge, or a truncated one".to_string());
+    }
+    let text = std::str::from_utf8(&payload[4..4 + size]).map_err(|_| "not an Octo cartridge".to_string())?;
+    let json = parse_json(text).map_err(|_| "not an Octo cartridge".to_string())?;
+    let program = match json_field(&json, "program") {
+        Some(JsonValue::String(program)) => program.clone(),
+        _ => return Err("the Octo cartridge holds no program".to_string()),
+    };
+    let settings = match json_field(&json, "options") {
+        Some(options) => cartridge_settings(options)?,
+        None => RomSettings::default(),
+    };
+    return Ok(OctoCartridge { program, settings });
+}
+
+/**
+ * Settings matching the Octo options : speed, quirks and colours
+ */
+fn cartridge_settings(options: &JsonValue) -> Result<RomSettings, String> {
+    let mut settings = RomSettings::default();
+    if let Some(JsonValue::Number(tickrate)) = json_field(options, "tickrate") {
+        let steps = (tickrate * OCTO_TICKS_PER_SECOND / FRAMES_PER_SECOND as f64).round();
+        settings.instructions_per_frame = Some(steps.clamp(1.0, MAX_INSTRUCTIONS_PER_FRAME as f64) as u32);
+    }
+
+    let flag = |name: &str| match json_field(options, name) {
+        Some(JsonValue::Bool(value)) => Some(*value),
+        _ => None,
+    };
+    let mut quirks = build_default_quirks();
+    let mut any_quirk = false;
+    // Octo names the quirks after the SUPER-CHIP behaviours, a flag set turns away from the COSMAC VIP one
+    if let Some(value) = flag("shiftQuirks") {
+        quirks.shift_vy = !value;
+        any_quirk = true;
+    }
+    if let Some(value) = flag("loadStoreQuirks") {
+        quirks.load_store_increment = !value;
+        any_quirk = true;
+    }
+    if let Some(value) = flag("jumpQuirks") {
+        quirks.jump_vx = value;
+        any_quirk = true;
+    }
+    if let Some(value) = flag("logicQuirks") {
+        quirks.logic_reset_vf = value;
+        any_quirk = true;
+    }
+    if let Some(value) = flag("clipQuirks") {
+        quirks.clip_sprites = value;
+        any_quirk = true;
+    }
+    if any_quirk {
+        settings.quirks = Some(quirks);
+    }
+
+    if let Some(JsonValue::String(color)) = json_field(options, "fillColor") {
+        settings.fg = Some(parse_color(color).map_err(|e| format!("Octo cartridge fillColor : {}", e))?);
+    }
+    if let Some(JsonValue::String(color)) = json_field(options, "backgroundColor") {
+        settings.bg = Some(parse_color(color).map_err(|e| format!("Octo cartridge backgroundColor : {}", e))?);
+    }
+    return Ok(settings);
+}
+
+/**
+ * Colour indexes of the pixels of each frame of a GIF image, in the order they are stored
+ */
+pub fn decode_gif_frames(bytes: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let damaged = || "the GIF image is damaged".to_string();
+    if !is_gif(bytes) || bytes.len() < 13 {
+        return Err("not a GIF image".to_string());
+    }
+    let mut position = 13;
+    if bytes[10] & 0x80 != 0 {
+        position += 3 << ((bytes[10] & 7) + 1);
+    }
+    let mut frames = Vec::new();
+    loop {
+        match bytes.get(position) {
+            // Extension : its label, then data blocks
+            Some(0x21) => {
+                position += 2;
+                read_sub_blocks(bytes, &mut position).ok_or_else(damaged)?;
+            }
+            // Image : its descriptor, an optional colour table, then the LZW data
+            Some(0x2C) => {
+                let descriptor = bytes.get(position + 1..position + 10).ok_or_else(damaged)?;
+                let width = u16::from_le_bytes([descriptor[4], descriptor[5]]) as usize;
+                let height = u16::from_le_bytes([descriptor[6], descriptor[7]]) as usize;
+                position += 10;
+                if descriptor[8] & 0x80 != 0 {
+                    position += 3 << ((descriptor[8] & 7) + 1);
+                }
+                let min_code_size = *bytes.get(position).ok_or_else(damaged)? as u32;
+                position += 1;
+                let data = read_sub_blocks(bytes, &mut position).ok_or_else(damaged)?;
+                let mut pixels = lzw_decompress(&data, min_code_size)?;
+                pixels.truncate(width * height);
+                frames.push(pixels);
+            }
+            Some(0x3B) => return Ok(frames),
+            _ => return Err(damaged()),
+        }
+    }
+}
+
+/**
+ * Data split in blocks of at most 255 bytes, each one after its length, up to an empty block
+ */
+fn read_sub_blocks(bytes: &[u8], position: &mut usize) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    loop {
+        let length = *bytes.get(*position)? as usize;
+        *position += 1;
+        if length == 0 {
+            return Some(data);
+        }
+        data.extend_from_slice(bytes.get(*position..*position + length)?);
+        *position += length;
+    }
+}
+
+/**
+ * Expand the GIF flavour of LZW : variable width codes, least significant bit first
+ */
+pub fn lzw_decompress(data: &[u8], min_code_size: u32) -> Result<Vec<u8>, String> {
+    if !(1..GIF_MAX_CODE_BITS).contains(&min_code_size) {
+        return Err("the GIF image is damaged".to_string());
+    }
+    let clear = 1u16 << min_code_size;
+    let end = clear + 1;
+    // Each code is a previous code followed by one index, the first codes are the indexes themselves
+    let mut table: Vec<(Option<u16>, u8)> = Vec::new();
+    let reset = |table: &mut Vec<(Option<u16>, u8)>| {
+        table.clear();
+        table.extend((0..clear + 2).map(|index| (None, index as u8)));
+    };
+    reset(&mut table);
+    let expand = |table: &Vec<(Option<u16>, u8)>, mut code: u16| {
+        let mut indexes = Vec::new();
+        loop {
+            let (prefix, index) = table[code as usize];
+            indexes.push(index);
+            match prefix {
+                Some(prefix) => code = prefix,
+                None => break,
+            }
+        }
+        indexes.reverse();
+        indexes
+    };
+
+    let mut out = Vec::new();
+    let mut width = min_code_size + 1;
+    let mut previous: Option<u16> = None;
+    let mut bit = 0;
+    while bit + width as usize <= data.len() * 8 {
+        let mut code = 0u16;
+        for i in 0..width as usize {
+            code |= (((data[(bit + i) / 8] >> ((bit + i) % 8)) & 1) as u16) << i;
+        }
+        bit += width as usize;
+        if code == clear {
+            reset(&mut table);
+            width = min_code_size + 1;
+            previous = None;
+            continue;
+        }
+        if code == end {
+            break;
+        }
+        let indexes = match previous {
+            _ if (code as usize) < table.len() => expand(&table, code),
+            // The code being defined : the previous string followed by its own first index
+            Some(previous) if code as usize == table.len() => {
+                let mut indexes = expand(&table, previous);
+                indexes.push(indexes[0]);
+                indexes
+            }
+            _ => return Err("the GIF image is damaged".to_string()),
+        };
+        out.extend_from_slice(&indexes);
+        if let Some(previous) = previous {
+            if table.len() < 1 << GIF_MAX_CODE_BITS {
+                table.push((Some(previous), indexes[0]));
+            }
+        }
+        if table.len() == 1 << width && width < GIF_MAX_CODE_BITS {
+            width += 1;
+        }
+        previous = Some(code);
+    }
+    return Ok(out);
+}
+
+/**
+ * JSON value, enough of the format for the cartridge payload : arrays are checked but their content is not kept
+ */
+#[derive(Debug)]
+enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array,
+    Object(Vec<(String, JsonValue)>),
+}
+
+fn json_field<'a>(value: &'a JsonValue, name: &str) -> Option<&'a JsonValue> {
+    return match value {
+        JsonValue::Object(fields) => fields.iter().find(|(key, _)| key == name).map(|(_, value)| value),
+        _ => None,
+    };
+}
+
+fn parse_json(text: &str) -> Result<JsonValue, String> {
+    let mut chars = text.chars().peekable();
+    let value = parse_json_value(&mut chars)?;
+    skip_json_spaces(&mut chars);
+    return match chars.next() {
+        None => Ok(value),
+        Some(c) => Err(format!("unexpected '{}' after the JSON value", c)),
+    };
+}
+
+type JsonChars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+fn skip_json_spaces(chars: &mut JsonChars) {
+    while matches!(chars.peek(), Some(c) if c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+fn parse_json_value(chars: &mut JsonChars) -> Result<JsonValue, String> {
+    skip_json_spaces(chars);
+    match chars.peek() {
+        Some('{') => {
+            chars.next();
+            let mut fields = Vec::new();
+            loop {
+                skip_json_spaces(chars);
+                if fields.is_empty() && chars.peek() == Some(&'}') {
+                    chars.next();
+                    return Ok(JsonValue::Object(fields));
+                }
+                let key = match parse_json_value(chars)? {
+                    JsonValue::String(key) => key,
+                    _ => return Err("JSON object keys must be strings".to_string()),
+                };
+                skip_json_spaces(chars);
+                if chars.next() != Some(':') {
+                    return Err("expected ':' in a JSON object".to_string());
+                }
+                fields.push((key, parse_json_value(chars)?));
+                skip_json_spaces(chars);
+                match chars.next() {
+                    Some(',') => (),
+                    Some('}') => return Ok(JsonValue::Object(fields)),
+                    _ => return Err("expected ',' or '}' in a JSON object".to_string()),
+                }
+            }
+        }
+        Some('[') => {
+            chars.next();
+            skip_json_spaces(chars);
+            if chars.peek() == Some(&']') {
+                chars.next();
+                return Ok(JsonValue::Array);
+            }
+            loop {
+                parse_json_value(chars)?;
+                skip_json_spaces(chars);
+                match chars.next() {
+                    Some(',') => (),
+                    Some(']') => return Ok(JsonValue::Array),
+                    _ => return Err("expected ',' or ']' in a JSON array".to_string()),
+                }
+            }
+        }
+        Some('"') => {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    None => return Err("unterminated JSON string".to_string()),
+                    Some('"') => return Ok(JsonValue::String(text)),
+                    Some('\\') => {
+                        let escaped = match chars.next() {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some('r') => '\r',
+                            Some('b') => '\u{8}',
+                            Some('f') => '\u{c}',
+                            Some('u') => {
+                                let hex: String = chars.by_ref().take(4).collect();
+                                let code = u32::from_str_radix(&hex, 16).map_err(|_| "invalid JSON escape".to_string())?;
+                                char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                            }
+                            Some(c) => c,
+                            None => return Err("unterminated JSON string".to_string()),
+                        };
+                        text.push(escaped);
+                    }
+                    Some(c) => text.push(c),
+                }
+            }
+        }
+        _ => {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || "+-.".contains(c)) {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            return match word.as_str() {
+                "null" => Ok(JsonValue::Null),
+                "true" => Ok(JsonValue::Bool(true)),
+                "false" => Ok(JsonValue::Bool(false)),
+                _ => word.parse::<f64>().map(JsonValue::Number).map_err(|_| format!("'{}' is not a JSON value", word)),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::chip8_quirks::build_default_quirks;
+    use crate::octo_assembler::assemble;
+    use super::*;
+
+    /**
+     * GIF image of 64 pixels wide frames carrying the payload of a cartridge in their 2 low bits
+     *
+     * The LZW data clears its table every 2 pixels, so that all its codes stay 3 bits wide.
+     */
+    pub(crate) fn build_cartridge(json: &str, frame_count: usize) -> Vec<u8> {
+        let mut payload = (json.len() as u32).to_be_bytes().to_vec();
+        payload.extend_from_slice(json.as_bytes());
+        return build_gif(&payload, frame_count);
+    }
+
+    #[allow(clippy::manual_div_ceil)]
+    fn build_gif(payload: &[u8], frame_count: usize) -> Vec<u8> {
+        let pixels: Vec<u8> = payload.iter().flat_map(|byte| (0..4).rev().map(move |i| (byte >> (i * 2)) & 3)).collect();
+        // Whole rows in each frame, only the last one is padded
+        let height = ((pixels.len() + frame_count - 1) / frame_count + 63) / 64;
+        let frame_size = height * 64;
+
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend_from_slice(&[64, 0, height as u8, 0, 0x81, 0, 0]);
+        gif.extend_from_slice(&[0, 0, 0, 0xFF, 0xFF, 0xFF, 0x80, 0x80, 0x80, 0x40, 0x40, 0x40]);
+        // Graphic control extension, skipped by the reader
+        gif.extend_from_slice(&[0x21, 0xF9, 4, 0, 10, 0, 0, 0]);
+        for frame in pixels.chunks(frame_size) {
+            let mut frame = frame.to_vec();
+            frame.resize(frame_size, 0);
+            let mut codes = Vec::new();
+            for pair in frame.chunks(2) {
+                codes.push(4);
+                codes.extend(pair.iter().map(|pixel| *pixel as u32));
+            }
+            codes.push(5);
+            let mut data = vec![0u8; (codes.len() * 3 + 7) / 8];
+            for (index, code) in codes.iter().enumerate() {
+                for i in 0..3 {
+                    data[(index * 3 + i) / 8] |= (((code >> i) & 1) as u8) << ((index * 3 + i) % 8);
+                }
+            }
+            gif.extend_from_slice(&[0x2C, 0, 0, 0, 0, 64, 0, height as u8, 0, 0, 2]);
+            for block in data.chunks(255) {
+                gif.push(block.len() as u8);
+                gif.extend_from_slice(block);
+            }
+            gif.push(0);
+        }
+        gif.push(0x3B);
+        return gif;
+    }
+
+    #[test]
+    fn cartridge_with_options() {
+        let json = r##"{"program": ": main\n  v0 := 0x2A\n  jump main", "options": {"tickrate": 20, "fillColor": "#FFCC00",
+            "backgroundColor": "#996600", "shiftQuirks": true, "jumpQuirks": false, "other": [1, {"a": null}]}}"##;
+        let cartridge = read_cartridge(&build_cartridge(json, 3)).unwrap();
+        assert_eq!(cartridge.program, ": main\n  v0 := 0x2A\n  jump main");
+        assert_eq!(cartridge.settings.instructions_per_frame, Some(20 * 60 / FRAMES_PER_SECOND));
+        assert_eq!(cartridge.settings.fg, Some(parse_color("#FFCC00").unwrap()));
+        assert_eq!(cartridge.settings.bg, Some(parse_color("#996600").unwrap()));
+        let mut quirks = build_default_quirks();
+        quirks.shift_vy = false;
+        quirks.jump_vx = false;
+        assert_eq!(cartridge.settings.quirks, Some(quirks));
+    }
+
+    #[test]
+    fn exported_cartridge() {
+        // 2 frames of 64 x 32 pixels with a 16 colours palette, the label drawn in the high bits of the indexes. Written
+        // by the encoder of the gif crate, its LZW codes start 5 bits wide.
+        let cartridge = read_cartridge(include_bytes!("../resources/octo-cartridge.gif")).unwrap();
+        assert_eq!(cartridge.program, ": draw-box\n  i := box\n  sprite v0 v1 4\n  return\n\n: box\n  0xF0 0x90 0x90 0xF0\n\n\
+            : main\n  v0 := 0\n  v1 := 8\n  loop\n    draw-box\n    v0 += 6\n    if v0 != 60 then\n  again\n  v2 := 3\n\
+            \x20 if v2 < 5 then v3 := 1\n  : done\n  jump done\n");
+        assert!(assemble(&cartridge.program).is_ok());
+        assert_eq!(cartridge.settings.instructions_per_frame, Some(20 * 60 / FRAMES_PER_SECOND));
+        assert_eq!(cartridge.settings.fg, Some(parse_color("#FFCC00").unwrap()));
+        assert_eq!(cartridge.settings.bg, Some(parse_color("#996600").unwrap()));
+        let mut quirks = build_default_quirks();
+        quirks.shift_vy = true;
+        quirks.load_store_increment = false;
+        quirks.jump_vx = false;
+        quirks.logic_reset_vf = true;
+        quirks.clip_sprites = true;
+        assert_eq!(cartridge.settings.quirks, Some(quirks));
+    }
+
+    #[test]
+    fn cartridge_without_options() {
+        let cartridge = read_cartridge(&build_cartridge(r#"{"program": ": main jump main"}"#, 1)).unwrap();
+        assert_eq!(cartridge.program, ": main jump main");
+        assert!(cartridge.settings.instructions_per_frame.is_none() && cartridge.settings.quirks.is_none());
+    }
+
+    #[test]
+    fn cartridge_errors() {
+        assert_eq!(read_cartridge(b"\x89PNG\r\n").err(), Some("not a GIF image".to_string()));
+        assert_eq!(read_cartridge(&build_cartridge(r#"{"options": {}}"#, 1)).err(), Some("the Octo cartridge holds no program".to_string()));
+        assert_eq!(read_cartridge(&build_cartridge(r#"{"program": 1"#, 1)).err(), Some("not an Octo cartridge".to_string()));
+        assert!(read_cartridge(&build_cartridge(r#"{"program": "", "options": {"fillColor": "red"}}"#, 1)).err().unwrap()
+                    .starts_with("Octo cartridge fillColor : "));
+
+        // The frames hold less than the length announced
+        let mut payload = 1000u32.to_be_bytes().to_vec();
+        payload.extend_from_slice(br#"{"program": ""}"#);
+        assert_eq!(read_cartridge(&build_gif(&payload, 2)).err(), Some("not an Octo cartridge, or a truncated one".to_string()));
+        assert_eq!(read_cartridge(&build_gif(&[0, 0], 1)).err(), Some("not an Octo cartridge".to_string()));
+
+        // Cut in the middle of the image data
+        let gif = build_cartridge(r#"{"program": ""}"#, 1);
+        assert_eq!(read_cartridge(&gif[..gif.len() - 10]).err(), Some("the GIF image is damaged".to_string()));
+    }
+}
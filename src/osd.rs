@@ -0,0 +1,121 @@
+#![allow(clippy::needless_return)]
+
+use std::time::{Duration, Instant};
+
+// Size of a character of the font, in font pixels
+pub const FONT_WIDTH: usize = 3;
+pub const FONT_HEIGHT: usize = 5;
+
+// How long a message stays on screen
+const MESSAGE_DURATION: Duration = Duration::from_secs(2);
+
+// Messages shown at once, the oldest ones go away first
+const MAX_MESSAGES: usize = 4;
+
+/**
+ * Text drawn over the emulator output : transient messages and live metrics
+ */
+pub struct Osd {
+
+    // Messages and the time they were shown, oldest first
+    messages: Vec<(String, Instant)>,
+
+    // Metrics line, such as "40 FPS 540 IPS VIP PAUSED"
+    pub(crate) stats: String,
+
+    pub(crate) show_stats: bool,
+}
+
+pub fn build_osd(show_stats: bool) -> Osd {
+    return Osd { messages: Vec::new(), stats: String::new(), show_stats };
+}
+
+/**
+ * Show a message for a few seconds
+ */
+pub fn osd_message(osd: &mut Osd, text: &str) {
+    osd.messages.push((text.to_string(), Instant::now()));
+    if osd.messages.len() > MAX_MESSAGES {
+        osd.messages.remove(0);
+    }
+}
+
+/**
+ * Lines to draw now, from the top of the screen, expired messages are dropped
+ */
+pub fn osd_lines(osd: &mut Osd) -> Vec<String> {
+    let now = Instant::now();
+    osd.messages.retain(|(_, shown)| now.duration_since(*shown) < MESSAGE_DURATION);
+    let mut lines = Vec::new();
+    if osd.show_stats && !osd.stats.is_empty() {
+        lines.push(osd.stats.clone());
+    }
+    lines.extend(osd.messages.iter().map(|(text, _)| text.clone()));
+    return lines;
+}
+
+/**
+ * Rows of a character, the 3 low bits of each row are its pixels from left to right
+ *
+ * Letters are drawn in upper case, characters without a glyph are drawn as '?'.
+ */
+pub fn glyph(c: char) -> [u8; FONT_HEIGHT] {
+    return match c.to_ascii_uppercase() {
+        ' ' => [0b000, 0b000, 0b000, 0b000, 0b000],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '(' => [0b001, 0b010, 0b010, 0b010, 0b001],
+        ')' => [0b100, 0b010, 0b010, 0b010, 0b100],
+        '[' => [0b011, 0b010, 0b010, 0b010, 0b011],
+        ']' => [0b110, 0b010, 0b010, 0b010, 0b110],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        '\'' => [0b010, 0b010, 0b000, 0b000, 0b000],
+        '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+        '>' => [0b100, 0b010, 0b001, 0b010, 0b100],
+        '<' => [0b001, 0b010, 0b100, 0b010, 0b001],
+        _ => [0b111, 0b001, 0b011, 0b000, 0b010],
+    };
+}
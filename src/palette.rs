@@ -0,0 +1,94 @@
+#![allow(clippy::needless_return)]
+
+/**
+ * Colours used to show the display
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+
+    // Theme name, as given on the command line
+    pub(crate) name: String,
+
+    // ARGB colour of each pixel value, 0 is the background and 1 the foreground.
+    // Multi-plane displays use 4 entries (2 planes) or 16 entries (4 planes).
+    pub(crate) colors: Vec<u32>,
+
+    // ARGB colour of the grid drawn between the pixels
+    pub(crate) grid: u32,
+}
+
+// Built-in themes, in the order they are cycled through
+pub const THEME_NAMES: [&str; 4] = ["classic", "amber", "white", "octo"];
+
+/**
+ * Built-in theme of this name
+ */
+pub fn palette_preset(name: &str) -> Option<Palette> {
+    let (colors, grid) = match name {
+        // Green on dark blue, the original look of the emulator
+        "classic" => ([0xFF072636, 0xFF5ABE5A, 0xFF3A8FB7, 0xFFC8E6C8], 0xFF0E3044),
+        // Amber monochrome monitor
+        "amber" => ([0xFF1A0F00, 0xFFFFB000, 0xFFA05A00, 0xFFFFE0A0], 0xFF2A1A00),
+        "white" => ([0xFF000000, 0xFFFFFFFF, 0xFF808080, 0xFFC0C0C0], 0xFF202020),
+        // Default colours of the Octo IDE
+        "octo" => ([0xFF996600, 0xFFFFCC00, 0xFFFF6600, 0xFF662200], 0xFF8A5C00),
+        _ => return None,
+    };
+    return Some(Palette { name: name.to_string(), colors: colors.to_vec(), grid });
+}
+
+/**
+ * Built-in theme following this one, wrapping around
+ */
+pub fn next_palette(palette: &Palette) -> Palette {
+    let index = THEME_NAMES.iter().position(|name| *name == palette.name).map(|i| i + 1).unwrap_or(0);
+    return palette_preset(THEME_NAMES[index % THEME_NAMES.len()]).unwrap();
+}
+
+/**
+ * ARGB colour of a pixel value
+ */
+pub fn palette_color(palette: &Palette, value: usize) -> u32 {
+    return palette.colors[value % palette.colors.len()];
+}
+
+/**
+ * ARGB colour between the background (intensity 0) and the foreground (intensity 255)
+ */
+pub fn intensity_color(palette: &Palette, intensity: u8) -> u32 {
+    let background = palette_color(palette, 0);
+    let foreground = palette_color(palette, 1);
+    let mut color = 0xFF000000;
+    for shift in [0, 8, 16] {
+        let from = (background >> shift) & 0xFF;
+        let to = (foreground >> shift) & 0xFF;
+        let channel = (from * (255 - intensity as u32) + to * intensity as u32) / 255;
+        color |= channel << shift;
+    }
+    return color;
+}
+
+/**
+ * Parse a colour written `#RRGGBB` or `RRGGBB`
+ */
+pub fn parse_color(text: &str) -> Result<u32, String> {
+    let hex = text.trim().trim_start_matches('#');
+    if hex.len() != 6 {
+        return Err(format!("'{}' is not a colour, expected #RRGGBB", text));
+    }
+    return match u32::from_str_radix(hex, 16) {
+        Ok(rgb) => Ok(0xFF000000 | rgb),
+        Err(_) => Err(format!("'{}' is not a colour, expected #RRGGBB", text)),
+    };
+}
+
+/**
+ * Parse a comma separated list of 4 or 16 colours
+ */
+pub fn parse_palette_colors(text: &str) -> Result<Vec<u32>, String> {
+    let colors = text.split(',').map(parse_color).collect::<Result<Vec<u32>, String>>()?;
+    if colors.len() != 4 && colors.len() != 16 {
+        return Err(format!("a palette has 4 or 16 colours, {} given", colors.len()));
+    }
+    return Ok(colors);
+}
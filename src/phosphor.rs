@@ -0,0 +1,64 @@
+#![allow(clippy::needless_return)]
+
+use crate::chip8_display::{Chip8Display, DISPLAY_HEIGHT, DISPLAY_WIDTH, build_chip8_display, get_px_at};
+
+/**
+ * How long a pixel stays visible after it is turned off
+ *
+ * Games erase and redraw their sprites with XOR, so moving sprites are off during part of the frames and flicker.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PersistenceMode {
+    // Show the display as it is
+    Off,
+    // Turned off pixels fade out over this number of frames
+    Decay(u32),
+    // Show the pixels lit in the current or in the previous frame
+    Blend,
+}
+
+/**
+ * Brightness of each pixel after persistence, from 0 (off) to 255 (lit)
+ */
+pub struct Phosphor {
+    mode: PersistenceMode,
+    pub(crate) intensity: Vec<u8>,
+    // Display of the previous frame, for the blend mode
+    previous: Chip8Display,
+}
+
+pub fn build_phosphor(mode: PersistenceMode) -> Phosphor {
+    return Phosphor { mode, intensity: vec![0; DISPLAY_WIDTH * DISPLAY_HEIGHT], previous: build_chip8_display() };
+}
+
+/**
+ * Update the brightness of the pixels with a new frame
+ */
+pub fn phosphor_update(phosphor: &mut Phosphor, display: &Chip8Display) {
+    for y in 0..DISPLAY_HEIGHT {
+        for x in 0..DISPLAY_WIDTH {
+            let lit = get_px_at(display, x, y);
+            let pixel = &mut phosphor.intensity[y * DISPLAY_WIDTH + x];
+            *pixel = match phosphor.mode {
+                PersistenceMode::Off => if lit { 255 } else { 0 },
+                PersistenceMode::Decay(frames) => {
+                    if lit {
+                        255
+                    } else {
+                        let step = (255 / frames.max(1)).max(1) as u8;
+                        pixel.saturating_sub(step)
+                    }
+                }
+                PersistenceMode::Blend => if lit || get_px_at(&phosphor.previous, x, y) { 255 } else { 0 },
+            };
+        }
+    }
+    phosphor.previous = display.clone();
+}
+
+/**
+ * Brightness of the pixel at (x, y)
+ */
+pub fn phosphor_intensity(phosphor: &Phosphor, x: usize, y: usize) -> u8 {
+    return phosphor.intensity[y * DISPLAY_WIDTH + x];
+}
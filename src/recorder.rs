@@ -0,0 +1,346 @@
+#![allow(clippy::needless_return)]
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::config::{DEFAULT_TONE, DEFAULT_VOLUME};
+use crate::chip8_display::{Chip8Display, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::image_export::{RgbImage, encode_ppm};
+use crate::palette::{Palette, intensity_color};
+use crate::phosphor::{PersistenceMode, Phosphor, build_phosphor, phosphor_update};
+
+// Audio of the recordings
+const SAMPLE_RATE: u32 = 22050;
+
+// GIF codes are at most 12 bits wide
+const GIF_MAX_CODE: u16 = 4095;
+
+/**
+ * Gameplay recording, kept in memory until it is saved
+ *
+ * A path ending with .gif gives an animated GIF, any other path a directory of PPM frames.
+ * The beeper is saved next to it as a WAV file.
+ */
+pub struct Recorder {
+    pub(crate) path: String,
+    palette: Palette,
+    phosphor: Phosphor,
+    // Size of the recorded pixels
+    scale: usize,
+    // Brightness of the pixels of each frame
+    frames: Vec<Vec<u8>>,
+    // Beeper state of each frame
+    beeps: Vec<bool>,
+    frames_per_second: u32,
+    // Beeper tone, the same as in the window
+    pub(crate) tone: f32,
+    pub(crate) volume: f32,
+}
+
+pub fn build_recorder(path: &str, palette: Palette, persistence: PersistenceMode, scale: usize, frames_per_second: u32) -> Recorder {
+    return Recorder {
+        path: path.to_string(),
+        palette,
+        phosphor: build_phosphor(persistence),
+        scale: scale.max(1),
+        frames: Vec::new(),
+        beeps: Vec::new(),
+        frames_per_second,
+        tone: DEFAULT_TONE,
+        volume: DEFAULT_VOLUME,
+    };
+}
+
+/**
+ * Add a frame to the recording
+ */
+pub fn record_frame(recorder: &mut Recorder, display: &Chip8Display, beep: bool) {
+    phosphor_update(&mut recorder.phosphor, display);
+    recorder.frames.push(recorder.phosphor.intensity.clone());
+    recorder.beeps.push(beep);
+}
+
+/**
+ * Write the recording, returns the number of frames saved
+ */
+pub fn save_recording(recorder: &Recorder) -> Result<usize, String> {
+    let is_gif = recorder.path.to_lowercase().ends_with(".gif");
+    let wav_path = if is_gif {
+        std::fs::write(&recorder.path, encode_gif(recorder)).map_err(|e| format!("cannot write {} ({})", recorder.path, e))?;
+        Path::new(&recorder.path).with_extension("wav")
+    } else {
+        let directory = Path::new(&recorder.path);
+        std::fs::create_dir_all(directory).map_err(|e| format!("cannot create {} ({})", recorder.path, e))?;
+        for (n, frame) in recorder.frames.iter().enumerate() {
+            let path = directory.join(format!("frame_{:06}.ppm", n));
+            std::fs::write(&path, encode_ppm(&frame_image(recorder, frame)))
+                .map_err(|e| format!("cannot write {} ({})", path.display(), e))?;
+        }
+        directory.join("audio.wav")
+    };
+    std::fs::write(&wav_path, encode_wav(recorder)).map_err(|e| format!("cannot write {} ({})", wav_path.display(), e))?;
+    return Ok(recorder.frames.len());
+}
+
+fn frame_image(recorder: &Recorder, frame: &[u8]) -> RgbImage {
+    let width = DISPLAY_WIDTH * recorder.scale;
+    let height = DISPLAY_HEIGHT * recorder.scale;
+    let mut data = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            let intensity = frame[(y / recorder.scale) * DISPLAY_WIDTH + x / recorder.scale];
+            let color = intensity_color(&recorder.palette, intensity);
+            data.extend_from_slice(&[(color >> 16) as u8, (color >> 8) as u8, color as u8]);
+        }
+    }
+    return RgbImage { width, height, data };
+}
+
+/**
+ * Beeper of the recording as a 16 bits mono WAV file
+ */
+fn encode_wav(recorder: &Recorder) -> Vec<u8> {
+    let mut samples: Vec<i16> = Vec::new();
+    let mut phase = 0.0f32;
+    for (n, beep) in recorder.beeps.iter().enumerate() {
+        // Frames do not hold a whole number of samples, spread the remainder
+        let start = n as u64 * SAMPLE_RATE as u64 / recorder.frames_per_second as u64;
+        let end = (n as u64 + 1) * SAMPLE_RATE as u64 / recorder.frames_per_second as u64;
+        for _ in start..end {
+            let sample = if *beep { (phase * std::f32::consts::TAU).sin() * recorder.volume } else { 0.0 };
+            samples.push((sample * i16::MAX as f32) as i16);
+            phase = (phase + recorder.tone / SAMPLE_RATE as f32).fract();
+        }
+    }
+
+    let data_size = (samples.len() * 2) as u32;
+    let mut out = Vec::with_capacity(44 + data_size as usize);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(36 + data_size).to_le_bytes());
+    out.extend_from_slice(b"WAVEfmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, mono
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    out.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+    out.extend_from_slice(&2u16.to_le_bytes());
+    out.extend_from_slice(&16u16.to_le_bytes());
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_size.to_le_bytes());
+    for sample in samples {
+        out.extend_from_slice(&sample.to_le_bytes());
+    }
+    return out;
+}
+
+/**
+ * Writes variable width codes, least significant bit first
+ */
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    bits: u32,
+}
+
+fn write_code(writer: &mut BitWriter, code: u16, width: u32) {
+    writer.buffer |= (code as u32) << writer.bits;
+    writer.bits += width;
+    while writer.bits >= 8 {
+        writer.bytes.push(writer.buffer as u8);
+        writer.buffer >>= 8;
+        writer.bits -= 8;
+    }
+}
+
+/**
+ * Compress 8 bits colour indexes with the GIF flavour of LZW
+ */
+fn lzw_compress(indexes: &[u8]) -> Vec<u8> {
+    let clear: u16 = 256;
+    let end: u16 = 257;
+    let mut writer = BitWriter { bytes: Vec::new(), buffer: 0, bits: 0 };
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next_code = end + 1;
+    let mut width = 9;
+
+    write_code(&mut writer, clear, width);
+    let mut current: Option<u16> = None;
+    for index in indexes {
+        let prefix = match current {
+            Some(prefix) => prefix,
+            None => {
+                current = Some(*index as u16);
+                continue;
+            }
+        };
+        if let Some(code) = table.get(&(prefix, *index)) {
+            current = Some(*code);
+            continue;
+        }
+        write_code(&mut writer, prefix, width);
+        if next_code > GIF_MAX_CODE {
+            // Table full, start over
+            write_code(&mut writer, clear, width);
+            table.clear();
+            next_code = end + 1;
+            width = 9;
+        } else {
+            table.insert((prefix, *index), next_code);
+            // The decoder widens its codes one code later than the encoder adds them
+            if next_code == (1 << width) && width < 12 {
+                width += 1;
+            }
+            next_code += 1;
+        }
+        current = Some(*index as u16);
+    }
+    if let Some(prefix) = current {
+        write_code(&mut writer, prefix, width);
+    }
+    write_code(&mut writer, end, width);
+    if writer.bits > 0 {
+        writer.bytes.push(writer.buffer as u8);
+    }
+    return writer.bytes;
+}
+
+/**
+ * Recording as an animated GIF looping forever
+ *
+ * The colour index of a pixel is its brightness, identical frames are merged into a longer one.
+ */
+fn encode_gif(recorder: &Recorder) -> Vec<u8> {
+    let width = (DISPLAY_WIDTH * recorder.scale) as u16;
+    let height = (DISPLAY_HEIGHT * recorder.scale) as u16;
+    let mut out = Vec::new();
+    out.extend_from_slice(b"GIF89a");
+    out.extend_from_slice(&width.to_le_bytes());
+    out.extend_from_slice(&height.to_le_bytes());
+    // Global colour table of 256 entries
+    out.extend_from_slice(&[0xF7, 0, 0]);
+    for intensity in 0..=255u8 {
+        let color = intensity_color(&recorder.palette, intensity);
+        out.extend_from_slice(&[(color >> 16) as u8, (color >> 8) as u8, color as u8]);
+    }
+    out.extend_from_slice(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00");
+
+    let mut n = 0;
+    while n < recorder.frames.len() {
+        let mut repeat = 1;
+        while n + repeat < recorder.frames.len() && recorder.frames[n + repeat] == recorder.frames[n] {
+            repeat += 1;
+        }
+        // Delays are in hundredths of second, rounded so they add up to the real duration
+        let start = n as u64 * 100 / recorder.frames_per_second as u64;
+        let end = (n + repeat) as u64 * 100 / recorder.frames_per_second as u64;
+        let delay = (end - start).max(2) as u16;
+        out.extend_from_slice(&[0x21, 0xF9, 0x04, 0x00]);
+        out.extend_from_slice(&delay.to_le_bytes());
+        out.extend_from_slice(&[0x00, 0x00]);
+
+        out.push(0x2C);
+        out.extend_from_slice(&[0, 0, 0, 0]);
+        out.extend_from_slice(&width.to_le_bytes());
+        out.extend_from_slice(&height.to_le_bytes());
+        out.push(0x00);
+
+        let mut indexes = Vec::with_capacity(width as usize * height as usize);
+        for y in 0..height as usize {
+            for x in 0..width as usize {
+                indexes.push(recorder.frames[n][(y / recorder.scale) * DISPLAY_WIDTH + x / recorder.scale]);
+            }
+        }
+        out.push(8);
+        for block in lzw_compress(&indexes).chunks(255) {
+            out.push(block.len() as u8);
+            out.extend_from_slice(block);
+        }
+        out.push(0x00);
+        n += repeat;
+    }
+    out.push(0x3B);
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    use crate::chip8_display::{build_chip8_display, xor_px_at};
+    use crate::octo_cartridge::{decode_gif_frames, lzw_decompress};
+    use crate::palette::palette_preset;
+    use super::*;
+
+    #[test]
+    fn lzw_known_answer() {
+        // Written by the LZW encoder of the weezl crate (the one of the gif crate), the codes go from 9 to 10 bits
+        let expected = [
+            "0001001030A00002050E2254D0E081448A173678105932850B1937770231B224AA552E63D2C0B5DBA700038A1B47B0A0D1B3A8132C61D6D2",
+            "F59360824794337E28B11AB68D1E03133FB2D071A4AA58387F1A705499230916B4770F60409133A916367D1E829451E48A5ABE0F46D850DA",
+            "358E010D2F8762713320A34B225BE220F458A30919BE135710E152B7A1092059E6323411648B5D882B8E88F19BB18614370B4F1211F3A7A3",
+            "4E2C772AD0941A27020CA86F1F3C8B1B5106953A17716AE9F34128D9032B9CC8AD98C38B80934BE158D811D6208B2979401A6D538107D985",
+            "33B8106859D50F8A287B493AC933C229DE114FF4988CDA478595812FB928B851562290371D98EA4D910501CEB31790E0499955018F361FA1",
+            "0AA8714623D33F33CCD0B0C9006C50E3C32911F0618E14BDA46089007074B3842E286072C01DE860A1CC0EAD8020890179B42386354C0473",
+            "832B247032C12204F061CF1BE990E18D16D544E18C12C814318C10C000F1CB0F3D0A3344314828E344345568E3053969BC63073F842C3009",
+            "07A4B8704B10CC58214E1BF9182201282DECA2043668DC730806A8E8B04C17F00862412A3D4863063F919CE00B15EC0CB2012D4C98F34706",
+            "B438A14E2121F8A2053E95CC200D1C10BCF24440",
+        ].concat();
+        let indexes: Vec<u8> = (0..400u32).map(|i| ((i * i + 3 * i) / 5 % 256) as u8).collect();
+        let compressed: String = lzw_compress(&indexes).iter().map(|byte| format!("{:02X}", byte)).collect();
+        assert_eq!(compressed, expected);
+    }
+
+    #[test]
+    fn lzw_round_trip() {
+        let mut rng = StdRng::seed_from_u64(38);
+        let noise: Vec<u8> = (0..20000).map(|_| rng.gen()).collect();
+        let few_colors: Vec<u8> = (0..50000).map(|_| rng.gen_range(0..3)).collect();
+        let inputs = [Vec::new(), vec![7], vec![0; 100000], noise, few_colors];
+        for indexes in inputs.iter() {
+            assert_eq!(&lzw_decompress(&lzw_compress(indexes), 8).unwrap(), indexes);
+        }
+    }
+
+    #[test]
+    fn gif_frames_round_trip() {
+        let mut recorder = build_recorder("test.gif", palette_preset("classic").unwrap(), PersistenceMode::Off, 2, 40);
+        let mut display = build_chip8_display();
+        record_frame(&mut recorder, &display, false);
+        xor_px_at(&mut display, 3, 1);
+        xor_px_at(&mut display, 63, 31);
+        record_frame(&mut recorder, &display, false);
+        record_frame(&mut recorder, &display, false);
+
+        // Identical frames are merged
+        let frames = decode_gif_frames(&encode_gif(&recorder)).unwrap();
+        assert_eq!(frames.len(), 2);
+        let width = DISPLAY_WIDTH * 2;
+        assert_eq!(frames[1].len(), width * DISPLAY_HEIGHT * 2);
+        assert!(frames[0].iter().all(|index| *index == 0));
+        let lit: Vec<usize> = (0..frames[1].len()).filter(|n| frames[1][*n] != 0).collect();
+        assert_eq!(lit, [2 * width + 6, 2 * width + 7, 3 * width + 6, 3 * width + 7,
+            62 * width + 126, 62 * width + 127, 63 * width + 126, 63 * width + 127]);
+        assert!(lit.iter().all(|n| frames[1][*n] == 255));
+    }
+
+    #[test]
+    fn wav_of_one_second() {
+        let mut recorder = build_recorder("test.gif", palette_preset("classic").unwrap(), PersistenceMode::Off, 1, 40);
+        let display = build_chip8_display();
+        for n in 0..40 {
+            record_frame(&mut recorder, &display, n >= 20);
+        }
+        let wav = encode_wav(&recorder);
+        assert_eq!(wav.len(), 44 + SAMPLE_RATE as usize * 2);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(wav[40..44], (SAMPLE_RATE * 2).to_le_bytes());
+        // Silent for the first half second, then the beeper plays
+        let samples: Vec<i16> = wav[44..].chunks(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect();
+        assert!(samples[..SAMPLE_RATE as usize / 2].iter().all(|sample| *sample == 0));
+        assert!(samples[SAMPLE_RATE as usize / 2..].iter().any(|sample| *sample != 0));
+    }
+}
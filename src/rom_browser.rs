@@ -0,0 +1,149 @@
+#![allow(clippy::needless_return)]
+
+use std::path::Path;
+
+use crate::frontend::MenuCommand;
+use crate::rom_file::{archive_roms, decode_rom};
+use crate::sha1::sha1_hex;
+use crate::zip_archive::{entry_file_name, is_zip, split_archive_path};
+
+// Directory listed when none is given
+pub const DEFAULT_ROM_DIR: &str = "resources";
+
+// ROMs listed at once, the list scrolls to keep the selection visible
+const VISIBLE_ROMS: usize = 16;
+
+// Files next to the ROMs that are not programs (keymaps, symbols...)
+pub const SIDE_FILE_EXTENSIONS: [&str; 5] = ["keys", "sym", "md", "txt", "toml"];
+
+/**
+ * A program found in the ROM directory
+ */
+pub struct RomEntry {
+    pub(crate) name: String,
+    pub(crate) path: String,
+    pub(crate) size: usize,
+    // SHA-1 of the file, in hexadecimal
+    pub(crate) hash: String,
+    // Zip archive holding several programs, size is their number
+    pub(crate) archive: bool,
+}
+
+/**
+ * ROM picker, shown when no ROM was given and when going back from a game
+ */
+pub struct RomBrowser {
+    // Directory or zip archive listed
+    pub(crate) dir: String,
+    pub(crate) archive: bool,
+    pub(crate) entries: Vec<RomEntry>,
+    // Index of the highlighted entry
+    pub(crate) selected: usize,
+}
+
+pub fn build_rom_browser(dir: &str) -> Result<RomBrowser, String> {
+    if Path::new(dir).is_file() {
+        return Ok(RomBrowser { dir: dir.to_string(), archive: true, entries: scan_archive(dir)?, selected: 0 });
+    }
+    return Ok(RomBrowser { dir: dir.to_string(), archive: false, entries: scan_roms(dir)?, selected: 0 });
+}
+
+/**
+ * List the programs of a directory sorted by name, skipping the files that cannot be programs
+ */
+pub fn scan_roms(dir: &str) -> Result<Vec<RomEntry>, String> {
+    let files = std::fs::read_dir(dir).map_err(|e| format!("cannot list the ROM directory {} ({})", dir, e))?;
+    let mut entries = Vec::new();
+    for file in files.flatten() {
+        let path = file.path();
+        let name = file.file_name().to_string_lossy().to_string();
+        let extension = path.extension().map(|ext| ext.to_string_lossy().to_lowercase()).unwrap_or_default();
+        if !path.is_file() || name.starts_with('.') || SIDE_FILE_EXTENSIONS.contains(&extension.as_str()) {
+            continue;
+        }
+        let bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(_) => continue,
+        };
+        let path = path.to_string_lossy().to_string();
+        if is_zip(&bytes) {
+            let count = archive_roms(&path, &bytes, None).map(|roms| roms.len()).unwrap_or(0);
+            if count > 0 {
+                entries.push(RomEntry { name, path, size: count, hash: String::new(), archive: true });
+            }
+            continue;
+        }
+        if let Ok(rom) = decode_rom(&name, bytes) {
+            entries.push(RomEntry { name, path, size: rom.bytes.len(), hash: sha1_hex(&rom.bytes), archive: false });
+        }
+    }
+    entries.sort_by_key(|entry| entry.name.to_lowercase());
+    return Ok(entries);
+}
+
+/**
+ * List the programs of a zip archive sorted by name, as paths such as `pack.zip:TETRIS`
+ */
+pub fn scan_archive(file: &str) -> Result<Vec<RomEntry>, String> {
+    let bytes = std::fs::read(file).map_err(|e| format!("cannot read the archive {} ({})", file, e))?;
+    let mut entries: Vec<RomEntry> = archive_roms(file, &bytes, None)?.into_iter()
+        .map(|(name, rom)| RomEntry {
+            path: format!("{}:{}", file, name),
+            name,
+            size: rom.bytes.len(),
+            hash: sha1_hex(&rom.bytes),
+            archive: false,
+        })
+        .collect();
+    entries.sort_by_key(|entry| entry.name.to_lowercase());
+    return Ok(entries);
+}
+
+/**
+ * Apply a menu command, returns the path of the ROM to launch when one was picked
+ */
+pub fn browser_command(browser: &mut RomBrowser, command: MenuCommand) -> Option<String> {
+    if browser.entries.is_empty() {
+        return None;
+    }
+    let last = browser.entries.len() - 1;
+    match command {
+        MenuCommand::Move(offset) => browser.selected = (browser.selected as i32 + offset).clamp(0, last as i32) as usize,
+        MenuCommand::End(end) => browser.selected = if end { last } else { 0 },
+        MenuCommand::Select => return Some(browser.entries[browser.selected].path.clone()),
+    }
+    return None;
+}
+
+/**
+ * Text of the browser : a title, then one line per visible ROM with its size and the start of its hash
+ */
+pub fn browser_lines(browser: &RomBrowser) -> Vec<String> {
+    let place = if browser.archive { browser.dir.clone() } else { format!("{}/", browser.dir.trim_end_matches('/')) };
+    let mut lines = vec![format!("ROMS IN {} - ENTER TO PLAY", place)];
+    if browser.entries.is_empty() {
+        lines.push("NO ROM FOUND".to_string());
+        return lines;
+    }
+    let first = browser.selected.saturating_sub(VISIBLE_ROMS / 2).min(browser.entries.len().saturating_sub(VISIBLE_ROMS));
+    for (i, entry) in browser.entries.iter().enumerate().skip(first).take(VISIBLE_ROMS) {
+        let cursor = if i == browser.selected { '>' } else { ' ' };
+        let name: String = entry.name.chars().take(20).collect();
+        if entry.archive {
+            lines.push(format!("{} {:<20} {:>5} ROMS", cursor, name, entry.size));
+        } else {
+            lines.push(format!("{} {:<20} {:>5} B {}", cursor, name, entry.size, &entry.hash[..8]));
+        }
+    }
+    return lines;
+}
+
+/**
+ * Name of a ROM without its directory nor extension, used in titles and file names
+ *
+ * The ROMs of an archive are named after their file in the archive.
+ */
+pub fn rom_display_name(path: &str) -> String {
+    let file = split_archive_path(path).map(|(_, member)| entry_file_name(member)).unwrap_or(path);
+    return Path::new(file).file_stem().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+}
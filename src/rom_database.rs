@@ -0,0 +1,173 @@
+#![allow(clippy::needless_return)]
+
+use crate::chip8_quirks::{Chip8Quirks, QUIRK_PRESETS, quirks_preset};
+use crate::keymap::{KeyAction, parse_binding};
+use crate::palette::parse_color;
+use crate::toml_parser::{TomlEntry, entry_integer, entry_string, entry_string_array, parse_toml};
+use crate::user_dirs::user_config_dir;
+
+// Database shipped with the emulator
+const BUNDLED_DATABASE: &str = include_str!("../resources/roms.toml");
+
+// User database, in the configuration directory
+const USER_DATABASE_FILE: &str = "roms.toml";
+
+// Instructions per frame accepted from the settings files
+pub const MAX_INSTRUCTIONS_PER_FRAME: i64 = 10000;
+
+/**
+ * Settings that change from one ROM to another, unset fields keep the value of the previous layer
+ */
+#[derive(Debug, Clone, Default)]
+pub struct RomSettings {
+    pub(crate) instructions_per_frame: Option<u32>,
+    pub(crate) quirks: Option<Chip8Quirks>,
+    // Bindings applied on top of the keymap
+    pub(crate) keys: Vec<(KeyAction, Vec<String>)>,
+    pub(crate) fg: Option<u32>,
+    pub(crate) bg: Option<u32>,
+}
+
+/**
+ * What the database knows about a ROM
+ */
+#[derive(Debug, Clone)]
+pub struct RomInfo {
+    // SHA-1 of the ROM, in lower case hexadecimal
+    pub(crate) hash: String,
+    pub(crate) title: Option<String>,
+    pub(crate) author: Option<String>,
+    pub(crate) platform: Option<String>,
+    pub(crate) settings: RomSettings,
+}
+
+/**
+ * ROMs known by the emulator
+ */
+pub struct RomDatabase {
+    pub(crate) roms: Vec<RomInfo>,
+}
+
+/**
+ * Database of the ROMs shipped with the emulator
+ */
+pub fn build_rom_database() -> RomDatabase {
+    let roms = parse_rom_database(BUNDLED_DATABASE).unwrap_or_else(|e| panic!("Error : bundled ROM database, {}", e));
+    return RomDatabase { roms };
+}
+
+/**
+ * Path of the user database, which does not need to exist
+ */
+pub fn user_database_path() -> Option<String> {
+    return user_config_dir().map(|dir| dir.join(USER_DATABASE_FILE).to_string_lossy().to_string());
+}
+
+/**
+ * Add the entries of a database file, the values it gives replace the ones of the ROMs already known
+ */
+pub fn load_rom_database(database: &mut RomDatabase, path: &str) -> Result<(), String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("cannot read the ROM database {} ({})", path, e))?;
+    let roms = parse_rom_database(&text).map_err(|e| format!("{} {}", path, e))?;
+    for rom in roms {
+        match database.roms.iter_mut().find(|known| known.hash == rom.hash) {
+            Some(known) => merge_rom_info(known, rom),
+            None => database.roms.push(rom),
+        }
+    }
+    return Ok(());
+}
+
+fn merge_rom_info(known: &mut RomInfo, rom: RomInfo) {
+    known.title = rom.title.or(known.title.take());
+    known.author = rom.author.or(known.author.take());
+    known.platform = rom.platform.or(known.platform.take());
+    known.settings = merge_settings(&known.settings, &rom.settings);
+}
+
+/**
+ * Settings of a layer applied over the ones of the layer below
+ */
+pub fn merge_settings(below: &RomSettings, above: &RomSettings) -> RomSettings {
+    let mut keys = below.keys.clone();
+    keys.extend(above.keys.iter().cloned());
+    return RomSettings {
+        instructions_per_frame: above.instructions_per_frame.or(below.instructions_per_frame),
+        quirks: above.quirks.or(below.quirks),
+        keys,
+        fg: above.fg.or(below.fg),
+        bg: above.bg.or(below.bg),
+    };
+}
+
+/**
+ * What the database knows about the ROM of this SHA-1
+ */
+pub fn rom_info<'a>(database: &'a RomDatabase, hash: &str) -> Option<&'a RomInfo> {
+    return database.roms.iter().find(|rom| rom.hash.eq_ignore_ascii_case(hash));
+}
+
+/**
+ * Text shown when a known ROM starts, such as "Brix by Andreas Gustafsson"
+ */
+pub fn rom_description(info: &RomInfo) -> Option<String> {
+    let title = info.title.as_ref()?;
+    return Some(match &info.author {
+        Some(author) => format!("{} by {}", title, author),
+        None => title.clone(),
+    });
+}
+
+/**
+ * Parse a database, one table per ROM named after its SHA-1
+ */
+pub fn parse_rom_database(text: &str) -> Result<Vec<RomInfo>, String> {
+    let mut roms = Vec::new();
+    for table in parse_toml(text)? {
+        if table.name.is_empty() {
+            if let Some(entry) = table.entries.first() {
+                return Err(format!("line {} : '{}' is not in a ROM table", entry.line, entry.key));
+            }
+            continue;
+        }
+        let hash = table.name.join(".").to_lowercase();
+        if hash.len() != 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("line {} : [{}] is not the SHA-1 of a ROM", table.line, hash));
+        }
+        let mut rom = RomInfo { hash, title: None, author: None, platform: None, settings: RomSettings::default() };
+        for entry in &table.entries {
+            match entry.key.as_str() {
+                "title" => rom.title = Some(entry_string(entry)?),
+                "author" => rom.author = Some(entry_string(entry)?),
+                "platform" => rom.platform = Some(entry_string(entry)?),
+                _ => parse_settings_entry(&mut rom.settings, entry)?,
+            }
+        }
+        roms.push(rom);
+    }
+    return Ok(roms);
+}
+
+/**
+ * Read a per-ROM setting, shared by the ROM database and the configuration file
+ */
+pub fn parse_settings_entry(settings: &mut RomSettings, entry: &TomlEntry) -> Result<(), String> {
+    match entry.key.as_str() {
+        "instructions_per_frame" => settings.instructions_per_frame = Some(entry_integer(entry, 1, MAX_INSTRUCTIONS_PER_FRAME)? as u32),
+        "quirks" => {
+            let name = entry_string(entry)?;
+            let quirks = quirks_preset(&name)
+                .ok_or_else(|| format!("line {} : unknown quirks '{}', expected one of {}", entry.line, name, QUIRK_PRESETS.join(", ")))?;
+            settings.quirks = Some(quirks);
+        }
+        "keys" => {
+            for binding in entry_string_array(entry)? {
+                settings.keys.push(parse_binding(&binding).map_err(|e| format!("line {} : {}", entry.line, e))?);
+            }
+        }
+        "fg" => settings.fg = Some(parse_color(&entry_string(entry)?).map_err(|e| format!("line {} : {}", entry.line, e))?),
+        "bg" => settings.bg = Some(parse_color(&entry_string(entry)?).map_err(|e| format!("line {} : {}", entry.line, e))?),
+        _ => return Err(format!("line {} : unknown key '{}'", entry.line, entry.key)),
+    }
+    return Ok(());
+}
@@ -0,0 +1,136 @@
+#![allow(clippy::needless_return)]
+
+use crate::chip8::check_rom;
+use crate::octo_assembler::assemble;
+use crate::octo_cartridge::{is_gif, read_cartridge};
+use crate::rom_browser::SIDE_FILE_EXTENSIONS;
+use crate::rom_database::RomSettings;
+use crate::rom_patch::patch_rom;
+use crate::zip_archive::{find_zip_entry, is_zip, split_archive_path, zip_candidates, zip_entries, zip_extract};
+
+/**
+ * Program read from a file, with the settings the file gives (the options of an Octo cartridge)
+ */
+pub struct RomFile {
+    pub(crate) bytes: Vec<u8>,
+    pub(crate) settings: RomSettings,
+}
+
+/**
+ * Read a program from a file, from a file inside a zip archive (`pack.zip:TETRIS`) or from an Octo cartridge
+ *
+ * An archive given without a file name must hold a single program.
+ */
+pub fn read_rom_file(path: &str) -> Result<RomFile, String> {
+    let (file, member) = match split_archive_path(path) {
+        Some((archive, member)) => (archive, Some(member)),
+        None => (path, None),
+    };
+    let bytes = std::fs::read(file).map_err(|e| format!("cannot read the ROM {} ({})", file, e))?;
+    if !is_zip(&bytes) {
+        if member.is_some() {
+            return Err(format!("{} is not a zip archive", file));
+        }
+        return decode_rom(path, bytes);
+    }
+    let mut roms = archive_roms(file, &bytes, member)?;
+    return match roms.len() {
+        1 => Ok(roms.pop().unwrap().1),
+        0 => Err(format!("{} holds no ROM", file)),
+        count => Err(format!("{} holds {} ROMs, pick one as {}:<name>", file, count, file)),
+    };
+}
+
+/**
+ * Read a program as read_rom_file does, then apply IPS or BPS patches to it in order
+ */
+pub fn read_patched_rom(path: &str, patches: &[String]) -> Result<RomFile, String> {
+    let mut rom = read_rom_file(path)?;
+    if !patches.is_empty() {
+        rom.bytes = check_rom(path, patch_rom(rom.bytes, patches)?)?;
+    }
+    return Ok(rom);
+}
+
+/**
+ * Program of a file content : an Octo cartridge is assembled, other files are the program itself
+ */
+pub fn decode_rom(name: &str, bytes: Vec<u8>) -> Result<RomFile, String> {
+    if is_gif(&bytes) {
+        let cartridge = read_cartridge(&bytes).map_err(|e| format!("{} : {}", name, e))?;
+        let program = assemble(&cartridge.program).map_err(|e| format!("{} : {}", name, e))?;
+        return Ok(RomFile { bytes: check_rom(name, program)?, settings: cartridge.settings });
+    }
+    return Ok(RomFile { bytes: check_rom(name, bytes)?, settings: RomSettings::default() });
+}
+
+/**
+ * Programs of a zip archive with their path inside it, the given one or all the files that are valid programs
+ */
+pub fn archive_roms(file: &str, bytes: &[u8], member: Option<&str>) -> Result<Vec<(String, RomFile)>, String> {
+    let entries = zip_entries(bytes).map_err(|e| format!("{} : {}", file, e))?;
+    if let Some(name) = member {
+        let entry = find_zip_entry(&entries, name).ok_or_else(|| format!("{} holds no file named {}", file, name))?;
+        let content = zip_extract(bytes, entry).map_err(|e| format!("{} : {}", file, e))?;
+        return Ok(vec![(entry.name.clone(), decode_rom(&format!("{}:{}", file, entry.name), content)?)]);
+    }
+    let mut roms = Vec::new();
+    for index in zip_candidates(&entries, &SIDE_FILE_EXTENSIONS) {
+        let entry = &entries[index];
+        let rom = zip_extract(bytes, entry).and_then(|content| decode_rom(&entry.name, content));
+        if let Ok(rom) = rom {
+            roms.push((entry.name.clone(), rom));
+        }
+    }
+    return Ok(roms);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::octo_cartridge::tests::build_cartridge;
+    use crate::zip_archive::tests::{build_zip, stored_file};
+    use super::*;
+
+    fn pack() -> Vec<u8> {
+        return build_zip(&[
+            stored_file("games/", &[]),
+            stored_file("games/BRIX", &[0x12, 0x00]),
+            stored_file("games/PONG", &[0x13, 0x00]),
+            stored_file("games/PONG.keys", b"5=Space"),
+            stored_file("games/EMPTY", &[]),
+            stored_file("CART.gif", &build_cartridge(r#"{"program": ": main jump main", "options": {"tickrate": 20}}"#, 1)),
+        ]);
+    }
+
+    #[test]
+    fn archive_member_by_name() {
+        let roms = archive_roms("pack.zip", &pack(), Some("pong")).unwrap();
+        assert_eq!(roms.len(), 1);
+        assert_eq!(roms[0].0, "games/PONG");
+        assert_eq!(roms[0].1.bytes, vec![0x13, 0x00]);
+
+        let roms = archive_roms("pack.zip", &pack(), Some("cart.GIF")).unwrap();
+        assert_eq!(roms[0].1.bytes, vec![0x12, 0x00]);
+        assert_eq!(roms[0].1.settings.instructions_per_frame, Some(30));
+
+        assert_eq!(archive_roms("pack.zip", &pack(), Some("TETRIS")).err(), Some("pack.zip holds no file named TETRIS".to_string()));
+        assert_eq!(archive_roms("pack.zip", &pack(), Some("EMPTY")).err(), Some("pack.zip:games/EMPTY is empty".to_string()));
+    }
+
+    #[test]
+    fn archive_programs() {
+        // Directories, side files and files that are not programs are left out
+        let names: Vec<String> = archive_roms("pack.zip", &pack(), None).unwrap().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["games/BRIX", "games/PONG", "CART.gif"]);
+        assert_eq!(archive_roms("pack.zip", &pack()[..100], None).err(), Some("pack.zip : the zip archive is damaged".to_string()));
+    }
+
+    #[test]
+    fn cartridges_are_assembled() {
+        let rom = decode_rom("CART.gif", build_cartridge(r#"{"program": ": main\n  v1 := 2\n  jump main"}"#, 2)).unwrap();
+        assert_eq!(rom.bytes, vec![0x61, 0x02, 0x12, 0x00]);
+        assert_eq!(decode_rom("BAD.gif", build_cartridge(r#"{"program": ": start\n  jump start"}"#, 1)).err(),
+                   Some("BAD.gif : the program has no ': main' label".to_string()));
+        assert_eq!(decode_rom("BRIX", vec![0x12, 0x00]).unwrap().bytes, vec![0x12, 0x00]);
+    }
+}
@@ -0,0 +1,297 @@
+/*!
+ * Patches applied to a ROM when it is loaded, for the fixes and translations shared as IPS or BPS files
+ *
+ * IPS patches only list the bytes to write. BPS patches end with the CRC-32 of the original ROM, of the patched ROM and
+ * of the patch itself, all three are checked.
+ */
+
+#![allow(clippy::needless_return)]
+
+use crate::image_export::crc32;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_END: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+// CRC-32 of the original ROM, of the patched ROM and of the patch, ending a BPS patch
+const BPS_FOOTER_SIZE: usize = 12;
+
+/**
+ * Apply patch files in order to a ROM
+ */
+pub fn patch_rom(rom: Vec<u8>, patches: &[String]) -> Result<Vec<u8>, String> {
+    let mut rom = rom;
+    for path in patches {
+        let patch = std::fs::read(path).map_err(|e| format!("cannot read the patch {} ({})", path, e))?;
+        rom = apply_patch(&rom, &patch).map_err(|e| format!("{} : {}", path, e))?;
+    }
+    return Ok(rom);
+}
+
+/**
+ * Apply an IPS or a BPS patch to a ROM, the format is told by the start of the patch
+ */
+pub fn apply_patch(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.starts_with(IPS_MAGIC) {
+        return apply_ips(rom, patch);
+    }
+    if patch.starts_with(BPS_MAGIC) {
+        return apply_bps(rom, patch);
+    }
+    return Err("not an IPS nor a BPS patch".to_string());
+}
+
+fn read_be(patch: &[u8], offset: usize, size: usize) -> Result<usize, String> {
+    let field = patch.get(offset..offset + size).ok_or("the patch is truncated")?;
+    return Ok(field.iter().fold(0, |value, byte| value << 8 | *byte as usize));
+}
+
+/**
+ * IPS : records of a 24 bits offset and a 16 bits size followed by the bytes, or by a count and a byte to repeat when
+ * the size is 0, up to "EOF" and an optional 24 bits size to truncate the ROM to
+ */
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = rom.to_vec();
+    let mut position = IPS_MAGIC.len();
+    loop {
+        if patch.get(position..position + IPS_END.len()) == Some(IPS_END) {
+            position += IPS_END.len();
+            break;
+        }
+        let offset = read_be(patch, position, 3)?;
+        let size = read_be(patch, position + 3, 2)?;
+        position += 5;
+        let bytes = if size > 0 {
+            let bytes = patch.get(position..position + size).ok_or("the patch is truncated")?.to_vec();
+            position += size;
+            bytes
+        } else {
+            let count = read_be(patch, position, 2)?;
+            let value = read_be(patch, position + 2, 1)? as u8;
+            position += 3;
+            vec![value; count]
+        };
+        // Records can write past the end of the ROM to make it longer
+        if out.len() < offset + bytes.len() {
+            out.resize(offset + bytes.len(), 0);
+        }
+        out[offset..offset + bytes.len()].copy_from_slice(&bytes);
+    }
+    if patch.len() >= position + 3 {
+        out.truncate(read_be(patch, position, 3)?);
+    }
+    return Ok(out);
+}
+
+/**
+ * Variable length number of BPS patches, 7 bits per byte with the last byte flagged by its high bit
+ */
+fn read_number(patch: &[u8], position: &mut usize) -> Result<usize, String> {
+    let mut value: usize = 0;
+    let mut shift: usize = 1;
+    loop {
+        let byte = *patch.get(*position).ok_or("the patch is truncated")? as usize;
+        *position += 1;
+        value = value.checked_add((byte & 0x7F).checked_mul(shift).ok_or("the patch is damaged")?).ok_or("the patch is damaged")?;
+        if byte & 0x80 != 0 {
+            return Ok(value);
+        }
+        shift = shift.checked_shl(7).filter(|shift| *shift <= 1 << 56).ok_or("the patch is damaged")?;
+        value += shift;
+    }
+}
+
+/**
+ * Move a copy offset by a signed number of BPS patches, the low bit giving the sign
+ */
+fn move_offset(offset: usize, patch: &[u8], position: &mut usize) -> Result<usize, String> {
+    let number = read_number(patch, position)?;
+    let moved = if number & 1 == 1 { offset.checked_sub(number >> 1) } else { offset.checked_add(number >> 1) };
+    return moved.ok_or_else(|| "the patch is damaged".to_string());
+}
+
+fn read_le32(patch: &[u8], offset: usize) -> u32 {
+    return u32::from_le_bytes([patch[offset], patch[offset + 1], patch[offset + 2], patch[offset + 3]]);
+}
+
+/**
+ * BPS : the sizes of the ROMs and the metadata, then actions copying bytes of the original ROM, of the patch or of
+ * the patched ROM already written, each one reading from its own offset
+ */
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.len() < BPS_MAGIC.len() + BPS_FOOTER_SIZE {
+        return Err("the patch is truncated".to_string());
+    }
+    let actions_end = patch.len() - BPS_FOOTER_SIZE;
+    if crc32(&patch[..patch.len() - 4]) != read_le32(patch, patch.len() - 4) {
+        return Err("the patch fails its CRC check".to_string());
+    }
+    let mut position = BPS_MAGIC.len();
+    let source_size = read_number(patch, &mut position)?;
+    let target_size = read_number(patch, &mut position)?;
+    let metadata_size = read_number(patch, &mut position)?;
+    position = position.checked_add(metadata_size).ok_or("the patch is damaged")?;
+    if source_size != rom.len() || crc32(rom) != read_le32(patch, actions_end) {
+        return Err("the patch was made for another ROM".to_string());
+    }
+
+    let damaged = || "the patch is damaged".to_string();
+    let mut out = Vec::with_capacity(target_size.min(actions_end));
+    let mut source_offset = 0;
+    let mut target_offset = 0;
+    while position < actions_end {
+        let action = read_number(patch, &mut position)?;
+        let length = (action >> 2) + 1;
+        if out.len() + length > target_size {
+            return Err(damaged());
+        }
+        match action & 3 {
+            // Bytes of the original ROM at the same place
+            0 => out.extend_from_slice(rom.get(out.len()..out.len() + length).ok_or_else(damaged)?),
+            // Bytes stored in the patch
+            1 => {
+                out.extend_from_slice(patch.get(position..position + length).filter(|_| position + length <= actions_end).ok_or_else(damaged)?);
+                position += length;
+            }
+            // Bytes of the original ROM from anywhere
+            2 => {
+                source_offset = move_offset(source_offset, patch, &mut position)?;
+                out.extend_from_slice(rom.get(source_offset..source_offset + length).ok_or_else(damaged)?);
+                source_offset += length;
+            }
+            // Bytes already written, the copy can overlap the bytes it produces
+            _ => {
+                target_offset = move_offset(target_offset, patch, &mut position)?;
+                if target_offset >= out.len() {
+                    return Err(damaged());
+                }
+                for _ in 0..length {
+                    out.push(out[target_offset]);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if out.len() != target_size || crc32(&out) != read_le32(patch, actions_end + 4) {
+        return Err("the patched ROM fails its CRC check".to_string());
+    }
+    return Ok(out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_number(out: &mut Vec<u8>, value: usize) {
+        let mut value = value;
+        loop {
+            let low = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(low | 0x80);
+                return;
+            }
+            out.push(low);
+            value -= 1;
+        }
+    }
+
+    /**
+     * Action of a BPS patch : its kind, its length, and for the copies the move of their offset
+     */
+    fn push_action(out: &mut Vec<u8>, kind: usize, length: usize, offset_move: Option<isize>) {
+        push_number(out, ((length - 1) << 2) | kind);
+        if let Some(offset_move) = offset_move {
+            push_number(out, (offset_move.unsigned_abs() << 1) | (offset_move < 0) as usize);
+        }
+    }
+
+    /**
+     * BPS patch of the actions, the sizes and the CRC-32 are the ones of these source and target
+     */
+    fn build_bps(source: &[u8], target: &[u8], actions: &[u8]) -> Vec<u8> {
+        let mut patch = BPS_MAGIC.to_vec();
+        push_number(&mut patch, source.len());
+        push_number(&mut patch, target.len());
+        push_number(&mut patch, 0);
+        patch.extend_from_slice(actions);
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        return patch;
+    }
+
+    #[test]
+    fn ips_records() {
+        let patch = b"PATCH\x00\x00\x02\x00\x02\xAA\xBB\x00\x00\x05\x00\x00\x00\x03\xCCEOF";
+        assert_eq!(apply_patch(&[0; 8], patch), Ok(vec![0, 0, 0xAA, 0xBB, 0, 0xCC, 0xCC, 0xCC]));
+        // Records past the end make the ROM longer
+        let patch = b"PATCH\x00\x00\x06\x00\x00\x00\x04\x11EOF";
+        assert_eq!(apply_patch(&[1; 4], patch), Ok(vec![1, 1, 1, 1, 0, 0, 0x11, 0x11, 0x11, 0x11]));
+    }
+
+    #[test]
+    fn ips_truncation_extension() {
+        let patch = b"PATCH\x00\x00\x01\x00\x01\x22EOF\x00\x00\x03";
+        assert_eq!(apply_patch(&[0, 1, 2, 3, 4, 5], patch), Ok(vec![0, 0x22, 2]));
+    }
+
+    #[test]
+    fn ips_errors() {
+        assert_eq!(apply_patch(&[0; 4], b"PATCH\x00\x00"), Err("the patch is truncated".to_string()));
+        assert_eq!(apply_patch(&[0; 4], b"PATCH\x00\x00\x01\x00\x04\x01\x02EOF"), Err("the patch is truncated".to_string()));
+        assert_eq!(apply_patch(&[0; 4], b"PATCH\x00\x00\x01\x00\x00\x00"), Err("the patch is truncated".to_string()));
+        assert_eq!(apply_patch(&[0; 4], b"PBTCH"), Err("not an IPS nor a BPS patch".to_string()));
+    }
+
+    #[test]
+    fn bps_overlapping_target_copy() {
+        let mut actions = Vec::new();
+        // "AB" from the source, then 6 bytes copied from the start of the target while they are written
+        push_action(&mut actions, 0, 2, None);
+        push_action(&mut actions, 3, 6, Some(0));
+        assert_eq!(apply_patch(b"AB", &build_bps(b"AB", b"ABABABAB", &actions)), Ok(b"ABABABAB".to_vec()));
+    }
+
+    #[test]
+    fn bps_source_copy_and_target_read() {
+        let mut actions = Vec::new();
+        push_action(&mut actions, 2, 3, Some(2));
+        push_action(&mut actions, 1, 1, None);
+        actions.push(b'x');
+        // Back to the start of the source, after reading 3 bytes from offset 2
+        push_action(&mut actions, 2, 2, Some(-5));
+        assert_eq!(apply_patch(b"HELLO", &build_bps(b"HELLO", b"LLOxHE", &actions)), Ok(b"LLOxHE".to_vec()));
+    }
+
+    #[test]
+    fn bps_crc_checks() {
+        let mut actions = Vec::new();
+        push_action(&mut actions, 0, 2, None);
+        push_action(&mut actions, 3, 6, Some(0));
+        let patch = build_bps(b"AB", b"ABABABAB", &actions);
+
+        let mut damaged = patch.clone();
+        damaged[BPS_MAGIC.len() + 4] ^= 1;
+        assert_eq!(apply_patch(b"AB", &damaged), Err("the patch fails its CRC check".to_string()));
+
+        assert_eq!(apply_patch(b"AC", &patch), Err("the patch was made for another ROM".to_string()));
+        assert_eq!(apply_patch(b"ABC", &patch), Err("the patch was made for another ROM".to_string()));
+
+        let wrong_target = build_bps(b"AB", b"ABABABAC", &actions);
+        assert_eq!(apply_patch(b"AB", &wrong_target), Err("the patched ROM fails its CRC check".to_string()));
+    }
+
+    #[test]
+    fn bps_errors() {
+        assert_eq!(apply_patch(b"AB", b"BPS1\x82"), Err("the patch is truncated".to_string()));
+        // A copy from the target before anything is written
+        let mut actions = Vec::new();
+        push_action(&mut actions, 3, 2, Some(0));
+        assert_eq!(apply_patch(b"AB", &build_bps(b"AB", b"AB", &actions)), Err("the patch is damaged".to_string()));
+        // More bytes than the target holds
+        let mut actions = Vec::new();
+        push_action(&mut actions, 0, 2, None);
+        assert_eq!(apply_patch(b"AB", &build_bps(b"AB", b"A", &actions)), Err("the patch is damaged".to_string()));
+    }
+}
@@ -0,0 +1,211 @@
+#![allow(clippy::needless_return)]
+
+use crate::chip8::{Chip8, KEYBOARD_SIZE, NO_KEY, REGISTER_COUNT, STACK_SIZE, build_chip8};
+use crate::chip8_display::DISPLAY_WIDTH;
+
+// Start of every save state file, followed by the format version
+const STATE_MAGIC: &[u8; 4] = b"RC8S";
+const STATE_VERSION: u8 = 1;
+
+// Extension of the save state files
+pub const STATE_EXTENSION: &str = "state";
+
+/**
+ * Machine read from a save state, with the SHA-1 of the ROM it was saved from
+ */
+pub struct SaveState {
+    pub(crate) rom_hash: String,
+    pub(crate) device: Chip8,
+}
+
+/**
+ * Tell whether these bytes are a save state rather than a ROM
+ */
+pub fn is_save_state(bytes: &[u8]) -> bool {
+    return bytes.starts_with(STATE_MAGIC);
+}
+
+/**
+ * File name of a save state of a ROM, in the current directory
+ */
+pub fn state_name(rom_name: &str) -> String {
+    return format!("{}.{}", rom_name, STATE_EXTENSION);
+}
+
+/**
+ * Write the machine state as bytes
+ *
+ * The host side settings (turbo, held keys) and the random generator are not saved, the generator is seeded again when loading.
+ */
+pub fn encode_state(device: &Chip8, rom_hash: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(STATE_MAGIC);
+    out.push(STATE_VERSION);
+    let mut hash = [0u8; 40];
+    for (byte, c) in hash.iter_mut().zip(rom_hash.bytes()) {
+        *byte = c;
+    }
+    out.extend_from_slice(&hash);
+    out.extend_from_slice(&device.memory);
+    out.extend_from_slice(&device.vn);
+    out.push(device.dt);
+    out.push(device.st);
+    out.extend_from_slice(&device.i.to_be_bytes());
+    out.extend_from_slice(&device.pc.to_be_bytes());
+    out.push(device.sp);
+    for address in device.stack.iter() {
+        out.extend_from_slice(&address.to_be_bytes());
+    }
+    out.extend_from_slice(&device.cycle.to_be_bytes());
+    out.extend_from_slice(&device.ticks.to_be_bytes());
+    out.push(if device.key < KEYBOARD_SIZE { device.key as u8 } else { 0xFF });
+    out.push(device.key_wait_start.is_some() as u8);
+    out.extend_from_slice(&device.key_wait_start.unwrap_or(0).to_be_bytes());
+    for row in device.display.display_data.iter() {
+        out.extend_from_slice(row);
+    }
+    let quirks = &device.quirks;
+    out.extend_from_slice(&[quirks.shift_vy as u8, quirks.load_store_increment as u8, quirks.jump_vx as u8, quirks.logic_reset_vf as u8, quirks.clip_sprites as u8]);
+    return out;
+}
+
+/**
+ * Reader of the bytes of a save state, failing on truncated files
+ */
+struct StateReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+fn take<'a>(reader: &mut StateReader<'a>, count: usize) -> Result<&'a [u8], String> {
+    if reader.position + count > reader.bytes.len() {
+        return Err("the save state is truncated".to_string());
+    }
+    let bytes = &reader.bytes[reader.position..reader.position + count];
+    reader.position += count;
+    return Ok(bytes);
+}
+
+fn take_u8(reader: &mut StateReader) -> Result<u8, String> {
+    return Ok(take(reader, 1)?[0]);
+}
+
+fn take_u16(reader: &mut StateReader) -> Result<u16, String> {
+    let bytes = take(reader, 2)?;
+    return Ok(u16::from_be_bytes([bytes[0], bytes[1]]));
+}
+
+fn take_u64(reader: &mut StateReader) -> Result<u64, String> {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(take(reader, 8)?);
+    return Ok(u64::from_be_bytes(value));
+}
+
+/**
+ * Read a machine from the bytes of a save state
+ */
+pub fn decode_state(bytes: &[u8]) -> Result<SaveState, String> {
+    if !is_save_state(bytes) {
+        return Err("not a save state".to_string());
+    }
+    let mut reader = StateReader { bytes, position: STATE_MAGIC.len() };
+    let version = take_u8(&mut reader)?;
+    if version != STATE_VERSION {
+        return Err(format!("save state version {} is not supported", version));
+    }
+    let rom_hash = String::from_utf8_lossy(take(&mut reader, 40)?).trim_end_matches('\0').to_string();
+    let mut device = build_chip8();
+    let memory_size = device.memory.len();
+    device.memory.copy_from_slice(take(&mut reader, memory_size)?);
+    device.vn.copy_from_slice(take(&mut reader, REGISTER_COUNT)?);
+    device.dt = take_u8(&mut reader)?;
+    device.st = take_u8(&mut reader)?;
+    device.i = take_u16(&mut reader)?;
+    device.pc = take_u16(&mut reader)?;
+    device.sp = take_u8(&mut reader)?;
+    for address in device.stack.iter_mut() {
+        *address = take_u16(&mut reader)?;
+    }
+    device.cycle = take_u16(&mut reader)?;
+    device.ticks = take_u64(&mut reader)?;
+    let key = take_u8(&mut reader)? as usize;
+    device.key = if key < KEYBOARD_SIZE { key } else { NO_KEY };
+    let waiting = take_u8(&mut reader)? != 0;
+    let wait_start = take_u64(&mut reader)?;
+    device.key_wait_start = if waiting { Some(wait_start) } else { None };
+    for row in device.display.display_data.iter_mut() {
+        row.copy_from_slice(take(&mut reader, DISPLAY_WIDTH / 8)?);
+    }
+    let quirks = take(&mut reader, 5)?;
+    device.quirks.shift_vy = quirks[0] != 0;
+    device.quirks.load_store_increment = quirks[1] != 0;
+    device.quirks.jump_vx = quirks[2] != 0;
+    device.quirks.logic_reset_vf = quirks[3] != 0;
+    device.quirks.clip_sprites = quirks[4] != 0;
+    if device.sp as usize > STACK_SIZE || device.pc as usize + 1 >= memory_size {
+        return Err("the save state is damaged".to_string());
+    }
+    return Ok(SaveState { rom_hash, device });
+}
+
+pub fn save_state_file(path: &str, device: &Chip8, rom_hash: &str) -> Result<(), String> {
+    return std::fs::write(path, encode_state(device, rom_hash)).map_err(|e| format!("cannot write the save state {} ({})", path, e));
+}
+
+pub fn load_state_file(path: &str) -> Result<SaveState, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("cannot read the save state {} ({})", path, e))?;
+    return decode_state(&bytes).map_err(|e| format!("{} : {}", path, e));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chip8::{load_rom, step};
+    use super::*;
+
+    const HASH: &str = "b232ef880bd6060fb45fa6effed7edf0ae95670e";
+
+    fn running_chip8() -> Chip8 {
+        let mut device = build_chip8();
+        load_rom(&mut device, include_bytes!("../resources/BRIX"));
+        device.quirks.jump_vx = true;
+        for _ in 0..2000 {
+            step(&mut device);
+        }
+        return device;
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let device = running_chip8();
+        let bytes = encode_state(&device, HASH);
+        assert!(is_save_state(&bytes));
+        let state = decode_state(&bytes).unwrap();
+        assert_eq!(state.rom_hash, HASH);
+        assert_eq!(state.device.pc, device.pc);
+        assert_eq!(state.device.ticks, device.ticks);
+        assert_eq!(state.device.vn, device.vn);
+        assert_eq!(state.device.quirks, device.quirks);
+        assert_eq!(state.device.display.display_data, device.display.display_data);
+        assert_eq!(encode_state(&state.device, HASH), bytes);
+    }
+
+    #[test]
+    fn state_errors() {
+        let bytes = encode_state(&running_chip8(), HASH);
+        for size in 5..bytes.len() {
+            assert_eq!(decode_state(&bytes[..size]).err(), Some("the save state is truncated".to_string()));
+        }
+        assert_eq!(decode_state(b"RC8").err(), Some("not a save state".to_string()));
+
+        let mut other = bytes.clone();
+        other[0] = b'X';
+        assert_eq!(decode_state(&other).err(), Some("not a save state".to_string()));
+        let mut other = bytes.clone();
+        other[4] = STATE_VERSION + 1;
+        assert_eq!(decode_state(&other).err(), Some(format!("save state version {} is not supported", STATE_VERSION + 1)));
+        let mut other = bytes;
+        other[4..].iter_mut().for_each(|byte| *byte = 0xFF);
+        other[4] = STATE_VERSION;
+        assert_eq!(decode_state(&other).err(), Some("the save state is damaged".to_string()));
+    }
+}
@@ -0,0 +1,431 @@
+#![allow(clippy::needless_return)]
+
+use std::ffi::CStr;
+
+use fermium::{
+    *,
+    events::*, prelude::*,
+};
+use rodio::{OutputStream, Sink};
+use rodio::source::{SineWave, Source};
+
+use crate::chip8_display::{Chip8Display, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::chip8_keyboard_utils::{action_event, auto_repeats, on_ctrl_key, on_keyboard_event, on_menu_key, pad_axis_name, pad_button_name};
+use crate::frontend::{AudioSink, InputEvent, InputSource, VideoCommand, VideoSink};
+use crate::image_export::save_screenshot;
+use crate::keymap::{Keymap, keymap_action};
+use crate::osd::{FONT_HEIGHT, FONT_WIDTH, Osd, glyph, osd_lines, osd_message};
+use crate::palette::{Palette, intensity_color, next_palette, palette_color};
+use crate::phosphor::{Phosphor, phosphor_intensity, phosphor_update};
+
+// Colour of the borders around the display when the window aspect ratio differs (ARGB)
+const LETTERBOX: u32 = 0xFF000000;
+
+// Window size in window pixels per Chip 8 pixel
+pub const DEFAULT_SCALE: i32 = 10;
+pub const MAX_SCALE: i32 = 40;
+
+/**
+ * SDL window showing the Chip 8 display
+ *
+ * The display is copied to a streaming texture once per frame, then scaled to the window by the renderer.
+ * Creating it initializes SDL, dropping it closes the window and shuts SDL down.
+ */
+pub struct SdlVideo {
+    window: *mut SDL_Window,
+    renderer: *mut SDL_Renderer,
+    texture: *mut SDL_Texture,
+    // Texture content, one ARGB value per Chip 8 pixel
+    pixels: Vec<u32>,
+    // Draw the pixel grid over the display
+    grid: bool,
+    palette: Palette,
+    phosphor: Phosphor,
+    // Window pixels per Chip 8 pixel, when windowed
+    scale: i32,
+    fullscreen: bool,
+    // Only scale the display by whole numbers, leaving wider borders
+    integer_scale: bool,
+    osd: Osd,
+}
+
+// Stick position past which a direction is held, out of 32767
+const AXIS_DEAD_ZONE: i16 = 16000;
+
+/**
+ * SDL keyboard and game controller events
+ */
+pub struct SdlInput {
+    event: SDL_Event,
+    keymap: Keymap,
+    // Opened game controllers, by joystick instance id
+    controllers: Vec<(SDL_JoystickID, *mut SDL_GameController)>,
+    // Controller inputs held down, by joystick instance id, several controllers can hold the same Chip 8 key
+    pad_held: Vec<(SDL_JoystickID, String)>,
+}
+
+/**
+ * Rodio output playing a 500 Hz tone for the beeper
+ */
+pub struct RodioAudio {
+    // The stream must be kept alive while the sink plays
+    _stream: OutputStream,
+    sink: Sink,
+}
+
+pub fn build_sdl_video(palette: Palette, phosphor: Phosphor, grid: bool, scale: i32, fullscreen: bool, integer_scale: bool, osd: Osd) -> SdlVideo {
+    let scale = scale.clamp(1, MAX_SCALE);
+    unsafe {
+        assert_eq!(SDL_Init(SDL_INIT_EVERYTHING), 0);
+        let window = SDL_CreateWindow(
+            b"Khopa's Rusty Chip 8 Emulator\0".as_ptr().cast(),
+            SDL_WINDOWPOS_CENTERED,
+            SDL_WINDOWPOS_CENTERED,
+            DISPLAY_WIDTH as i32 * scale,
+            DISPLAY_HEIGHT as i32 * scale,
+            (SDL_WINDOW_OPENGL | SDL_WINDOW_ALLOW_HIGHDPI | SDL_WINDOW_RESIZABLE).0,
+        );
+        // Panic if window is not null
+        assert!(!window.is_null());
+        SDL_SetWindowMinimumSize(window, DISPLAY_WIDTH as i32, DISPLAY_HEIGHT as i32);
+        if fullscreen {
+            SDL_SetWindowFullscreen(window, SDL_WINDOW_FULLSCREEN_DESKTOP.0);
+        }
+
+        let renderer = SDL_CreateRenderer(window, -1, 1);
+        // Panic if renderer is not null
+        assert!(!renderer.is_null());
+        SDL_SetRenderDrawColor(renderer, 0, 0, 255, 255);
+
+        let texture = SDL_CreateTexture(
+            renderer,
+            SDL_PIXELFORMAT_ARGB8888.0,
+            SDL_TEXTUREACCESS_STREAMING.0,
+            DISPLAY_WIDTH as i32,
+            DISPLAY_HEIGHT as i32,
+        );
+        // Panic if texture is not null
+        assert!(!texture.is_null());
+        let pixels = vec![palette_color(&palette, 0); DISPLAY_WIDTH * DISPLAY_HEIGHT];
+        return SdlVideo { window, renderer, texture, pixels, grid, palette, phosphor, scale, fullscreen, integer_scale, osd };
+    }
+}
+
+pub fn build_sdl_input(keymap: Keymap) -> SdlInput {
+    return SdlInput { event: SDL_Event::default(), keymap, controllers: Vec::new(), pad_held: Vec::new() };
+}
+
+pub fn build_rodio_audio(tone: f32, volume: f32) -> RodioAudio {
+    let (stream, stream_handle) = OutputStream::try_default().unwrap();
+    let sink = Sink::try_new(&stream_handle).unwrap();
+    let source = SineWave::new(tone).amplify(volume);
+    sink.append(source);
+    sink.pause();
+    return RodioAudio { _stream: stream, sink };
+}
+
+unsafe fn set_draw_color(renderer: *mut SDL_Renderer, argb: u32) {
+    SDL_SetRenderDrawColor(renderer, (argb >> 16) as u8, (argb >> 8) as u8, argb as u8, (argb >> 24) as u8);
+}
+
+/**
+ * Area of the output where the display is drawn, as large as possible while keeping its aspect ratio
+ *
+ * The rest of the output is left as borders (letterboxing).
+ */
+fn display_rect(output_w: i32, output_h: i32, integer_scale: bool) -> SDL_Rect {
+    let width = DISPLAY_WIDTH as i32;
+    let height = DISPLAY_HEIGHT as i32;
+    let (w, h) = if integer_scale {
+        let scale = (output_w / width).min(output_h / height).max(1);
+        (width * scale, height * scale)
+    } else if output_w * height > output_h * width {
+        (output_h * width / height, output_h)
+    } else {
+        (output_w, output_w * height / width)
+    };
+    return SDL_Rect { x: (output_w - w) / 2, y: (output_h - h) / 2, w, h };
+}
+
+/**
+ * Draw the borders of every Chip 8 pixel, over the scaled display
+ *
+ * Neighbour pixels share the line between them. Below 3 screen pixels per Chip 8 pixel the lines would hide the display,
+ * so no grid is drawn.
+ */
+unsafe fn render_grid(renderer: *mut SDL_Renderer, rect: &SDL_Rect, color: u32) {
+    let width = DISPLAY_WIDTH as i32;
+    let height = DISPLAY_HEIGHT as i32;
+    if rect.w / width < 3 {
+        return;
+    }
+    set_draw_color(renderer, color);
+    let right = rect.x + rect.w - 1;
+    let bottom = rect.y + rect.h - 1;
+    for column in 0..=width {
+        let x = (rect.x + column * rect.w / width).min(right);
+        SDL_RenderDrawLine(renderer, x, rect.y, x, bottom);
+    }
+    for row in 0..=height {
+        let y = (rect.y + row * rect.h / height).min(bottom);
+        SDL_RenderDrawLine(renderer, rect.x, y, right, y);
+    }
+}
+
+/**
+ * Draw the on-screen display lines in the top left corner, white on a translucent black box
+ */
+unsafe fn render_osd(renderer: *mut SDL_Renderer, lines: &[String], output_h: i32) {
+    // Size of a font pixel, text stays readable when the window grows
+    let unit = (output_h / 160).max(1);
+    let line_height = (FONT_HEIGHT as i32 + 2) * unit;
+    let advance = (FONT_WIDTH as i32 + 1) * unit;
+    let mut boxes = Vec::new();
+    let mut text = Vec::new();
+    for (row, line) in lines.iter().enumerate() {
+        let top = unit * 2 + row as i32 * line_height;
+        let left = unit * 2;
+        boxes.push(SDL_Rect { x: left - unit, y: top - unit, w: line.chars().count() as i32 * advance + unit, h: line_height });
+        for (column, c) in line.chars().enumerate() {
+            let rows = glyph(c);
+            for (dy, bits) in rows.iter().enumerate() {
+                for dx in 0..FONT_WIDTH {
+                    if bits & (0b100 >> dx) != 0 {
+                        let x = left + column as i32 * advance + dx as i32 * unit;
+                        text.push(SDL_Rect { x, y: top + dy as i32 * unit, w: unit, h: unit });
+                    }
+                }
+            }
+        }
+    }
+    SDL_SetRenderDrawBlendMode(renderer, SDL_BLENDMODE_BLEND);
+    SDL_SetRenderDrawColor(renderer, 0, 0, 0, 160);
+    SDL_RenderFillRects(renderer, boxes.as_ptr(), boxes.len() as i32);
+    SDL_SetRenderDrawColor(renderer, 255, 255, 255, 255);
+    SDL_RenderFillRects(renderer, text.as_ptr(), text.len() as i32);
+    SDL_SetRenderDrawBlendMode(renderer, SDL_BLENDMODE_NONE);
+}
+
+impl VideoSink for SdlVideo {
+    fn present(&mut self, display: &Chip8Display) {
+        phosphor_update(&mut self.phosphor, display);
+        for y in 0..DISPLAY_HEIGHT {
+            for x in 0..DISPLAY_WIDTH {
+                self.pixels[y * DISPLAY_WIDTH + x] = intensity_color(&self.palette, phosphor_intensity(&self.phosphor, x, y));
+            }
+        }
+        unsafe {
+            SDL_UpdateTexture(self.texture, std::ptr::null(), self.pixels.as_ptr().cast(), (DISPLAY_WIDTH * 4) as i32);
+            let mut output_w = 0;
+            let mut output_h = 0;
+            SDL_GetRendererOutputSize(self.renderer, &mut output_w, &mut output_h);
+            let rect = display_rect(output_w, output_h, self.integer_scale);
+            set_draw_color(self.renderer, LETTERBOX);
+            SDL_RenderClear(self.renderer);
+            SDL_RenderCopy(self.renderer, self.texture, std::ptr::null(), &rect);
+            if self.grid {
+                render_grid(self.renderer, &rect, self.palette.grid);
+            }
+            let lines = osd_lines(&mut self.osd);
+            if !lines.is_empty() {
+                render_osd(self.renderer, &lines, output_h);
+            }
+            SDL_RenderPresent(self.renderer);
+            SDL_Delay(25); // 40 FPS cap
+        }
+    }
+
+    fn set_palette(&mut self, palette: &Palette) {
+        self.palette = palette.clone();
+    }
+
+    fn present_menu(&mut self, lines: &[String]) {
+        let mut lines = lines.to_vec();
+        lines.extend(osd_lines(&mut self.osd));
+        unsafe {
+            let mut output_w = 0;
+            let mut output_h = 0;
+            SDL_GetRendererOutputSize(self.renderer, &mut output_w, &mut output_h);
+            set_draw_color(self.renderer, self.palette.colors[0]);
+            SDL_RenderClear(self.renderer);
+            render_osd(self.renderer, &lines, output_h);
+            SDL_RenderPresent(self.renderer);
+            SDL_Delay(25);
+        }
+    }
+
+    fn show_message(&mut self, text: &str) {
+        // The console keeps a log of the messages shown over the display
+        println!("{}", text);
+        osd_message(&mut self.osd, text);
+    }
+
+    fn set_stats(&mut self, stats: &str) {
+        self.osd.stats = stats.to_string();
+    }
+
+    fn set_status(&mut self, status: &str) {
+        let title = format!("Khopa's Rusty Chip 8 Emulator - {}\0", status.replace('\0', ""));
+        unsafe { SDL_SetWindowTitle(self.window, title.as_ptr().cast()) };
+    }
+
+    fn screenshot(&mut self, path: &str, scale: usize) -> Result<(), String> {
+        return save_screenshot(path, &self.phosphor, &self.palette, scale);
+    }
+
+    fn command(&mut self, command: VideoCommand) {
+        unsafe {
+            match command {
+                VideoCommand::ToggleFullscreen => {
+                    self.fullscreen = !self.fullscreen;
+                    let flags = if self.fullscreen { SDL_WINDOW_FULLSCREEN_DESKTOP.0 } else { 0 };
+                    SDL_SetWindowFullscreen(self.window, flags);
+                }
+                VideoCommand::ToggleIntegerScale => self.integer_scale = !self.integer_scale,
+                VideoCommand::NextTheme => {
+                    self.palette = next_palette(&self.palette);
+                    osd_message(&mut self.osd, &format!("Theme {}", self.palette.name));
+                }
+                VideoCommand::ToggleStats => self.osd.show_stats = !self.osd.show_stats,
+                VideoCommand::Zoom(steps) => {
+                    if !self.fullscreen {
+                        self.scale = (self.scale + steps).clamp(1, MAX_SCALE);
+                        SDL_SetWindowSize(self.window, DISPLAY_WIDTH as i32 * self.scale, DISPLAY_HEIGHT as i32 * self.scale);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for SdlVideo {
+    fn drop(&mut self) {
+        unsafe {
+            SDL_DestroyTexture(self.texture);
+            SDL_DestroyWindow(self.window);
+            SDL_Quit();
+        }
+    }
+}
+
+/**
+ * Press or release a controller input, the bound action is released when no controller holds it anymore
+ */
+fn on_pad_input(input: &mut SdlInput, controller: SDL_JoystickID, name: String, down: bool, events: &mut Vec<InputEvent>) {
+    let action = match keymap_action(&input.keymap, &name) {
+        Some(action) => action,
+        None => return,
+    };
+    let keymap = &input.keymap;
+    let is_held = |held: &Vec<(SDL_JoystickID, String)>| held.iter().any(|(_, host)| keymap_action(keymap, host) == Some(action));
+    let was_held = is_held(&input.pad_held);
+    let entry = (controller, name);
+    if !down {
+        input.pad_held.retain(|held| *held != entry);
+    } else if !input.pad_held.contains(&entry) {
+        input.pad_held.push(entry);
+    }
+    let now_held = is_held(&input.pad_held);
+    if was_held != now_held {
+        events.push(action_event(action, now_held));
+    }
+}
+
+/**
+ * Turn a stick or trigger position into its two directions pressed or released
+ */
+fn on_pad_axis(input: &mut SdlInput, controller: SDL_JoystickID, axis: u8, value: i16, events: &mut Vec<InputEvent>) {
+    on_pad_input(input, controller, pad_axis_name(axis, false), value < -AXIS_DEAD_ZONE, events);
+    on_pad_input(input, controller, pad_axis_name(axis, true), value > AXIS_DEAD_ZONE, events);
+}
+
+/**
+ * Open a controller plugged in, SDL also reports the controllers plugged in before it started
+ */
+unsafe fn open_controller(input: &mut SdlInput, device_index: i32) {
+    let controller = SDL_GameControllerOpen(device_index);
+    if controller.is_null() {
+        return;
+    }
+    let id = SDL_JoystickInstanceID(SDL_GameControllerGetJoystick(controller));
+    if input.controllers.iter().any(|(opened, _)| *opened == id) {
+        SDL_GameControllerClose(controller);
+        return;
+    }
+    input.controllers.push((id, controller));
+    println!("Game controller {} connected", CStr::from_ptr(SDL_GameControllerName(controller)).to_string_lossy());
+}
+
+/**
+ * Close an unplugged controller and release what it held
+ */
+unsafe fn close_controller(input: &mut SdlInput, id: SDL_JoystickID, events: &mut Vec<InputEvent>) {
+    let held: Vec<String> = input.pad_held.iter().filter(|(controller, _)| *controller == id).map(|(_, host)| host.clone()).collect();
+    for host in held {
+        on_pad_input(input, id, host, false, events);
+    }
+    if let Some(position) = input.controllers.iter().position(|(opened, _)| *opened == id) {
+        SDL_GameControllerClose(input.controllers.remove(position).1);
+        println!("Game controller disconnected");
+    }
+}
+
+impl InputSource for SdlInput {
+    fn poll_events(&mut self) -> Vec<InputEvent> {
+        let mut events = Vec::new();
+        unsafe {
+            while SDL_PollEvent(&mut self.event) > 0 {
+                let event = self.event;
+                match event.type_ {
+                    SDL_QUIT => events.push(InputEvent::Quit),
+                    SDL_KEYUP => events.extend(on_keyboard_event(&self.keymap, event.key.keysym.scancode, false)),
+                    // Key repeats, sent while a key is held down
+                    SDL_KEYDOWN if event.key.repeat != 0 && !auto_repeats(event.key.keysym.scancode) => (),
+                    SDL_KEYDOWN => {
+                        let ctrl = event.key.keysym.mod_ & KMOD_CTRL.0 as u16 != 0;
+                        match on_ctrl_key(event.key.keysym.scancode).filter(|_| ctrl) {
+                            Some(hotkey) => events.push(hotkey),
+                            None => events.extend(on_keyboard_event(&self.keymap, event.key.keysym.scancode, true)),
+                        }
+                        events.extend(on_menu_key(event.key.keysym.scancode));
+                    }
+                    SDL_CONTROLLERDEVICEADDED => open_controller(self, event.cdevice.which),
+                    SDL_CONTROLLERDEVICEREMOVED => close_controller(self, SDL_JoystickID(event.cdevice.which), &mut events),
+                    SDL_CONTROLLERBUTTONDOWN | SDL_CONTROLLERBUTTONUP => {
+                        let down = event.type_ == SDL_CONTROLLERBUTTONDOWN;
+                        on_pad_input(self, event.cbutton.which, pad_button_name(event.cbutton.button), down, &mut events);
+                    }
+                    SDL_CONTROLLERAXISMOTION => on_pad_axis(self, event.caxis.which, event.caxis.axis, event.caxis.value, &mut events),
+                    SDL_DROPFILE if !event.drop.file.is_null() => {
+                        events.push(InputEvent::Open(CStr::from_ptr(event.drop.file).to_string_lossy().to_string()));
+                        SDL_free(event.drop.file as *mut c_void);
+                    }
+                    _ => (),
+                }
+            }
+        }
+        return events;
+    }
+
+    fn set_keymap(&mut self, keymap: Keymap) {
+        self.keymap = keymap;
+    }
+}
+
+impl Drop for SdlInput {
+    fn drop(&mut self) {
+        for (_, controller) in &self.controllers {
+            unsafe { SDL_GameControllerClose(*controller) };
+        }
+    }
+}
+
+impl AudioSink for RodioAudio {
+    fn set_beep(&mut self, on: bool) {
+        if on {
+            self.sink.play();
+        } else {
+            self.sink.pause();
+        }
+    }
+}
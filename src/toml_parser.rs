@@ -0,0 +1,383 @@
+/*!
+ * Reader for the subset of TOML used by the settings files
+ *
+ * Supported : `[table]` headers with bare, quoted and dotted names, `key = value` pairs, basic and literal strings,
+ * integers (decimal and 0x hexadecimal), floats, booleans, arrays (over several lines too) and `#` comments.
+ * Not supported : inline tables, arrays of tables, multi-line strings and dates.
+ */
+
+#![allow(clippy::needless_return)]
+
+/**
+ * Value of a key
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum TomlValue {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<TomlValue>),
+}
+
+/**
+ * Key and value, with the line they were written on for the error messages
+ */
+#[derive(Debug, Clone)]
+pub struct TomlEntry {
+    pub(crate) key: String,
+    pub(crate) value: TomlValue,
+    pub(crate) line: usize,
+}
+
+/**
+ * Table and its keys, the keys written before the first header go in a table without name
+ */
+#[derive(Debug, Clone)]
+pub struct TomlTable {
+    // Parts of the dotted name, `[rom."BRIX"]` is ["rom", "BRIX"]
+    pub(crate) name: Vec<String>,
+    pub(crate) line: usize,
+    pub(crate) entries: Vec<TomlEntry>,
+}
+
+struct TomlParser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+}
+
+/**
+ * Parse a document into its tables, in the order they are written
+ */
+pub fn parse_toml(text: &str) -> Result<Vec<TomlTable>, String> {
+    let mut parser = TomlParser { chars: text.chars().peekable(), line: 1 };
+    let mut tables = vec![TomlTable { name: Vec::new(), line: 1, entries: Vec::new() }];
+    loop {
+        skip_blank(&mut parser, true);
+        match parser.chars.peek() {
+            None => break,
+            Some('[') => {
+                parser.chars.next();
+                skip_blank(&mut parser, false);
+                let name = parse_dotted_key(&mut parser)?;
+                skip_blank(&mut parser, false);
+                expect_char(&mut parser, ']')?;
+                if tables.iter().any(|table| table.name == name) {
+                    return Err(format!("line {} : table [{}] is defined twice", parser.line, name.join(".")));
+                }
+                tables.push(TomlTable { name, line: parser.line, entries: Vec::new() });
+            }
+            Some(_) => {
+                let line = parser.line;
+                let key = parse_dotted_key(&mut parser)?.join(".");
+                skip_blank(&mut parser, false);
+                expect_char(&mut parser, '=')?;
+                skip_blank(&mut parser, false);
+                let value = parse_value(&mut parser)?;
+                let table = tables.last_mut().unwrap();
+                if table.entries.iter().any(|entry| entry.key == key) {
+                    return Err(format!("line {} : key '{}' is defined twice", line, key));
+                }
+                table.entries.push(TomlEntry { key, value, line });
+            }
+        }
+        // Nothing but a comment can follow on the line
+        skip_blank(&mut parser, false);
+        match parser.chars.peek() {
+            None | Some('\n') => (),
+            Some(c) => return Err(format!("line {} : unexpected '{}' at the end of the line", parser.line, c)),
+        }
+    }
+    return Ok(tables);
+}
+
+/**
+ * Skip spaces and comments, and the line ends too when asked
+ */
+fn skip_blank(parser: &mut TomlParser, newlines: bool) {
+    while let Some(&c) = parser.chars.peek() {
+        match c {
+            ' ' | '\t' | '\r' => { parser.chars.next(); }
+            '\n' if newlines => {
+                parser.chars.next();
+                parser.line += 1;
+            }
+            '#' => {
+                while matches!(parser.chars.peek(), Some(c) if *c != '\n') {
+                    parser.chars.next();
+                }
+            }
+            _ => break,
+        }
+    }
+}
+
+fn expect_char(parser: &mut TomlParser, expected: char) -> Result<(), String> {
+    return match parser.chars.next() {
+        Some(c) if c == expected => Ok(()),
+        Some(c) => Err(format!("line {} : expected '{}', found '{}'", parser.line, expected, c)),
+        None => Err(format!("line {} : expected '{}', found the end of the file", parser.line, expected)),
+    };
+}
+
+/**
+ * Key made of bare or quoted parts separated by dots
+ */
+fn parse_dotted_key(parser: &mut TomlParser) -> Result<Vec<String>, String> {
+    let mut parts = Vec::new();
+    loop {
+        let part = match parser.chars.peek() {
+            Some('"') | Some('\'') => parse_string(parser)?,
+            _ => {
+                let mut part = String::new();
+                while let Some(&c) = parser.chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                        break;
+                    }
+                    part.push(c);
+                    parser.chars.next();
+                }
+                if part.is_empty() {
+                    return Err(format!("line {} : expected a key", parser.line));
+                }
+                part
+            }
+        };
+        parts.push(part);
+        skip_blank(parser, false);
+        if parser.chars.peek() != Some(&'.') {
+            return Ok(parts);
+        }
+        parser.chars.next();
+        skip_blank(parser, false);
+    }
+}
+
+/**
+ * Basic string with escapes, or literal string between single quotes
+ */
+fn parse_string(parser: &mut TomlParser) -> Result<String, String> {
+    let quote = parser.chars.next().unwrap();
+    let mut text = String::new();
+    loop {
+        match parser.chars.next() {
+            None | Some('\n') => return Err(format!("line {} : unterminated string", parser.line)),
+            Some(c) if c == quote => return Ok(text),
+            Some('\\') if quote == '"' => {
+                let escaped = match parser.chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('\\') => '\\',
+                    Some('"') => '"',
+                    Some(c) => return Err(format!("line {} : unknown escape '\\{}'", parser.line, c)),
+                    None => return Err(format!("line {} : unterminated string", parser.line)),
+                };
+                text.push(escaped);
+            }
+            Some(c) => text.push(c),
+        }
+    }
+}
+
+fn parse_value(parser: &mut TomlParser) -> Result<TomlValue, String> {
+    return match parser.chars.peek() {
+        Some('"') | Some('\'') => Ok(TomlValue::String(parse_string(parser)?)),
+        Some('[') => {
+            parser.chars.next();
+            let mut values = Vec::new();
+            loop {
+                skip_blank(parser, true);
+                if parser.chars.peek() == Some(&']') {
+                    parser.chars.next();
+                    return Ok(TomlValue::Array(values));
+                }
+                values.push(parse_value(parser)?);
+                skip_blank(parser, true);
+                match parser.chars.next() {
+                    Some(',') => (),
+                    Some(']') => return Ok(TomlValue::Array(values)),
+                    _ => return Err(format!("line {} : expected ',' or ']' in the array", parser.line)),
+                }
+            }
+        }
+        _ => {
+            let mut word = String::new();
+            while let Some(&c) = parser.chars.peek() {
+                if !(c.is_ascii_alphanumeric() || "+-_.".contains(c)) {
+                    break;
+                }
+                word.push(c);
+                parser.chars.next();
+            }
+            parse_scalar(&word).ok_or_else(|| format!("line {} : '{}' is not a value", parser.line, word))
+        }
+    };
+}
+
+/**
+ * Boolean, integer or float written without quotes
+ */
+fn parse_scalar(word: &str) -> Option<TomlValue> {
+    match word {
+        "true" => return Some(TomlValue::Boolean(true)),
+        "false" => return Some(TomlValue::Boolean(false)),
+        _ => (),
+    }
+    let digits = word.replace('_', "");
+    if let Some(hex) = digits.strip_prefix("0x") {
+        return i64::from_str_radix(hex, 16).ok().map(TomlValue::Integer);
+    }
+    if let Ok(value) = digits.parse::<i64>() {
+        return Some(TomlValue::Integer(value));
+    }
+    if digits.starts_with(|c: char| c.is_ascii_digit() || c == '+' || c == '-') {
+        return digits.parse::<f64>().ok().filter(|value| value.is_finite()).map(TomlValue::Float);
+    }
+    return None;
+}
+
+/**
+ * Name of the type of a value, for the error messages
+ */
+fn type_name(value: &TomlValue) -> &'static str {
+    return match value {
+        TomlValue::String(_) => "a string",
+        TomlValue::Integer(_) => "an integer",
+        TomlValue::Float(_) => "a float",
+        TomlValue::Boolean(_) => "a boolean",
+        TomlValue::Array(_) => "an array",
+    };
+}
+
+fn type_error(entry: &TomlEntry, expected: &str) -> String {
+    return format!("line {} : {} must be {}, not {}", entry.line, entry.key, expected, type_name(&entry.value));
+}
+
+pub fn entry_string(entry: &TomlEntry) -> Result<String, String> {
+    return match &entry.value {
+        TomlValue::String(text) => Ok(text.clone()),
+        _ => Err(type_error(entry, "a string")),
+    };
+}
+
+pub fn entry_bool(entry: &TomlEntry) -> Result<bool, String> {
+    return match entry.value {
+        TomlValue::Boolean(value) => Ok(value),
+        _ => Err(type_error(entry, "true or false")),
+    };
+}
+
+/**
+ * Integer between min and max
+ */
+pub fn entry_integer(entry: &TomlEntry, min: i64, max: i64) -> Result<i64, String> {
+    return match entry.value {
+        TomlValue::Integer(value) if value >= min && value <= max => Ok(value),
+        TomlValue::Integer(value) => Err(format!("line {} : {} must be between {} and {}, not {}", entry.line, entry.key, min, max, value)),
+        _ => Err(type_error(entry, "an integer")),
+    };
+}
+
+/**
+ * Number between min and max, integers are accepted
+ */
+pub fn entry_float(entry: &TomlEntry, min: f64, max: f64) -> Result<f64, String> {
+    let value = match entry.value {
+        TomlValue::Float(value) => value,
+        TomlValue::Integer(value) => value as f64,
+        _ => return Err(type_error(entry, "a number")),
+    };
+    if value < min || value > max {
+        return Err(format!("line {} : {} must be between {} and {}, not {}", entry.line, entry.key, min, max, value));
+    }
+    return Ok(value);
+}
+
+pub fn entry_string_array(entry: &TomlEntry) -> Result<Vec<String>, String> {
+    return match &entry.value {
+        TomlValue::Array(values) => values.iter()
+            .map(|value| match value {
+                TomlValue::String(text) => Ok(text.clone()),
+                other => Err(format!("line {} : {} must hold strings, not {}", entry.line, entry.key, type_name(other))),
+            })
+            .collect(),
+        _ => Err(type_error(entry, "an array of strings")),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry<'a>(tables: &'a [TomlTable], name: &[&str], key: &str) -> &'a TomlEntry {
+        let table = tables.iter().find(|table| table.name == name).unwrap();
+        return table.entries.iter().find(|entry| entry.key == key).unwrap();
+    }
+
+    #[test]
+    fn dotted_and_quoted_table_names() {
+        let tables = parse_toml("top = 1\n[video]\nscale = 12\n[rom.\"BRIX.ch8\"]\nquirks = 'vip'\n[ a . b ]\nc.d = true\n").unwrap();
+        let names: Vec<Vec<String>> = tables.iter().map(|table| table.name.clone()).collect();
+        assert_eq!(names, vec![vec![], vec!["video".to_string()], vec!["rom".to_string(), "BRIX.ch8".to_string()],
+                               vec!["a".to_string(), "b".to_string()]]);
+        assert_eq!(entry(&tables, &[], "top").value, TomlValue::Integer(1));
+        assert_eq!(entry(&tables, &["rom", "BRIX.ch8"], "quirks").value, TomlValue::String("vip".to_string()));
+        assert_eq!(entry(&tables, &["a", "b"], "c.d").value, TomlValue::Boolean(true));
+        assert_eq!(tables[2].line, 4);
+    }
+
+    #[test]
+    fn multi_line_arrays() {
+        let tables = parse_toml("keys = [\n  \"5=Space\", # fire\n\n  'turbo=T',\n]\nafter = 2\n").unwrap();
+        let keys = entry(&tables, &[], "keys");
+        assert_eq!(entry_string_array(keys).unwrap(), vec!["5=Space".to_string(), "turbo=T".to_string()]);
+        assert_eq!(keys.line, 1);
+        assert_eq!(entry(&tables, &[], "after").line, 6);
+        assert_eq!(parse_toml("empty = []\nnested = [[1, 2], [3]]").unwrap()[0].entries[1].value,
+                   TomlValue::Array(vec![TomlValue::Array(vec![TomlValue::Integer(1), TomlValue::Integer(2)]),
+                                         TomlValue::Array(vec![TomlValue::Integer(3)])]));
+    }
+
+    #[test]
+    fn numbers_and_strings() {
+        let tables = parse_toml("hex = 0xFF\nbig = 1_000_000\nneg = -12\nfloat = 0.1\nexp = 1e3\ntext = \"a\\tb\\\"c\"\nraw = 'a\\b'").unwrap();
+        let values: Vec<TomlValue> = tables[0].entries.iter().map(|entry| entry.value.clone()).collect();
+        assert_eq!(values, vec![TomlValue::Integer(255), TomlValue::Integer(1000000), TomlValue::Integer(-12),
+                                TomlValue::Float(0.1), TomlValue::Float(1000.0), TomlValue::String("a\tb\"c".to_string()),
+                                TomlValue::String("a\\b".to_string())]);
+    }
+
+    #[test]
+    fn duplicates_are_refused() {
+        assert_eq!(parse_toml("a = 1\nb = 2\na = 3").unwrap_err(), "line 3 : key 'a' is defined twice");
+        assert_eq!(parse_toml("[video]\n[audio]\n[video]").unwrap_err(), "line 3 : table [video] is defined twice");
+        assert_eq!(parse_toml("[rom.'X']\n[rom.\"X\"]").unwrap_err(), "line 2 : table [rom.X] is defined twice");
+        // The same key in two tables is fine
+        assert!(parse_toml("[video]\nfg = 1\n[rom.X]\nfg = 2").is_ok());
+    }
+
+    #[test]
+    fn errors_give_their_line() {
+        assert_eq!(parse_toml("\n\nscale 12").unwrap_err(), "line 3 : expected '=', found '1'");
+        assert_eq!(parse_toml("a = \"open\nb = 1").unwrap_err(), "line 1 : unterminated string");
+        assert_eq!(parse_toml("a = 1 2").unwrap_err(), "line 1 : unexpected '2' at the end of the line");
+        assert_eq!(parse_toml("# c\na = nope").unwrap_err(), "line 2 : 'nope' is not a value");
+        assert_eq!(parse_toml("a = [1,\n2\n3]").unwrap_err(), "line 3 : expected ',' or ']' in the array");
+        assert_eq!(parse_toml("a = \"\\q\"").unwrap_err(), "line 1 : unknown escape '\\q'");
+        assert_eq!(parse_toml("[video").unwrap_err(), "line 1 : expected ']', found the end of the file");
+        assert_eq!(parse_toml("= 1").unwrap_err(), "line 1 : expected a key");
+    }
+
+    #[test]
+    fn entry_checks_give_their_line() {
+        let tables = parse_toml("\nscale = 50\nvolume = 0.5\ngrid = 1\nkeys = [1]").unwrap();
+        let entries = &tables[0].entries;
+        assert_eq!(entry_integer(&entries[0], 1, 40).unwrap_err(), "line 2 : scale must be between 1 and 40, not 50");
+        assert_eq!(entry_float(&entries[1], 0.0, 1.0), Ok(0.5));
+        assert_eq!(entry_float(&entries[0], 0.0, 100.0), Ok(50.0));
+        assert_eq!(entry_bool(&entries[2]).unwrap_err(), "line 4 : grid must be true or false, not an integer");
+        assert_eq!(entry_string(&entries[1]).unwrap_err(), "line 3 : volume must be a string, not a float");
+        assert_eq!(entry_string_array(&entries[3]).unwrap_err(), "line 5 : keys must hold strings, not an integer");
+    }
+}
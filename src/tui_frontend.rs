@@ -0,0 +1,338 @@
+#![allow(clippy::needless_return)]
+
+use std::io::{Read, Write};
+use std::process::Command;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::chip8::KEYBOARD_SIZE;
+use crate::chip8_display::{Chip8Display, DISPLAY_HEIGHT, DISPLAY_WIDTH, get_px_at};
+use crate::frontend::{AudioSink, InputEvent, InputSource, MenuCommand, VideoSink};
+use crate::keymap::{KeyAction, Keymap, keymap_action};
+
+// Terminals only send key presses, a key is released when it was not repeated for this long.
+// The first repeat of a held key comes later than the next ones.
+const KEY_FIRST_RELEASE_DELAY: Duration = Duration::from_millis(250);
+const KEY_REPEAT_RELEASE_DELAY: Duration = Duration::from_millis(100);
+
+// How long a message stays on the status line
+const MESSAGE_DURATION: Duration = Duration::from_secs(2);
+
+// Ctrl-C, received as a byte since the terminal is in raw mode
+const CTRL_C: u8 = 0x03;
+
+// Ctrl-S and Ctrl-L, saving and restoring the machine
+const CTRL_S: u8 = 0x13;
+const CTRL_L: u8 = 0x0C;
+
+// Escape, alone it opens the ROM browser, it also starts the sequences sent by the arrows and the page keys
+const ESCAPE: u8 = 0x1B;
+
+/**
+ * Characters used to draw the display
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TuiMode {
+    // One character for 1x2 pixels, 64x16 characters
+    HalfBlock,
+    // One character for 2x4 pixels, 32x8 characters
+    Braille,
+}
+
+/**
+ * Display drawn in place in the terminal with ANSI escapes
+ *
+ * Creating it switches the terminal to raw mode, dropping it restores the terminal.
+ */
+pub struct TuiVideo {
+    mode: TuiMode,
+    // Emulator state shown under the display
+    status: String,
+    // Last message and the time it was shown
+    message: Option<(String, Instant)>,
+    // Terminal settings to restore, as given by `stty -g`
+    saved_settings: String,
+}
+
+/**
+ * Keys read from the terminal in raw mode
+ */
+pub struct TuiInput {
+    bytes: Receiver<u8>,
+    keymap: Keymap,
+    // Held keys (last time seen, repeated)
+    held: [Option<(Instant, bool)>; KEYBOARD_SIZE],
+    turbo: Option<(Instant, bool)>,
+}
+
+/**
+ * Beeper played with the terminal bell
+ */
+pub struct TuiAudio {
+    beeping: bool,
+    // The bell is not rung when the beeper is muted
+    muted: bool,
+}
+
+/**
+ * Switch the terminal to raw mode with `stty`, failing when there is no Unix terminal to draw in
+ */
+pub fn build_tui_video(mode: TuiMode) -> Result<TuiVideo, String> {
+    let output = Command::new("stty").arg("-g").output()
+        .map_err(|e| format!("--tui needs the stty command of Unix terminals ({})", e))?;
+    if !output.status.success() {
+        return Err(format!("--tui needs a terminal, stty cannot read its settings ({})", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    let saved_settings = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let raw = Command::new("stty").args(["raw", "-echo"]).status().map_err(|e| format!("cannot run stty ({})", e))?;
+    if !raw.success() {
+        return Err(format!("stty cannot switch the terminal to raw mode ({})", raw));
+    }
+    // Clear the screen and hide the cursor
+    print!("\x1b[2J\x1b[?25l");
+    return Ok(TuiVideo { mode, status: String::new(), message: None, saved_settings });
+}
+
+pub fn build_tui_input(keymap: Keymap) -> TuiInput {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        for byte in std::io::stdin().lock().bytes() {
+            match byte {
+                Ok(byte) => {
+                    if sender.send(byte).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+    return TuiInput { bytes: receiver, keymap, held: [None; KEYBOARD_SIZE], turbo: None };
+}
+
+pub fn build_tui_audio(muted: bool) -> TuiAudio {
+    return TuiAudio { beeping: false, muted };
+}
+
+/**
+ * Draw the display with half blocks, each character holds two pixels stacked
+ */
+fn draw_half_blocks(display: &Chip8Display, out: &mut String) {
+    for y in (0..DISPLAY_HEIGHT).step_by(2) {
+        for x in 0..DISPLAY_WIDTH {
+            let top = get_px_at(display, x, y);
+            let bottom = y + 1 < DISPLAY_HEIGHT && get_px_at(display, x, y + 1);
+            out.push(match (top, bottom) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' ',
+            });
+        }
+        out.push_str("\r\n");
+    }
+}
+
+/**
+ * Draw the display with braille patterns, each character holds 2x4 pixels
+ */
+fn draw_braille(display: &Chip8Display, out: &mut String) {
+    // Bit of each dot of a braille cell, by row then column
+    const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+    for y in (0..DISPLAY_HEIGHT).step_by(4) {
+        for x in (0..DISPLAY_WIDTH).step_by(2) {
+            let mut bits = 0;
+            for (dy, row) in DOTS.iter().enumerate() {
+                for (dx, bit) in row.iter().enumerate() {
+                    if y + dy < DISPLAY_HEIGHT && get_px_at(display, x + dx, y + dy) {
+                        bits |= bit;
+                    }
+                }
+            }
+            out.push(std::char::from_u32(0x2800 + bits).unwrap());
+        }
+        out.push_str("\r\n");
+    }
+}
+
+impl VideoSink for TuiVideo {
+    fn present(&mut self, display: &Chip8Display) {
+        // Go back to the top left corner and draw over the previous frame
+        let mut out = String::from("\x1b[H");
+        match self.mode {
+            TuiMode::HalfBlock => draw_half_blocks(display, &mut out),
+            TuiMode::Braille => draw_braille(display, &mut out),
+        }
+        let mut status = self.status.clone();
+        if let Some((message, shown)) = &self.message {
+            if shown.elapsed() < MESSAGE_DURATION {
+                status = format!("{} - {}", status, message);
+            }
+        }
+        // Clear the end of the line and the lines below, the status can get shorter and a menu can be taller
+        out.push_str(&format!("{} - Ctrl-C to quit\x1b[K\r\n\x1b[J", status));
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(out.as_bytes());
+        let _ = stdout.flush();
+        thread::sleep(Duration::from_millis(25)); // 40 FPS cap
+    }
+
+    fn set_status(&mut self, status: &str) {
+        self.status = status.to_string();
+    }
+
+    fn show_message(&mut self, text: &str) {
+        self.message = Some((text.to_string(), Instant::now()));
+    }
+
+    fn present_menu(&mut self, lines: &[String]) {
+        let mut out = String::from("\x1b[H");
+        for line in lines {
+            out.push_str(&format!("{}\x1b[K\r\n", line));
+        }
+        if let Some((message, shown)) = &self.message {
+            if shown.elapsed() < MESSAGE_DURATION {
+                out.push_str(&format!("{}\x1b[K\r\n", message));
+            }
+        }
+        out.push_str("\x1b[J");
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(out.as_bytes());
+        let _ = stdout.flush();
+        thread::sleep(Duration::from_millis(25));
+    }
+}
+
+impl Drop for TuiVideo {
+    fn drop(&mut self) {
+        // Show the cursor again and restore the terminal settings
+        print!("\x1b[?25h\r\n");
+        let _ = std::io::stdout().flush();
+        let _ = Command::new("stty").arg(&self.saved_settings).status();
+    }
+}
+
+/**
+ * Name of the key typing a terminal character, as the keymap names host keys
+ */
+fn host_key_name(byte: u8) -> Option<String> {
+    return match byte {
+        b'\t' => Some("Tab".to_string()),
+        b' ' => Some("Space".to_string()),
+        b'!'..=b'~' => Some((byte as char).to_ascii_uppercase().to_string()),
+        _ => None,
+    };
+}
+
+/**
+ * Browser command of the escape sequence starting the bytes (after the escape byte), with the length of the sequence
+ */
+fn menu_sequence(bytes: &[u8]) -> Option<(MenuCommand, usize)> {
+    let command = match bytes {
+        [b'[', b'A', ..] => (MenuCommand::Move(-1), 2),
+        [b'[', b'B', ..] => (MenuCommand::Move(1), 2),
+        [b'[', b'H', ..] => (MenuCommand::End(false), 2),
+        [b'[', b'F', ..] => (MenuCommand::End(true), 2),
+        [b'[', b'5', b'~', ..] => (MenuCommand::Move(-10), 3),
+        [b'[', b'6', b'~', ..] => (MenuCommand::Move(10), 3),
+        _ => return None,
+    };
+    return Some(command);
+}
+
+/**
+ * Release a held key when the terminal did not repeat it recently
+ */
+fn expired(held: Option<(Instant, bool)>, now: Instant) -> bool {
+    return match held {
+        Some((seen, repeated)) => {
+            let delay = if repeated { KEY_REPEAT_RELEASE_DELAY } else { KEY_FIRST_RELEASE_DELAY };
+            now.duration_since(seen) > delay
+        }
+        None => false,
+    };
+}
+
+impl InputSource for TuiInput {
+    fn poll_events(&mut self) -> Vec<InputEvent> {
+        let mut events = Vec::new();
+        let now = Instant::now();
+        let bytes: Vec<u8> = self.bytes.try_iter().collect();
+        let mut i = 0;
+        while i < bytes.len() {
+            let byte = bytes[i];
+            i += 1;
+            let hotkey = match byte {
+                CTRL_C => Some(InputEvent::Quit),
+                CTRL_S => Some(InputEvent::SaveState),
+                CTRL_L => Some(InputEvent::LoadState),
+                _ => None,
+            };
+            if let Some(hotkey) = hotkey {
+                events.push(hotkey);
+                continue;
+            }
+            if byte == ESCAPE {
+                match menu_sequence(&bytes[i..]) {
+                    Some((command, length)) => {
+                        events.push(InputEvent::Menu(command));
+                        i += length;
+                    }
+                    // Other sequences (function keys...) are skipped up to their final byte
+                    None if bytes.get(i) == Some(&b'[') => {
+                        let end = bytes[i + 1..].iter().position(|b| (0x40..=0x7E).contains(b));
+                        i = end.map(|end| i + end + 2).unwrap_or(bytes.len());
+                    }
+                    None => events.push(InputEvent::Browse),
+                }
+                continue;
+            }
+            if byte == b'\r' || byte == b'\n' {
+                events.push(InputEvent::Menu(MenuCommand::Select));
+                continue;
+            }
+            match host_key_name(byte).and_then(|name| keymap_action(&self.keymap, &name)) {
+                Some(KeyAction::Turbo) => {
+                    if self.turbo.is_none() {
+                        events.push(InputEvent::Turbo(true));
+                    }
+                    self.turbo = Some((now, self.turbo.is_some()));
+                }
+                Some(KeyAction::Key(key)) => {
+                    if self.held[key].is_none() {
+                        events.push(InputEvent::Key(key, true));
+                    }
+                    self.held[key] = Some((now, self.held[key].is_some()));
+                }
+                None => (),
+            }
+        }
+        for key in 0..KEYBOARD_SIZE {
+            if expired(self.held[key], now) {
+                self.held[key] = None;
+                events.push(InputEvent::Key(key, false));
+            }
+        }
+        if expired(self.turbo, now) {
+            self.turbo = None;
+            events.push(InputEvent::Turbo(false));
+        }
+        return events;
+    }
+
+    fn set_keymap(&mut self, keymap: Keymap) {
+        self.keymap = keymap;
+    }
+}
+
+impl AudioSink for TuiAudio {
+    fn set_beep(&mut self, on: bool) {
+        // The bell cannot be held, ring it when the beeper starts
+        if on && !self.beeping && !self.muted {
+            print!("\x07");
+            let _ = std::io::stdout().flush();
+        }
+        self.beeping = on;
+    }
+}
@@ -0,0 +1,19 @@
+#![allow(clippy::needless_return)]
+
+use std::path::PathBuf;
+
+// Directory of the emulator files inside the user configuration directory
+const APP_DIR: &str = "rusty_chip_8";
+
+/**
+ * Directory of the user settings : %APPDATA%\rusty_chip_8 on Windows, $XDG_CONFIG_HOME/rusty_chip_8 or ~/.config/rusty_chip_8 elsewhere
+ */
+pub fn user_config_dir() -> Option<PathBuf> {
+    let env_dir = |name: &str| std::env::var_os(name).map(PathBuf::from).filter(|dir| dir.is_absolute());
+    let base = if cfg!(windows) {
+        env_dir("APPDATA")
+    } else {
+        env_dir("XDG_CONFIG_HOME").or_else(|| env_dir("HOME").map(|home| home.join(".config")))
+    };
+    return base.map(|dir| dir.join(APP_DIR));
+}
@@ -0,0 +1,366 @@
+#![allow(clippy::needless_return)]
+
+use std::path::Path;
+
+use crate::image_export::crc32;
+use crate::inflate::inflate;
+
+// Signatures of the records of a zip archive
+const LOCAL_HEADER: u32 = 0x04034B50;
+const CENTRAL_HEADER: u32 = 0x02014B50;
+const END_OF_DIRECTORY: u32 = 0x06054B50;
+
+// Size of the fixed parts of the records
+const LOCAL_HEADER_SIZE: usize = 30;
+const CENTRAL_HEADER_SIZE: usize = 46;
+const END_OF_DIRECTORY_SIZE: usize = 22;
+
+// Compression methods
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+/**
+ * File stored in a zip archive, as listed by its central directory
+ */
+#[derive(Debug)]
+pub struct ZipEntry {
+    // Path inside the archive, directories end with a slash
+    pub(crate) name: String,
+    pub(crate) method: u16,
+    pub(crate) encrypted: bool,
+    pub(crate) crc: u32,
+    pub(crate) compressed_size: usize,
+    pub(crate) size: usize,
+    // Offset of the local header, followed by the data
+    pub(crate) offset: usize,
+}
+
+/**
+ * Tell whether these bytes are a zip archive
+ */
+pub fn is_zip(bytes: &[u8]) -> bool {
+    return read_u32(bytes, 0) == Some(LOCAL_HEADER);
+}
+
+/**
+ * Split a path such as `pack.zip:TETRIS` into the archive and the file inside it
+ */
+pub fn split_archive_path(path: &str) -> Option<(&str, &str)> {
+    let lower = path.to_ascii_lowercase();
+    let split = lower.find(".zip:")? + ".zip".len();
+    return Some((&path[..split], &path[split + 1..]));
+}
+
+/**
+ * Archive part of a path, the path itself when it does not point inside an archive
+ */
+pub fn archive_file(path: &str) -> &str {
+    return split_archive_path(path).map(|(archive, _)| archive).unwrap_or(path);
+}
+
+/**
+ * File name of an entry without its directories
+ */
+pub fn entry_file_name(name: &str) -> &str {
+    return name.rsplit('/').next().unwrap_or(name);
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let field = bytes.get(offset..offset + 2)?;
+    return Some(u16::from_le_bytes([field[0], field[1]]));
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let field = bytes.get(offset..offset + 4)?;
+    return Some(u32::from_le_bytes([field[0], field[1], field[2], field[3]]));
+}
+
+/**
+ * List the files of an archive from its central directory, found at the end of the file after an optional comment
+ */
+pub fn zip_entries(bytes: &[u8]) -> Result<Vec<ZipEntry>, String> {
+    let damaged = || "the zip archive is damaged".to_string();
+    let end = (0..=bytes.len().saturating_sub(END_OF_DIRECTORY_SIZE)).rev()
+        .take(u16::MAX as usize + 1)
+        .find(|offset| read_u32(bytes, *offset) == Some(END_OF_DIRECTORY))
+        .ok_or_else(damaged)?;
+    let count = read_u16(bytes, end + 10).ok_or_else(damaged)? as usize;
+    let mut offset = read_u32(bytes, end + 16).ok_or_else(damaged)? as usize;
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        if read_u32(bytes, offset) != Some(CENTRAL_HEADER) {
+            return Err(damaged());
+        }
+        let header = bytes.get(offset..offset + CENTRAL_HEADER_SIZE).ok_or_else(damaged)?;
+        let name_length = read_u16(header, 28).unwrap() as usize;
+        let extra_length = read_u16(header, 30).unwrap() as usize;
+        let comment_length = read_u16(header, 32).unwrap() as usize;
+        let name_start = offset + CENTRAL_HEADER_SIZE;
+        let name = bytes.get(name_start..name_start + name_length).ok_or_else(damaged)?;
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(name).to_string(),
+            method: read_u16(header, 10).unwrap(),
+            encrypted: read_u16(header, 8).unwrap() & 1 != 0,
+            crc: read_u32(header, 16).unwrap(),
+            compressed_size: read_u32(header, 20).unwrap() as usize,
+            size: read_u32(header, 24).unwrap() as usize,
+            offset: read_u32(header, 42).unwrap() as usize,
+        });
+        offset = name_start + name_length + extra_length + comment_length;
+    }
+    return Ok(entries);
+}
+
+/**
+ * Decompress a file of an archive, checking its CRC-32
+ */
+pub fn zip_extract(bytes: &[u8], entry: &ZipEntry) -> Result<Vec<u8>, String> {
+    let damaged = || format!("{} is damaged in the zip archive", entry.name);
+    if entry.encrypted {
+        return Err(format!("{} is encrypted in the zip archive", entry.name));
+    }
+    if read_u32(bytes, entry.offset) != Some(LOCAL_HEADER) {
+        return Err(damaged());
+    }
+    let name_length = read_u16(bytes, entry.offset + 26).ok_or_else(damaged)? as usize;
+    let extra_length = read_u16(bytes, entry.offset + 28).ok_or_else(damaged)? as usize;
+    let start = entry.offset + LOCAL_HEADER_SIZE + name_length + extra_length;
+    let data = bytes.get(start..start + entry.compressed_size).ok_or_else(damaged)?;
+    let content = match entry.method {
+        METHOD_STORED => data.to_vec(),
+        METHOD_DEFLATE => inflate(data).map_err(|e| format!("{} : {}", entry.name, e))?,
+        method => return Err(format!("{} uses the compression method {}, only stored and deflated files can be read", entry.name, method)),
+    };
+    if content.len() != entry.size || crc32(&content) != entry.crc {
+        return Err(format!("{} fails its CRC check in the zip archive", entry.name));
+    }
+    return Ok(content);
+}
+
+/**
+ * Files of an archive that can be programs : not directories, hidden files nor files with these extensions
+ */
+pub fn zip_candidates(entries: &[ZipEntry], skipped_extensions: &[&str]) -> Vec<usize> {
+    return entries.iter().enumerate()
+        .filter(|(_, entry)| {
+            let file_name = entry_file_name(&entry.name);
+            let extension = Path::new(file_name).extension().map(|ext| ext.to_string_lossy().to_lowercase()).unwrap_or_default();
+            !entry.name.ends_with('/') && !file_name.starts_with('.') && !entry.name.starts_with("__MACOSX/")
+                && !skipped_extensions.contains(&extension.as_str())
+        })
+        .map(|(index, _)| index)
+        .collect();
+}
+
+/**
+ * Find a file of an archive by its path, or else by its file name, ignoring the case
+ */
+pub fn find_zip_entry<'a>(entries: &'a [ZipEntry], name: &str) -> Option<&'a ZipEntry> {
+    return entries.iter().find(|entry| entry.name == name)
+        .or_else(|| entries.iter().find(|entry| entry.name.eq_ignore_ascii_case(name)))
+        .or_else(|| entries.iter().find(|entry| entry_file_name(&entry.name).eq_ignore_ascii_case(name)));
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /**
+     * File to write in a test archive : its data as stored, and the content its CRC-32 and size are computed on
+     */
+    pub(crate) struct TestFile {
+        pub(crate) name: String,
+        pub(crate) method: u16,
+        pub(crate) data: Vec<u8>,
+        pub(crate) content: Vec<u8>,
+    }
+
+    pub(crate) fn stored_file(name: &str, content: &[u8]) -> TestFile {
+        return TestFile { name: name.to_string(), method: METHOD_STORED, data: content.to_vec(), content: content.to_vec() };
+    }
+
+    /**
+     * Zip archive of the files, local headers and data then the central directory
+     */
+    pub(crate) fn build_zip(files: &[TestFile]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut directory = Vec::new();
+        for file in files {
+            let offset = out.len() as u32;
+            let crc = crc32(&file.content);
+            out.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+            out.extend_from_slice(&[20, 0, 0, 0]);
+            out.extend_from_slice(&file.method.to_le_bytes());
+            out.extend_from_slice(&[0; 4]);
+            out.extend_from_slice(&crc.to_le_bytes());
+            out.extend_from_slice(&(file.data.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(file.content.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(file.name.len() as u16).to_le_bytes());
+            out.extend_from_slice(&[0, 0]);
+            out.extend_from_slice(file.name.as_bytes());
+            out.extend_from_slice(&file.data);
+
+            directory.extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+            directory.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            directory.extend_from_slice(&file.method.to_le_bytes());
+            directory.extend_from_slice(&[0; 4]);
+            directory.extend_from_slice(&crc.to_le_bytes());
+            directory.extend_from_slice(&(file.data.len() as u32).to_le_bytes());
+            directory.extend_from_slice(&(file.content.len() as u32).to_le_bytes());
+            directory.extend_from_slice(&(file.name.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&[0; 12]);
+            directory.extend_from_slice(&offset.to_le_bytes());
+            directory.extend_from_slice(file.name.as_bytes());
+        }
+        let directory_offset = out.len() as u32;
+        out.extend_from_slice(&directory);
+        out.extend_from_slice(&END_OF_DIRECTORY.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        out.extend_from_slice(&directory_offset.to_le_bytes());
+        out.extend_from_slice(&[0, 0]);
+        return out;
+    }
+
+    /**
+     * "abcabcabcabc" as a fixed Huffman deflate block : 3 literals, then 9 bytes copied from 3 bytes back
+     */
+    fn deflated_abc() -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut bit = 0;
+        // Huffman codes are written from their most significant bit, the other fields from their least significant one
+        let mut push = |value: u32, count: u32, huffman: bool| {
+            for i in 0..count {
+                if bit % 8 == 0 {
+                    out.push(0);
+                }
+                let shift = if huffman { count - 1 - i } else { i };
+                *out.last_mut().unwrap() |= (((value >> shift) & 1) as u8) << (bit % 8);
+                bit += 1;
+            }
+        };
+        // Last block, fixed codes
+        push(1, 1, false);
+        push(1, 2, false);
+        for c in b"abc" {
+            push(0x30 + *c as u32, 8, true);
+        }
+        // Length 9 is the symbol 263, distance 3 the code 2
+        push(263 - 256, 7, true);
+        push(2, 5, true);
+        push(0, 7, true);
+        return out;
+    }
+
+    fn deflated_file(name: &str) -> TestFile {
+        return TestFile { name: name.to_string(), method: METHOD_DEFLATE, data: deflated_abc(), content: b"abcabcabcabc".to_vec() };
+    }
+
+    #[test]
+    fn stored_zip() {
+        let zip = build_zip(&[stored_file("BRIX", &[0x6E, 0x05, 0x65, 0x00])]);
+        assert!(is_zip(&zip));
+        let entries = zip_entries(&zip).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].name.as_str(), entries[0].method, entries[0].size), ("BRIX", METHOD_STORED, 4));
+        assert_eq!(zip_extract(&zip, &entries[0]).unwrap(), vec![0x6E, 0x05, 0x65, 0x00]);
+    }
+
+    #[test]
+    fn deflated_zip() {
+        let zip = build_zip(&[deflated_file("abc.txt")]);
+        let entries = zip_entries(&zip).unwrap();
+        assert_eq!(entries[0].method, METHOD_DEFLATE);
+        assert_eq!((entries[0].compressed_size, entries[0].size), (6, 12));
+        assert_eq!(zip_extract(&zip, &entries[0]).unwrap(), b"abcabcabcabc".to_vec());
+    }
+
+    #[test]
+    fn entries_are_found_by_path_or_file_name() {
+        let zip = build_zip(&[
+            stored_file("games/", &[]),
+            stored_file("games/BRIX", &[1]),
+            stored_file("pong", &[2]),
+            stored_file("PONG", &[3]),
+            deflated_file("games/readme.txt"),
+        ]);
+        let entries = zip_entries(&zip).unwrap();
+        let found = |name: &str| find_zip_entry(&entries, name).map(|entry| zip_extract(&zip, entry).unwrap());
+        assert_eq!(found("games/BRIX"), Some(vec![1]));
+        assert_eq!(found("GAMES/brix"), Some(vec![1]));
+        assert_eq!(found("brix"), Some(vec![1]));
+        // The exact path wins over the one differing by its case
+        assert_eq!(found("PONG"), Some(vec![3]));
+        assert_eq!(found("Pong"), Some(vec![2]));
+        assert_eq!(found("readme.txt"), Some(b"abcabcabcabc".to_vec()));
+        assert_eq!(found("TETRIS"), None);
+        assert_eq!(zip_candidates(&entries, &["txt"]), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn hidden_files_are_not_candidates() {
+        let zip = build_zip(&[stored_file(".DS_Store", &[1]), stored_file("__MACOSX/._BRIX", &[1]), stored_file("games/.hidden", &[1]), stored_file("BRIX", &[1])]);
+        assert_eq!(zip_candidates(&zip_entries(&zip).unwrap(), &[]), vec![3]);
+    }
+
+    #[test]
+    fn crc_errors() {
+        let mut damaged = stored_file("BRIX", &[1, 2, 3, 4]);
+        damaged.data[2] = 0xFF;
+        let zip = build_zip(&[damaged]);
+        let entries = zip_entries(&zip).unwrap();
+        assert_eq!(zip_extract(&zip, &entries[0]).unwrap_err(), "BRIX fails its CRC check in the zip archive");
+
+        let mut short = deflated_file("abc.txt");
+        short.content.push(b'a');
+        let zip = build_zip(&[short]);
+        let entries = zip_entries(&zip).unwrap();
+        assert_eq!(zip_extract(&zip, &entries[0]).unwrap_err(), "abc.txt fails its CRC check in the zip archive");
+    }
+
+    #[test]
+    fn truncation_errors() {
+        let zip = build_zip(&[stored_file("BRIX", &[1, 2, 3, 4]), deflated_file("abc.txt")]);
+        let entries = zip_entries(&zip).unwrap();
+        // Without the end of the central directory
+        assert_eq!(zip_entries(&zip[..zip.len() - 4]).unwrap_err(), "the zip archive is damaged");
+        // Without the end of the central directory records
+        let directory_offset = read_u32(&zip, zip.len() - 6).unwrap() as usize;
+        let mut cut = zip[..directory_offset + 50].to_vec();
+        cut.extend_from_slice(&zip[zip.len() - END_OF_DIRECTORY_SIZE..]);
+        assert_eq!(zip_entries(&cut).unwrap_err(), "the zip archive is damaged");
+        // Without the end of the data
+        assert_eq!(zip_extract(&zip[..entries[0].offset + 32], &entries[0]).unwrap_err(), "BRIX is damaged in the zip archive");
+        assert_eq!(zip_extract(&zip[..entries[1].offset], &entries[1]).unwrap_err(), "abc.txt is damaged in the zip archive");
+
+        let mut cut = deflated_file("abc.txt");
+        cut.data.truncate(2);
+        let zip = build_zip(&[cut]);
+        let entries = zip_entries(&zip).unwrap();
+        assert_eq!(zip_extract(&zip, &entries[0]).unwrap_err(), "abc.txt : the compressed data is truncated");
+    }
+
+    #[test]
+    fn unsupported_entries() {
+        let mut shrunk = stored_file("BRIX", &[1]);
+        shrunk.method = 1;
+        let zip = build_zip(&[shrunk]);
+        let entries = zip_entries(&zip).unwrap();
+        assert_eq!(zip_extract(&zip, &entries[0]).unwrap_err(), "BRIX uses the compression method 1, only stored and deflated files can be read");
+
+        let mut entries = entries;
+        entries[0].encrypted = true;
+        assert_eq!(zip_extract(&zip, &entries[0]).unwrap_err(), "BRIX is encrypted in the zip archive");
+    }
+
+    #[test]
+    fn archive_paths() {
+        assert_eq!(split_archive_path("roms/Pack.ZIP:games/BRIX"), Some(("roms/Pack.ZIP", "games/BRIX")));
+        assert_eq!(split_archive_path("roms/BRIX"), None);
+        assert_eq!(archive_file("pack.zip:PONG"), "pack.zip");
+        assert_eq!(archive_file("PONG"), "PONG");
+        assert_eq!(entry_file_name("games/BRIX"), "BRIX");
+    }
+}